@@ -1,12 +1,20 @@
 use core::fmt;
 use std::str::FromStr;
 
+pub mod board;
+pub mod digest;
 pub mod fsm;
+pub mod group;
+pub mod heatmap;
+pub mod inline;
+pub mod outbox;
 pub mod telandler;
 pub mod telapi;
 pub mod telecom;
 pub mod telorker;
+pub mod throttle;
 pub mod utils;
+pub mod webhook;
 
 #[derive(Debug)]
 pub enum Error {
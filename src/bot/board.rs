@@ -0,0 +1,144 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread, time,
+};
+
+use chrono::Utc;
+
+use crate::{
+    config::Config,
+    db::{board_table::Board, repo, report},
+};
+
+use super::{
+    fsm::{self, ReportType},
+    telapi,
+    telapi::Telapi,
+    utils,
+};
+
+/// Raised on every saved survey, the board thread picks it up no more often
+/// than `BOARD_THROTTLE` allows. Starts raised to refresh boards after a restart.
+static DIRTY: AtomicBool = AtomicBool::new(true);
+
+pub fn notify() {
+    DIRTY.store(true, Ordering::SeqCst);
+}
+
+/// Posts a new board into the chat, pins it and remembers its message id.
+pub fn create(api: &Telapi, chat_id: i64, manager: &str) -> Result<(), telapi::Error> {
+    let message_id = api.post_text_message(chat_id, render(manager))?;
+    if let Err(e) = api.pin_message(chat_id, message_id) {
+        log::warn!("Can't pin the board in {}: {:?}", chat_id, e);
+    }
+
+    let mut board = Board::new();
+    board.chat_id.value = chat_id;
+    board.message_id.value = message_id;
+    board.manager.value = manager.to_string();
+    if let Err(e) = board.insert_or_update() {
+        log::error!("Can't store the board of {}: {}", chat_id, e);
+    }
+    Ok(())
+}
+
+pub fn start_thread(api: Arc<Telapi>) {
+    let throttle = Config::board_throttle_in_seconds().duration();
+    thread::spawn(move || {
+        let mut rendered: HashMap<i64, String> = HashMap::new();
+        let mut last_refresh: Option<time::Instant> = None;
+        loop {
+            thread::sleep(time::Duration::from_secs(1));
+            if matches!(last_refresh, Some(t) if t.elapsed() < throttle)
+                || !DIRTY.swap(false, Ordering::SeqCst)
+            {
+                continue;
+            }
+            last_refresh = Some(time::Instant::now());
+            refresh(&api, &mut rendered);
+        }
+    });
+}
+
+fn refresh(api: &Telapi, rendered: &mut HashMap<i64, String>) {
+    let boards = match Board::new().select_all() {
+        Ok(boards) => boards,
+        Err(e) => {
+            log::error!("Can't read boards: {}", e);
+            return;
+        }
+    };
+    for board in boards {
+        let text = render(&board.manager.value);
+        if rendered.get(&board.chat_id.value) == Some(&text) {
+            continue;
+        }
+        match api.edit_text_message(board.chat_id.value, board.message_id.value, text.clone()) {
+            Ok(_) => {
+                rendered.insert(board.chat_id.value, text);
+            }
+            Err(e) if e.is_not_modified() => {
+                rendered.insert(board.chat_id.value, text);
+            }
+            Err(e) if e.is_not_found() => {
+                log::info!("Board of {} is gone, forgetting it", board.chat_id.value);
+                let _ = board.delete();
+            }
+            Err(e) => log::error!("Can't update the board of {}: {:?}", board.chat_id.value, e),
+        }
+    }
+}
+
+/// Who has no electricity or network according to their latest answer.
+fn render(manager: &str) -> String {
+    let report_type = if manager.is_empty() {
+        ReportType::All
+    } else {
+        ReportType::Team(manager.to_string())
+    };
+    let latest = repo::storage()
+        .latest_reports(&report_type)
+        .unwrap_or_default();
+
+    let mut text = format!(
+        "<b>Live board</b>, {}, updated {}\n\n",
+        if manager.is_empty() {
+            "everybody".to_string()
+        } else {
+            format!("team of {}", utils::escape_html(manager))
+        },
+        Utc::now()
+            .with_timezone(&Config::report_utc_offset())
+            .format("%H:%M")
+    );
+    let affected: Vec<&report::Report> = latest
+        .iter()
+        .filter(|r| !r.electricity.value || !r.network.value)
+        .collect();
+    if affected.is_empty() {
+        text.push_str("Everybody has electricity and network");
+    }
+    for r in affected {
+        let mut issues = Vec::new();
+        if !r.electricity.value {
+            issues.push("no electricity");
+        }
+        if !r.network.value {
+            issues.push("no network");
+        }
+        text.push_str(&format!(
+            "• {} - {} ({})\n",
+            utils::escape_html(&fsm::with_username(
+                &r.name.value,
+                r.username.value.as_deref()
+            )),
+            issues.join(", "),
+            fsm::since_text(Some(r.timestamp.value))
+        ));
+    }
+    text
+}
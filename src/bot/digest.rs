@@ -0,0 +1,359 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use chrono::{DateTime, Datelike, Duration, FixedOffset, NaiveDateTime, Timelike, Utc, Weekday};
+use once_cell::sync::OnceCell;
+use prettytable::{format, row, table};
+
+use crate::{
+    config::Config,
+    db::{repo, report, subscription_table::Subscription, user_table::User},
+};
+
+use super::{
+    fsm::{self, ReportType},
+    outbox,
+    telecom::ReplyEnum,
+    utils, Error,
+};
+
+const WORST_COUNT: usize = 3;
+
+/// A digest that can't be queued is tried again after 2, 4, 8 and 16 minutes, then given up
+/// until the next week.
+const MAX_ATTEMPTS: u32 = 5;
+static RETRIES: OnceCell<Mutex<Retries>> = OnceCell::new();
+
+/// Sends the weekly digest to every recipient that hasn't got it yet this week.
+/// Called from the timer thread, so it does nothing outside of the digest hours.
+pub fn send_due() {
+    let now = Utc::now();
+    let since = match due_since(now, Config::report_utc_offset(), Config::digest_hour()) {
+        Some(since) => since,
+        None => return,
+    };
+    let recipients = match recipients() {
+        Ok(recipients) => recipients,
+        Err(e) => {
+            log::error!("Can't collect digest recipients: {}", e);
+            return;
+        }
+    };
+
+    let mut retries = RETRIES.get_or_init(Default::default).lock().unwrap();
+    for recipient in recipients {
+        if !is_due(
+            now,
+            recipient.last_sent,
+            Config::report_utc_offset(),
+            Config::digest_hour(),
+        ) || !retries.may_try(recipient.chat_id, since, now)
+        {
+            continue;
+        }
+        log::info!(
+            "Sending weekly digest of '{}' to {}",
+            recipient.team,
+            recipient.chat_id
+        );
+        let digest = ReplyEnum::Text(render(&recipient.team));
+        match outbox::enqueue(recipient.chat_id, digest, outbox::default_ttl()) {
+            Ok(()) => {
+                let mut subscription = Subscription::new();
+                subscription.chat_id.value = recipient.chat_id;
+                subscription.last_sent.value = now.format(Config::time_format()).to_string();
+                match subscription.insert_or_update() {
+                    Ok(_) => retries.succeeded(recipient.chat_id),
+                    Err(e) => {
+                        // The digest is queued already, it must not go out twice
+                        log::error!("Can't store digest delivery: {}", e);
+                        retries.give_up(recipient.chat_id, since);
+                    }
+                }
+            }
+            Err(e) => {
+                log::error!("Can't queue the digest for {}: {}", recipient.chat_id, e);
+                if !retries.failed(recipient.chat_id, since, now) {
+                    log::error!("Giving up the digest for {} this week", recipient.chat_id);
+                }
+            }
+        }
+    }
+}
+
+/// Digests of the current week which couldn't be queued, per chat.
+#[derive(Debug, Default)]
+struct Retries(HashMap<i64, Retry>);
+
+#[derive(Debug)]
+struct Retry {
+    /// The digest slot the failures belong to, see `due_since`.
+    since: DateTime<Utc>,
+    failures: u32,
+    next: DateTime<Utc>,
+}
+
+impl Retries {
+    fn may_try(&self, chat_id: i64, since: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        match self.0.get(&chat_id) {
+            Some(retry) if retry.since == since => {
+                retry.failures < MAX_ATTEMPTS && now >= retry.next
+            }
+            _ => true,
+        }
+    }
+
+    /// Doubles the delay before the next attempt, returns false once the digest is given up.
+    fn failed(&mut self, chat_id: i64, since: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        let retry = self.0.entry(chat_id).or_insert(Retry {
+            since,
+            failures: 0,
+            next: now,
+        });
+        if retry.since != since {
+            retry.since = since;
+            retry.failures = 0;
+        }
+        retry.failures += 1;
+        retry.next = now + Duration::minutes(1 << retry.failures.min(MAX_ATTEMPTS));
+        retry.failures < MAX_ATTEMPTS
+    }
+
+    fn give_up(&mut self, chat_id: i64, since: DateTime<Utc>) {
+        self.0.insert(
+            chat_id,
+            Retry {
+                since,
+                failures: MAX_ATTEMPTS,
+                next: since,
+            },
+        );
+    }
+
+    fn succeeded(&mut self, chat_id: i64) {
+        self.0.remove(&chat_id);
+    }
+}
+
+/// Flips the subscription of a registered user, returns the new state.
+pub fn toggle(chat_id: i64) -> Result<bool, Error> {
+    let storage = repo::storage();
+    let user = storage
+        .find_user(chat_id)
+        .ok()
+        .flatten()
+        .ok_or_else(|| Error::make_verbose("Please register first with /start"))?;
+    let users = storage
+        .all_users()
+        .map_err(|e| Error::Verbose(e.to_string()))?;
+
+    let mut subscription = Subscription::select_by_chat_id(chat_id)
+        .map_err(|e| Error::Verbose(e.to_string()))?
+        .unwrap_or_else(|| {
+            let mut s = Subscription::new();
+            s.chat_id.value = chat_id;
+            s.enabled.value = is_manager(&user, &users);
+            s
+        });
+    subscription.enabled.value = !subscription.enabled.value;
+    subscription
+        .insert_or_update()
+        .map_err(|e| Error::Verbose(e.to_string()))?;
+    Ok(subscription.enabled.value)
+}
+
+struct Recipient {
+    chat_id: i64,
+    team: String,
+    last_sent: Option<DateTime<Utc>>,
+}
+
+/// Managers plus everybody who opted in, minus the ones who opted out.
+fn recipients() -> repo::Result<Vec<Recipient>> {
+    let users = repo::storage().all_users()?;
+    let subscriptions = Subscription::new().select_all()?;
+    let mut result = Vec::new();
+    for user in users.iter() {
+        let subscription = subscriptions
+            .iter()
+            .find(|s| s.chat_id.value == user.chat_id.value);
+        let enabled = match subscription {
+            Some(s) => s.enabled.value,
+            None => is_manager(user, &users),
+        };
+        if enabled && user.active.value {
+            result.push(Recipient {
+                chat_id: user.chat_id.value,
+                team: team_of(user, &users),
+                last_sent: subscription.and_then(|s| parse_time(&s.last_sent.value)),
+            });
+        }
+    }
+    Ok(result)
+}
+
+fn is_manager(user: &User, users: &[User]) -> bool {
+    users.iter().any(|u| u.manager.value == user.name.value)
+}
+
+/// Managers get their own team, everybody else gets the team they belong to.
+fn team_of(user: &User, users: &[User]) -> String {
+    if is_manager(user, users) {
+        user.name.value.clone()
+    } else {
+        user.manager.value.clone()
+    }
+}
+
+fn render(team: &str) -> String {
+    let utc = Utc::now();
+    let summary = fsm::make_report(
+        repo::storage().as_ref(),
+        ReportType::Team(team.to_string()),
+        report::TimeOffset::Day(7),
+    )
+    .unwrap_or_default();
+
+    let mut text = format!(
+        "Weekly digest, team of {} [{} - {}]\n",
+        team,
+        (utc - Duration::days(7)).format("%Y-%m-%d"),
+        utc.format("%Y-%m-%d"),
+    );
+    if let Some((availele, availnet)) = fsm::average_availability(&summary) {
+        text.push_str(&format!(
+            "Availability: electricity {:.1} %, network {:.1} %\n",
+            availele * 100.0,
+            availnet * 100.0,
+        ));
+
+        let mut worst: Vec<&fsm::ReportSummary> = summary
+            .iter()
+            .filter(|s| s.availele < 1.0 || s.availnet < 1.0)
+            .collect();
+        worst.sort_by(|a, b| {
+            a.availele
+                .min(a.availnet)
+                .partial_cmp(&b.availele.min(b.availnet))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        if !worst.is_empty() {
+            let mut table = table!();
+            table.set_titles(row!["#", "Full Name", "Electricity", "Network"]);
+            table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+            for (idx, s) in worst.into_iter().take(WORST_COUNT).enumerate() {
+                table.add_row(row![
+                    format!("{}", idx + 1),
+                    s.name,
+                    format!("{:.1} %", s.availele * 100.0),
+                    format!("{:.1} %", s.availnet * 100.0),
+                ]);
+            }
+            text.push_str(&format!("\nWorst availability:\n{}", table));
+        }
+    }
+
+    let silent = repo::storage()
+        .silent(
+            &ReportType::Team(team.to_string()),
+            &report::TimeOffset::Day(7),
+        )
+        .unwrap_or_default();
+    if !silent.is_empty() {
+        text.push_str(&format!("\nNever answered ({}):\n", silent.len()));
+        silent.iter().for_each(|s| {
+            text.push_str(&format!(
+                " - {}, last answer: {}\n",
+                fsm::with_username(&s.name, s.username.as_deref()),
+                fsm::since_text(s.last_answer)
+            ))
+        });
+    }
+
+    format!("<pre>{}</pre>", utils::escape_html(&text))
+}
+
+/// The start of the current digest slot, when `now` is past the digest hour on Monday.
+fn due_since(now: DateTime<Utc>, offset: FixedOffset, hour: u32) -> Option<DateTime<Utc>> {
+    let local = now.with_timezone(&offset);
+    if local.weekday() != Weekday::Mon || local.hour() < hour {
+        return None;
+    }
+    local
+        .date()
+        .and_hms_opt(hour, 0, 0)
+        .map(|start| start.with_timezone(&Utc))
+}
+
+fn is_due(
+    now: DateTime<Utc>,
+    last_sent: Option<DateTime<Utc>>,
+    offset: FixedOffset,
+    hour: u32,
+) -> bool {
+    match (due_since(now, offset, hour), last_sent) {
+        (None, _) => false,
+        (Some(_), None) => true,
+        (Some(since), Some(last_sent)) => last_sent < since,
+    }
+}
+
+fn parse_time(text: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(text, Config::time_format())
+        .ok()
+        .map(|date_time| DateTime::<Utc>::from_utc(date_time, Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, FixedOffset, TimeZone, Utc};
+
+    use super::{is_due, Retries, MAX_ATTEMPTS};
+
+    #[test]
+    pub fn test_digest_is_due() {
+        let offset = FixedOffset::east_opt(2 * 3600).unwrap();
+        // 2022-11-14 is a Monday, 9:30 local time
+        let monday = Utc.ymd(2022, 11, 14).and_hms(7, 30, 0);
+        assert!(is_due(monday, None, offset, 9));
+        assert!(!is_due(monday, None, offset, 10));
+        assert!(!is_due(
+            monday,
+            Some(Utc.ymd(2022, 11, 14).and_hms(7, 5, 0)),
+            offset,
+            9
+        ));
+        assert!(is_due(
+            monday,
+            Some(Utc.ymd(2022, 11, 7).and_hms(7, 5, 0)),
+            offset,
+            9
+        ));
+        let tuesday = Utc.ymd(2022, 11, 15).and_hms(7, 30, 0);
+        assert!(!is_due(tuesday, None, offset, 9));
+    }
+
+    #[test]
+    pub fn test_digest_retries() {
+        let since = Utc.ymd(2022, 11, 14).and_hms(7, 0, 0);
+        let mut retries = Retries::default();
+        assert!(retries.may_try(10, since, since));
+
+        let mut now = since;
+        for _ in 1..MAX_ATTEMPTS {
+            assert!(retries.failed(10, since, now));
+            assert!(!retries.may_try(10, since, now + Duration::minutes(1)));
+            now += Duration::hours(1);
+            assert!(retries.may_try(10, since, now));
+        }
+        // Given up until the next week
+        assert!(!retries.failed(10, since, now));
+        assert!(!retries.may_try(10, since, now + Duration::hours(1)));
+        assert!(retries.may_try(10, since + Duration::days(7), now + Duration::days(7)));
+        assert!(retries.may_try(11, since, now));
+
+        retries.give_up(11, since);
+        assert!(!retries.may_try(11, since, now));
+        retries.succeeded(11);
+        assert!(retries.may_try(11, since, now));
+    }
+}
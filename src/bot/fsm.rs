@@ -1,4 +1,4 @@
-use chrono::{DateTime, Datelike, NaiveDateTime, Utc};
+use chrono::{DateTime, Datelike, Utc};
 use typed_builder::TypedBuilder as Builder;
 
 use core::fmt;
@@ -8,14 +8,19 @@ use std::{
     str::{self, FromStr},
 };
 use strum::IntoEnumIterator;
-use strum_macros::EnumIter;
+use strum_macros::{EnumIter, IntoStaticStr};
 
 use crate::{
     config::Config,
-    db::{report, user_table},
+    db::{
+        audit_table::{self, AuditEntry},
+        backup,
+        repo::{self, Storage},
+        report, user_table,
+    },
 };
 
-use super::{telecom::ReplyEnum, utils, Error};
+use super::{board, digest, heatmap, telecom::ReplyEnum, utils, Error};
 use prettytable::{format, row, table};
 
 #[derive(Debug, EnumIter, PartialEq, Hash, Eq, Clone, Default)]
@@ -28,6 +33,15 @@ pub enum Event {
     Start,
     Survey,
     Menu,
+    Digest,
+    Backup,
+    /// `/audit <chat id> [entries]`, the arguments are taken from the message.
+    Audit(String),
+    /// `/deactivate <chat id>` and `/reactivate <chat id>`, also by `@username`.
+    Deactivate(String),
+    Reactivate(String),
+    Forget,
+    ForgetConfirm,
     Back,
     Name(String),
     Allright,
@@ -42,6 +56,9 @@ pub enum Event {
     ReportOffsetDay,
     ReportOffsetWeek,
     ReportOffsetMonth,
+    ReportHeatmap,
+    ReportSilent,
+    ReportTrend,
     LMMikhail,
     LMElina,
     LMOleksandr,
@@ -64,6 +81,13 @@ impl UserDisplay for Event {
             Self::ReportOffsetDay => "Day".into(),
             Self::ReportOffsetWeek => "Week".into(),
             Self::ReportOffsetMonth => "Month".into(),
+            Self::ReportHeatmap => "Heatmap".into(),
+            Self::ReportSilent => "Silent".into(),
+            Self::ReportTrend => "Trend".into(),
+            Self::ForgetConfirm => "Delete my data".into(),
+            Self::Audit(_) => "Audit".into(),
+            Self::Deactivate(_) => "Deactivate".into(),
+            Self::Reactivate(_) => "Reactivate".into(),
             Self::ReportMe => "Me".into(),
             Self::ReportTeam => "My Team".into(),
             Self::ReportAll => "All".into(),
@@ -81,20 +105,42 @@ impl UserDisplay for Event {
     }
 }
 impl Event {
+    /// A command by its first word, e.g. `/audit 7 5` or `/help@bot_name`,
+    /// or the exact data of a button.
     pub fn from_string(text: &str) -> Option<Event> {
-        for it in Self::iter() {
-            if text
-                .to_lowercase()
-                .contains(it.to_user_string().to_lowercase().as_str())
-            {
-                return Some(it.into());
+        let text = text.trim();
+        let (command, args) = match text.split_once(char::is_whitespace) {
+            Some((command, args)) => (command, args.trim().to_string()),
+            None => (text, String::default()),
+        };
+        let command = command.split('@').next().unwrap_or_default().to_lowercase();
+        Self::iter().find_map(|it| {
+            let name = it.to_user_string();
+            if command == format!("/{}", name.to_lowercase()) {
+                Some(match it {
+                    Event::Audit(_) => Event::Audit(args.clone()),
+                    Event::Deactivate(_) => Event::Deactivate(args.clone()),
+                    Event::Reactivate(_) => Event::Reactivate(args.clone()),
+                    it => it,
+                })
+            } else if text == name {
+                Some(it)
+            } else {
+                None
             }
+        })
+    }
+
+    /// The event as written to the audit trail, names typed by users are left out.
+    fn audit_text(&self) -> String {
+        match self {
+            Self::Name(_) => "Name".to_string(),
+            e => e.to_string(),
         }
-        None
     }
 }
 
-#[derive(Debug, EnumIter, Clone)]
+#[derive(Debug, EnumIter, IntoStaticStr, Clone)]
 pub enum State {
     New(Data),
     Idle(Data),
@@ -104,6 +150,9 @@ pub enum State {
     SurvMore(Data),
     Report(Data),
     ReportFrame(Data),
+    Forget(Data),
+    /// The user asked to be forgotten, `UserData` drops the entry instead of keeping it.
+    Forgotten(Data),
 }
 
 impl fmt::Display for State {
@@ -135,11 +184,7 @@ impl From<report::Report> for Data {
         d.chat_id = item.chat_id.value;
         d.name = item.name.value;
         d.manager = item.manager.value;
-        if let Ok(date_time) =
-            NaiveDateTime::parse_from_str(&item.timestamp.value, Config::time_format())
-        {
-            d.utc = DateTime::<Utc>::from_utc(date_time, Utc);
-        }
+        d.utc = item.timestamp.value;
 
         if !item.network.value || !item.electricity.value {
             d.issues = Some(Issues::default());
@@ -152,21 +197,179 @@ impl From<report::Report> for Data {
 }
 
 impl Data {
-    fn on_start(&mut self, _e: Event) -> Result<(), Error> {
+    fn on_start(&mut self, _storage: &dyn Storage, _e: Event) -> Result<(), Error> {
         self.reply = Some(utils::reply_start_event());
         Ok(())
     }
-    fn on_help(&mut self, _e: Event) -> Result<(), Error> {
+    fn on_help(&mut self, _storage: &dyn Storage, _e: Event) -> Result<(), Error> {
         self.reply = Some(utils::reply_help_event());
         Ok(())
     }
-    fn on_menu(&mut self, _e: Event) -> Result<(), Error> {
+    fn on_menu(&mut self, _storage: &dyn Storage, _e: Event) -> Result<(), Error> {
         self.reply = Some(utils::reply_menu_texted(
             "Here is your menu, sir/ma'am/homie",
         ));
         Ok(())
     }
-    fn on_survey(&mut self, _e: Event) -> Result<(), Error> {
+    fn on_digest(&mut self, _storage: &dyn Storage, _e: Event) -> Result<(), Error> {
+        let text = match digest::toggle(self.chat_id) {
+            Ok(true) => "You will get the weekly digest every Monday morning".to_string(),
+            Ok(false) => "You won't get the weekly digest anymore".to_string(),
+            Err(e) => e.msg().unwrap_or_else(|| e.to_string()),
+        };
+        self.reply = Some(utils::make_reply_text(&text));
+        Ok(())
+    }
+    fn on_backup(&mut self, storage: &dyn Storage, _e: Event) -> Result<(), Error> {
+        let text = if Config::owner_telegram_id() != Some(self.chat_id) {
+            "Only the bot owner can make backups".to_string()
+        } else {
+            match backup::create() {
+                Ok(file) => format!(
+                    "Backup saved to {}, {:.1} KiB",
+                    file.path.display(),
+                    file.size as f64 / 1024.0
+                ),
+                Err(e) => format!("Can't make a backup: {}", e),
+            }
+        };
+        audit_table::record(storage, AuditEntry::admin(self.chat_id, "backup", &text));
+        self.reply = Some(utils::make_reply_text(&text));
+        Ok(())
+    }
+    fn on_audit(&mut self, storage: &dyn Storage, e: Event) -> Result<(), Error> {
+        if Config::owner_telegram_id() != Some(self.chat_id) {
+            self.reply = Some(utils::make_reply_text(
+                "Only the bot owner can read the audit trail",
+            ));
+            return Error::make_verbose("Not the owner").wrap();
+        }
+        let args = match e {
+            Event::Audit(args) => args,
+            _ => String::default(),
+        };
+        let mut args = args.split_whitespace();
+        let chat_id = match args.next().and_then(|arg| find_chat_id(storage, arg)) {
+            Some(chat_id) => chat_id,
+            None => {
+                self.reply = Some(utils::make_reply_text(
+                    "Usage: /audit <chat id or @username> [number of entries]",
+                ));
+                return Error::make_verbose("No chat id").wrap();
+            }
+        };
+        let limit = args
+            .next()
+            .and_then(|arg| arg.parse().ok())
+            .unwrap_or(AUDIT_ENTRIES)
+            .min(AUDIT_ENTRIES_MAX);
+        match storage.audit_trail(chat_id, limit) {
+            Ok(entries) => {
+                self.reply = Some(utils::make_reply_text(&audit_text(chat_id, &entries)));
+                Ok(())
+            }
+            Err(e) => {
+                let error = Error::Verbose(format!("Can't read the audit trail: {}", e));
+                self.reply = Some(utils::make_reply_text(error.msg().unwrap().as_str()));
+                error.wrap()
+            }
+        }
+    }
+    fn on_set_active(&mut self, storage: &dyn Storage, e: Event) -> Result<(), Error> {
+        let (active, args) = match e {
+            Event::Reactivate(args) => (true, args),
+            Event::Deactivate(args) => (false, args),
+            _ => return Error::make_verbose("Not an activation").wrap(),
+        };
+        let action = if active { "reactivate" } else { "deactivate" };
+        if Config::owner_telegram_id() != Some(self.chat_id) {
+            self.reply = Some(utils::make_reply_text(&format!(
+                "Only the bot owner can {} users",
+                action
+            )));
+            return Error::make_verbose("Not the owner").wrap();
+        }
+        let chat_id = match find_chat_id(storage, args.trim()) {
+            Some(chat_id) => chat_id,
+            None => {
+                self.reply = Some(utils::make_reply_text(&format!(
+                    "Usage: /{} <chat id or @username>",
+                    action
+                )));
+                return Error::make_verbose("No chat id").wrap();
+            }
+        };
+        match storage.set_active(chat_id, active) {
+            Ok(true) => {
+                audit_table::record(
+                    storage,
+                    AuditEntry::admin(chat_id, action, &format!("by {}", self.chat_id)),
+                );
+                self.reply = Some(utils::make_reply_text(&if active {
+                    format!("{} is surveyed again", chat_id)
+                } else {
+                    format!("{} won't be surveyed anymore", chat_id)
+                }));
+                Ok(())
+            }
+            Ok(false) => {
+                let error =
+                    Error::Verbose(format!("Nobody is registered with chat id {}", chat_id));
+                self.reply = Some(utils::make_reply_text(error.msg().unwrap().as_str()));
+                error.wrap()
+            }
+            Err(e) => {
+                let error = Error::Verbose(format!("Can't {} {}: {}", action, chat_id, e));
+                self.reply = Some(utils::make_reply_text(error.msg().unwrap().as_str()));
+                error.wrap()
+            }
+        }
+    }
+    fn on_forget(&mut self, storage: &dyn Storage, _e: Event) -> Result<(), Error> {
+        match storage.find_user(self.chat_id) {
+            Ok(Some(_)) => {
+                self.reply = Some(utils::make_reply_inline(
+                    "This deletes your registration and all your answers, it can't be undone. Are you sure?",
+                    Some(&[&[Event::ForgetConfirm, Event::Back]]),
+                ));
+                Ok(())
+            }
+            Ok(None) => {
+                self.reply = Some(utils::make_reply_text("Nothing is stored about you"));
+                Error::Verbose("Nothing to forget".to_string()).wrap()
+            }
+            Err(e) => {
+                let error = Error::Verbose(format!("Can't find your data: {}", e));
+                self.reply = Some(utils::make_reply_text(error.msg().unwrap().as_str()));
+                error.wrap()
+            }
+        }
+    }
+    fn on_forget_confirm(&mut self, storage: &dyn Storage, _e: Event) -> Result<(), Error> {
+        match storage.forget_user(self.chat_id) {
+            Ok(_) => {
+                audit_table::record(
+                    storage,
+                    AuditEntry::deletion(
+                        audit_table::OPERATOR,
+                        "forget",
+                        "registration and answers of a user",
+                    ),
+                );
+                board::notify();
+                self.reply = Some(utils::make_reply_text(
+                    "Your registration and answers are deleted, send /start to register again",
+                ));
+                Ok(())
+            }
+            Err(e) => {
+                let error = Error::Verbose(format!("Can't delete your data: {}", e));
+                self.reply = Some(utils::make_reply_text(error.msg().unwrap().as_str()));
+                error.wrap()
+            }
+        }
+    }
+    fn on_survey(&mut self, _storage: &dyn Storage, _e: Event) -> Result<(), Error> {
         self.reply = Some(utils::reply_survey_event());
         Ok(())
     }
@@ -175,7 +378,7 @@ impl Data {
     //     self.reply = Some(utils::reply_not_emplemented());
     //     Ok(())
     // }
-    fn on_reg_name(&mut self, e: Event) -> Result<(), Error> {
+    fn on_reg_name(&mut self, _storage: &dyn Storage, e: Event) -> Result<(), Error> {
         if let Event::Name(name) = e {
             if name
                 .chars()
@@ -193,10 +396,10 @@ impl Data {
             Error::Verbose(format!("Unexpected event: {}", e)).wrap()
         }
     }
-    fn on_reg_manager(&mut self, e: Event) -> Result<(), Error> {
+    fn on_reg_manager(&mut self, storage: &dyn Storage, e: Event) -> Result<(), Error> {
         self.manager = e.to_user_string();
         let user = user_table::User::from(self.clone());
-        match user.insert_or_update() {
+        match storage.save_user(&user) {
             Ok(_) => {
                 self.reply = Some(utils::reply_survey_event());
                 Ok(())
@@ -208,9 +411,9 @@ impl Data {
             }
         }
     }
-    fn on_survey_allright(&mut self, _e: Event) -> Result<(), Error> {
+    fn on_survey_allright(&mut self, storage: &dyn Storage, _e: Event) -> Result<(), Error> {
         self.issues = None;
-        if let Err(e) = report::survey_save(&self) {
+        if let Err(e) = report::survey_save(storage, self) {
             log::debug!("Alright: {}", e.to_string());
             self.reply = Some(utils::make_reply_text(
                 format!("{}", e.to_string()).as_str(),
@@ -220,7 +423,7 @@ impl Data {
         }
         Ok(())
     }
-    fn on_survey_more(&mut self, _e: Event) -> Result<(), Error> {
+    fn on_survey_more(&mut self, _storage: &dyn Storage, _e: Event) -> Result<(), Error> {
         self.reply = Some(utils::make_reply_inline(
             "What's up?",
             Some(&[
@@ -231,7 +434,7 @@ impl Data {
         Ok(())
     }
 
-    fn on_survey_issue(&mut self, e: Event) -> Result<(), Error> {
+    fn on_survey_issue(&mut self, storage: &dyn Storage, e: Event) -> Result<(), Error> {
         let mut result: Result<(), Error> = Ok(());
         if self.issues.is_none() {
             self.issues = Some(Issues::default());
@@ -247,7 +450,7 @@ impl Data {
             _ => result = Error::Verbose(format!("Unexpected event: {}", e)).wrap(),
         }
 
-        if let Err(e) = report::survey_save(&self) {
+        if let Err(e) = report::survey_save(storage, self) {
             self.reply = Some(utils::make_reply_text(
                 format!("{}", e.to_string()).as_str(),
             ));
@@ -257,89 +460,71 @@ impl Data {
 
         result
     }
-    fn on_report(&mut self, _e: Event) -> Result<(), Error> {
+    fn on_report(&mut self, _storage: &dyn Storage, _e: Event) -> Result<(), Error> {
         self.reply = Some(utils::reply_report_event());
         Ok(())
     }
-    fn on_report_offset(&mut self, e: Event) -> Result<(), Error> {
+    fn on_report_offset(&mut self, storage: &dyn Storage, e: Event) -> Result<(), Error> {
         let mut result: Result<(), Error> = Ok(());
-        let mut report_startpoint = Utc::now();
         if self.report.is_none() {
             self.report = Some(ReportData::default());
         }
         let utc = Utc::now();
-        match e {
-            Event::ReportOffsetDay => {
-                report_startpoint = utc - chrono::Duration::days(1);
-                self.report.as_mut().unwrap().offset = report::TimeOffset::Day(1)
-            }
-            Event::ReportOffsetWeek => {
-                report_startpoint = utc - chrono::Duration::days(7);
-                self.report.as_mut().unwrap().offset = report::TimeOffset::Day(7)
+        let mut report_startpoint = utc;
+        match report_period(&e, utc) {
+            Some((offset, startpoint)) => {
+                report_startpoint = startpoint;
+                self.report.as_mut().unwrap().offset = offset
             }
-            Event::ReportOffsetMonth => {
-                let days = if utc.month() == chrono::Month::December.number_from_month() {
-                    chrono::NaiveDate::from_ymd_opt(utc.year() + 1, 1, 1).unwrap()
-                } else {
-                    chrono::NaiveDate::from_ymd_opt(utc.year(), utc.month() + 1, 1).unwrap()
-                }
-                .signed_duration_since(
-                    chrono::NaiveDate::from_ymd_opt(utc.year(), utc.month(), 1).unwrap(),
-                )
-                .num_days();
-                report_startpoint = utc - chrono::Duration::days(days);
-                self.report.as_mut().unwrap().offset = report::TimeOffset::Month(1)
-            }
-            _ => result = Error::Verbose(format!("Unexpected event: {}", e)).wrap(),
+            None => result = Error::Verbose(format!("Unexpected event: {}", e)).wrap(),
         }
         let report_type = self.report.as_ref().unwrap().report_type.clone();
         let offset = self.report.as_ref().unwrap().offset.clone();
-        let summary = make_report(report_type, offset);
-        // let mut period: report::TimeOffset = report::TimeOffset::default();
-        let mut table = table!();
-        if summary.is_ok() {
-            table.set_titles(row!["#", "Full Name", "Electricity", "Network", "Updated"]);
-            table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
-
-            // if summary.as_ref().unwrap().len() > 0 {
-            //     period = summary.as_ref().unwrap().first().unwrap().period.clone();
-            // }
-            let mut idx = 0;
-            for s in summary.unwrap() {
-                idx += 1;
-                let days = (Utc::now().date() - s.last_update.date()).num_days();
-                table.add_row(row![
-                    format!("{idx}"),
-                    format!("{}", s.name),
-                    format!("{:.1} %", s.availele * 100.0),
-                    format!("{:.1} %", s.availnet * 100.0),
-                    format!(
-                        "{}",
-                        if days != 0 {
-                            format!("{} days ago", days)
-                        } else {
-                            "Today".into()
-                        }
-                    )
-                ]);
-            }
-        }
-
-        self.reply = Some(utils::make_reply_text(
-            format!(
-                "<pre>Report: [{} - {}]:\n{}\n</pre>",
-                report_startpoint.date().format("%Y-%m-%d"),
-                utc.date().format("%Y-%m-%d"),
-                table.to_string(),
-            )
-            .as_str(),
+        let kind = std::mem::take(&mut self.report.as_mut().unwrap().kind);
+        self.reply = Some(render_report(
+            storage,
+            self.chat_id,
+            report_type,
+            kind,
+            offset,
+            report_startpoint,
+            utc,
         ));
         // log::debug!("Report [{}]:\n{}", period, text);
 
         result
     }
 
-    fn on_report_type(&mut self, e: Event) -> Result<(), Error> {
+    fn on_report_kind(&mut self, _storage: &dyn Storage, e: Event) -> Result<(), Error> {
+        let mut result: Result<(), Error> = Ok(());
+        if self.report.is_none() {
+            self.report = Some(ReportData::new());
+        }
+        match e {
+            Event::ReportHeatmap => self.report.as_mut().unwrap().kind = ReportKind::Heatmap,
+            Event::ReportSilent => self.report.as_mut().unwrap().kind = ReportKind::Silent,
+            Event::ReportTrend => self.report.as_mut().unwrap().kind = ReportKind::Trend,
+            _ => result = Error::Verbose(format!("Unexpected event: {}", e)).wrap(),
+        }
+
+        self.reply = Some(match self.report.as_ref().unwrap().kind {
+            ReportKind::Trend => utils::make_reply_inline(
+                "Compare which periods?",
+                Some(&[&[Event::ReportOffsetWeek, Event::ReportOffsetMonth]]),
+            ),
+            _ => utils::make_reply_inline(
+                "For which period?",
+                Some(&[&[
+                    Event::ReportOffsetDay,
+                    Event::ReportOffsetWeek,
+                    Event::ReportOffsetMonth,
+                ]]),
+            ),
+        });
+        result
+    }
+
+    fn on_report_type(&mut self, _storage: &dyn Storage, e: Event) -> Result<(), Error> {
         let mut result: Result<(), Error> = Ok(());
         if self.report.is_none() {
             self.report = Some(ReportData::new());
@@ -389,10 +574,20 @@ pub struct Issues {
     pub impcat: u8,
 }
 
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum ReportKind {
+    #[default]
+    Availability,
+    Heatmap,
+    Silent,
+    Trend,
+}
+
 #[derive(Debug, Clone)]
 pub struct ReportData {
     pub report_type: ReportType,
     pub offset: report::TimeOffset,
+    pub kind: ReportKind,
 }
 
 impl ReportData {
@@ -400,6 +595,7 @@ impl ReportData {
         Self {
             report_type: ReportType::All,
             offset: report::TimeOffset::Day(1),
+            kind: ReportKind::default(),
         }
     }
 }
@@ -411,7 +607,8 @@ impl Default for ReportData {
 impl State {
     pub fn consume(mut self, e: Event) -> Transition {
         self.data_mut().reply = None;
-        match (self, e) {
+        let from: &'static str = (&self).into();
+        let mut transition = match (self, e) {
             (State::New(data), e @ Event::Start) => {
                 Transition::make_valid(State::New, State::RegName, data, e, Data::on_start)
             }
@@ -508,6 +705,16 @@ impl State {
                 e,
                 Data::on_report_offset,
             ),
+            (
+                State::ReportFrame(data),
+                e @ (Event::ReportHeatmap | Event::ReportSilent | Event::ReportTrend),
+            ) => Transition::make_valid(
+                State::ReportFrame,
+                State::ReportFrame,
+                data,
+                e,
+                Data::on_report_kind,
+            ),
             (State::ReportFrame(data), e @ Event::Survey) => Transition::make_valid(
                 State::ReportFrame,
                 State::SurvEntry,
@@ -515,8 +722,34 @@ impl State {
                 e,
                 Data::on_survey,
             ),
+            (State::Forget(data), e @ Event::ForgetConfirm) => Transition::make_valid(
+                State::Idle,
+                State::Forgotten,
+                data,
+                e,
+                Data::on_forget_confirm,
+            ),
+            (State::Forget(data), e @ Event::Back) => {
+                Transition::make_valid(State::Idle, State::Idle, data, e, Data::on_menu)
+            }
+            (State::New(data), e @ Event::Forget) => {
+                Transition::make_valid(State::New, State::Forget, data, e, Data::on_forget)
+            }
+            (s, e @ Event::Forget) => Transition::make_valid(
+                State::Idle,
+                State::Forget,
+                s.data().clone(),
+                e,
+                Data::on_forget,
+            ),
             (s, e @ Event::Help) => Transition::make_general(s, e, Data::on_help),
             (s, e @ Event::Menu) => Transition::make_general(s, e, Data::on_menu),
+            (s, e @ Event::Digest) => Transition::make_general(s, e, Data::on_digest),
+            (s, e @ Event::Backup) => Transition::make_general(s, e, Data::on_backup),
+            (s, e @ Event::Audit(_)) => Transition::make_general(s, e, Data::on_audit),
+            (s, e @ (Event::Deactivate(_) | Event::Reactivate(_))) => {
+                Transition::make_general(s, e, Data::on_set_active)
+            }
             (s, e @ Event::Rename) => Transition::make_valid(
                 State::Idle,
                 State::RegName,
@@ -526,7 +759,9 @@ impl State {
             ),
 
             (s, e) => Transition::make_shallow(s, e),
-        }
+        };
+        transition.from = from;
+        transition
     }
     pub fn consume_as_str(self, text: &str) -> Transition {
         match self {
@@ -554,6 +789,8 @@ impl State {
             Self::SurvMore(data) => data,
             Self::Report(data) => data,
             Self::ReportFrame(data) => data,
+            Self::Forget(data) => data,
+            Self::Forgotten(data) => data,
         }
     }
 
@@ -567,6 +804,8 @@ impl State {
             Self::SurvMore(data) => data,
             Self::Report(data) => data,
             Self::ReportFrame(data) => data,
+            Self::Forget(data) => data,
+            Self::Forgotten(data) => data,
         }
     }
 
@@ -576,9 +815,10 @@ impl State {
 }
 
 type Wrapper = fn(Data) -> State;
-type Handler = fn(&mut Data, Event) -> Result<(), Error>;
+type Handler = fn(&mut Data, &dyn Storage, Event) -> Result<(), Error>;
 
 pub struct Transition {
+    from: &'static str,
     wrap: Option<Wrapper>,
     wrap_fallback: Option<Wrapper>,
     data: Option<Data>,
@@ -596,6 +836,7 @@ impl Transition {
         func: Handler,
     ) -> Self {
         Self {
+            from: "",
             wrap: Some(wrap),
             wrap_fallback: Some(wrap_fallback),
             data: Some(data),
@@ -606,6 +847,7 @@ impl Transition {
     }
     pub fn make_general(next: State, event: Event, func: Handler) -> Self {
         Self {
+            from: "",
             wrap: None,
             wrap_fallback: None,
             data: None,
@@ -616,6 +858,7 @@ impl Transition {
     }
     pub fn make_shallow(next: State, event: Event) -> Self {
         Self {
+            from: "",
             wrap: None,
             wrap_fallback: None,
             data: None,
@@ -625,27 +868,49 @@ impl Transition {
         }
     }
 
-    pub fn transit(self) -> State {
+    /// Runs the handler and records the transition in the audit trail.
+    pub fn transit(self, storage: &dyn Storage) -> State {
         log::debug!("<TR> event: {}", self.event);
-        if self.data.is_some() && self.wrap.is_some() && self.func.is_some() {
+        let event = self.event.audit_text();
+        let (state, result) = if self.data.is_some() && self.wrap.is_some() && self.func.is_some() {
             let mut data = self.data.unwrap();
-            match (self.func.unwrap())(&mut data, self.event) {
+            let result = (self.func.unwrap())(&mut data, storage, self.event);
+            let state = match result {
                 Ok(_) => data.wrap(self.wrap.unwrap()),
                 Err(_) => data.wrap(self.wrap_fallback.unwrap()),
-            }
+            };
+            (state, result)
         } else {
             let mut state = self.next.unwrap();
-            if self.func.is_some() {
-                // let mut data = state.data().clone();
-                let data = state.data_mut();
-                let _ = (self.func.unwrap())(data, self.event);
-            }
-            state
+            let result = match self.func {
+                Some(func) => func(state.data_mut(), storage, self.event),
+                None => Ok(()),
+            };
+            (state, result)
+        };
+        // The trail of a forgotten user is gone with the rest of the data
+        if let State::Forgotten(_) = state {
+            return state;
         }
+        let to: &'static str = (&state).into();
+        audit_table::record(
+            storage,
+            AuditEntry::transition(
+                state.data().chat_id,
+                self.from,
+                &event,
+                to,
+                &match result {
+                    Ok(_) => "ok".to_string(),
+                    Err(e) => format!("error: {}", e.msg().unwrap_or_else(|| e.to_string())),
+                },
+            ),
+        );
+        state
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ReportType {
     Me(i64),
     Team(String),
@@ -653,12 +918,12 @@ pub enum ReportType {
 }
 
 pub struct ReportSummary {
-    name: String,
-    manager: String,
-    period: report::TimeOffset,
-    availnet: f64,
-    availele: f64,
-    last_update: DateTime<Utc>,
+    pub name: String,
+    pub manager: String,
+    pub period: report::TimeOffset,
+    pub availnet: f64,
+    pub availele: f64,
+    pub last_update: DateTime<Utc>,
 }
 impl ReportSummary {
     fn new() -> Self {
@@ -675,19 +940,17 @@ impl ReportSummary {
     fn calculate_field<F: 'static + Fn(&report::Report) -> bool>(
         &mut self,
         collection: &Vec<report::Report>,
+        end: DateTime<Utc>,
         func: F,
     ) -> f64 {
-        let mut time_startpoint = Utc::now();
+        let mut time_startpoint = end;
 
         let mut time_nores_collected = chrono::Duration::zero();
         let mut time_nores_ts = DateTime::<Utc>::default();
         let mut elestat_last = true;
         // let mut time_entry_ts = DateTime::<Utc>::default();
         collection.iter().for_each(|i| {
-            let time_entry_ts = DateTime::<Utc>::from_utc(
-                NaiveDateTime::parse_from_str(&i.timestamp.value, Config::time_format()).unwrap(),
-                Utc,
-            );
+            let time_entry_ts = i.timestamp.value;
             if time_entry_ts < time_startpoint {
                 time_startpoint = time_entry_ts;
             }
@@ -711,7 +974,7 @@ impl ReportSummary {
 
         if time_nores_ts != DateTime::<Utc>::default() {
             time_nores_collected = time_nores_collected
-                .checked_add(&(Utc::now() - time_nores_ts))
+                .checked_add(&(end - time_nores_ts))
                 .unwrap();
         }
 
@@ -721,31 +984,28 @@ impl ReportSummary {
         //     time_startpoint
         // );
         1.0 - (time_nores_collected.num_minutes() as f64
-            / ((Utc::now() - time_startpoint).num_minutes() as f64)) /* 3 due to 8hours work day (24/3=8) */
+            / ((end - time_startpoint).num_minutes() as f64)) /* 3 due to 8hours work day (24/3=8) */
     }
 }
 impl From<&Vec<report::Report>> for ReportSummary {
     fn from(collection: &Vec<report::Report>) -> Self {
+        Self::from_collection(collection, Utc::now())
+    }
+}
+impl ReportSummary {
+    /// Availability of the answers in `collection`, counted up to `end`.
+    fn from_collection(collection: &Vec<report::Report>, end: DateTime<Utc>) -> Self {
         let mut summary = ReportSummary::new();
         if !collection.is_empty() {
             summary.name = collection.first().unwrap().name.value.clone();
             summary.manager = collection.first().unwrap().manager.value.clone();
-            summary.last_update = DateTime::<Utc>::from_utc(
-                NaiveDateTime::parse_from_str("1970-01-01 00:00:00", Config::time_format())
-                    .unwrap(),
-                Utc,
-            );
-            summary.availele = summary.calculate_field(collection, |i| i.electricity.value);
-            summary.availnet = summary.calculate_field(collection, |i| i.network.value);
+            summary.last_update = DateTime::<Utc>::default();
+            summary.availele = summary.calculate_field(collection, end, |i| i.electricity.value);
+            summary.availnet = summary.calculate_field(collection, end, |i| i.network.value);
 
             collection.iter().for_each(|i| {
-                if let Ok(date_time) =
-                    NaiveDateTime::parse_from_str(&i.timestamp.value, Config::time_format())
-                {
-                    let utc = DateTime::<Utc>::from_utc(date_time, Utc);
-                    if utc > summary.last_update {
-                        summary.last_update = utc;
-                    }
+                if i.timestamp.value > summary.last_update {
+                    summary.last_update = i.timestamp.value;
                 }
             });
         }
@@ -759,25 +1019,130 @@ impl From<&Vec<report::Report>> for ReportSummary {
     }
 }
 
-fn make_report(
+/// Entries shown by `/audit` without a number, and at most.
+const AUDIT_ENTRIES: usize = 20;
+const AUDIT_ENTRIES_MAX: usize = 100;
+
+/// A chat id as is, or the chat id of the user with the `@username`.
+fn find_chat_id(storage: &dyn Storage, arg: &str) -> Option<i64> {
+    match arg.strip_prefix('@') {
+        Some(username) => storage.all_users().ok()?.into_iter().find_map(|u| {
+            u.username
+                .value
+                .filter(|name| name.eq_ignore_ascii_case(username))
+                .map(|_| u.chat_id.value)
+        }),
+        None => arg.parse().ok(),
+    }
+}
+
+/// The entries in the order they happened.
+fn audit_text(chat_id: i64, entries: &[AuditEntry]) -> String {
+    if entries.is_empty() {
+        return format!("Nothing recorded about {}", chat_id);
+    }
+    let mut text = format!("<pre>Audit of {}:\n", chat_id);
+    for a in entries.iter().rev() {
+        let what = if a.kind.value == audit_table::KIND_TRANSITION {
+            format!(
+                "{} -{}-> {}",
+                a.from_state.value, a.event.value, a.to_state.value
+            )
+        } else {
+            format!("{} {}", a.kind.value, a.event.value)
+        };
+        text.push_str(&format!(
+            "{} {}: {}\n",
+            a.timestamp.value.format(Config::time_format()),
+            what,
+            a.result.value
+        ));
+    }
+    text.push_str("</pre>");
+    text
+}
+
+/// Offset and start of the period picked with one of the `ReportOffset*` events.
+pub(crate) fn report_period(
+    e: &Event,
+    utc: DateTime<Utc>,
+) -> Option<(report::TimeOffset, DateTime<Utc>)> {
+    match e {
+        Event::ReportOffsetDay => {
+            Some((report::TimeOffset::Day(1), utc - chrono::Duration::days(1)))
+        }
+        Event::ReportOffsetWeek => {
+            Some((report::TimeOffset::Day(7), utc - chrono::Duration::days(7)))
+        }
+        Event::ReportOffsetMonth => {
+            let days = if utc.month() == chrono::Month::December.number_from_month() {
+                chrono::NaiveDate::from_ymd_opt(utc.year() + 1, 1, 1).unwrap()
+            } else {
+                chrono::NaiveDate::from_ymd_opt(utc.year(), utc.month() + 1, 1).unwrap()
+            }
+            .signed_duration_since(
+                chrono::NaiveDate::from_ymd_opt(utc.year(), utc.month(), 1).unwrap(),
+            )
+            .num_days();
+            Some((
+                report::TimeOffset::Month(1),
+                utc - chrono::Duration::days(days),
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// The reply with a report of `kind` for the period `from..to`, `chat_id` names the heatmap picture.
+pub(crate) fn render_report(
+    storage: &dyn Storage,
+    chat_id: i64,
+    report_type: ReportType,
+    kind: ReportKind,
+    offset: report::TimeOffset,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> ReplyEnum {
+    match kind {
+        ReportKind::Availability => reply_availability(storage, report_type, offset, from, to),
+        ReportKind::Heatmap => reply_heatmap(storage, chat_id, report_type, offset, from, to),
+        ReportKind::Silent => reply_silent(storage, report_type, offset, from, to),
+        ReportKind::Trend => reply_trend(storage, report_type, offset, to),
+    }
+}
+
+fn select_dataset(
+    storage: &dyn Storage,
+    report_type: ReportType,
+    report_period: &report::TimeOffset,
+    end: DateTime<Utc>,
+) -> repo::Result<Vec<report::Report>> {
+    storage.reports(&report_type, report_period, end)
+}
+
+pub(crate) fn make_report(
+    storage: &dyn Storage,
     report_type: ReportType,
     report_period: report::TimeOffset,
+) -> Result<Vec<ReportSummary>, Error> {
+    make_report_until(storage, report_type, report_period, Utc::now())
+}
+
+/// Same as `make_report` for the period which ends at `end` instead of now.
+pub(crate) fn make_report_until(
+    storage: &dyn Storage,
+    report_type: ReportType,
+    report_period: report::TimeOffset,
+    end: DateTime<Utc>,
 ) -> Result<Vec<ReportSummary>, Error> {
     let mut report_summary: Vec<ReportSummary> = Vec::new();
-    let r = report::Report::new();
-    let dataset = match report_type {
-        ReportType::All => r.select_many_timed(&report_period),
-        ReportType::Me(chat_id) => {
-            let mut u = user_table::User::new();
-            u.chat_id.value = chat_id;
-            r.select_many_timed_by(&u.chat_id, &report_period)
-        }
-        ReportType::Team(manager_name) => {
-            let mut u = user_table::User::new();
-            u.manager.value = manager_name;
-            r.select_many_timed_by(&u.manager, &report_period)
-        }
-    };
+    if matches!(report_period, report::TimeOffset::Day(days) if days >= 7)
+        || matches!(report_period, report::TimeOffset::Month(_))
+    {
+        return make_report_rollup(storage, report_type, report_period, end)
+            .map_err(|e| Error::Verbose(format!("Can't build report: {}", e)));
+    }
+    let dataset = select_dataset(storage, report_type, &report_period, end);
     if dataset.is_ok() {
         let mut map: HashMap<String, Vec<report::Report>> = HashMap::new();
         for d in dataset.unwrap() {
@@ -785,7 +1150,7 @@ fn make_report(
             entry.push(d);
         }
         for k in map.keys() {
-            let mut report_one: ReportSummary = map.get(k).unwrap().into();
+            let mut report_one = ReportSummary::from_collection(map.get(k).unwrap(), end);
             report_one.period = report_period.clone();
             report_summary.push(report_one)
         }
@@ -802,8 +1167,445 @@ fn make_report(
     Ok(report_summary)
 }
 
+/// Week and month reports read the whole UTC days of `[from, end)` from the
+/// `daily_availability` rollup, the partial days at both ends from the answers.
+/// The rollup ends at the latest answer, the time since is added on top.
+fn make_report_rollup(
+    storage: &dyn Storage,
+    report_type: ReportType,
+    report_period: report::TimeOffset,
+    end: DateTime<Utc>,
+) -> repo::Result<Vec<ReportSummary>> {
+    let from = report_period.start_from(end);
+    let midnight = |day: chrono::NaiveDate| DateTime::<Utc>::from_utc(day.and_hms(0, 0, 0), Utc);
+    let first_midnight = if from == midnight(from.date_naive()) {
+        from
+    } else {
+        midnight(from.date_naive() + chrono::Duration::days(1))
+    };
+    let last_midnight = std::cmp::max(midnight(end.date_naive()), first_midnight);
+
+    let mut covered: HashMap<i64, Coverage> = HashMap::new();
+    // The partial days at both ends
+    for (start, stop) in [(from, first_midnight.min(end)), (last_midnight, end)] {
+        if start >= stop {
+            continue;
+        }
+        let seeds = storage.latest_reports_before(&report_type, start)?;
+        let answers = storage.reports(&report_type, &report::TimeOffset::Day(1), stop)?;
+        add_coverage(&mut covered, &seeds, &answers, start, stop);
+    }
+    if first_midnight < last_midnight {
+        let last_day = (last_midnight - chrono::Duration::days(1)).date_naive();
+        for t in storage.daily_totals(&report_type, first_midnight.date_naive(), last_day)? {
+            covered
+                .entry(t.chat_id)
+                .or_default()
+                .add(t.tracked, t.electricity_off, t.network_off);
+        }
+        for last in storage.latest_reports(&report_type)? {
+            let tail =
+                (last_midnight - std::cmp::max(last.timestamp.value, first_midnight)).num_seconds();
+            if tail > 0 {
+                covered
+                    .entry(last.chat_id.value)
+                    .or_default()
+                    .add_state(&last, tail);
+            }
+        }
+    }
+
+    let mut report_summary = Vec::new();
+    for last in storage.latest_reports_before(&report_type, end)? {
+        let c = match covered.get(&last.chat_id.value) {
+            Some(c) if c.tracked > 0 => c,
+            _ => continue,
+        };
+        let mut summary = ReportSummary::new();
+        summary.name = last.name.value.clone();
+        summary.manager = last.manager.value.clone();
+        summary.period = report_period.clone();
+        summary.availele = 1.0 - c.electricity_off as f64 / c.tracked as f64;
+        summary.availnet = 1.0 - c.network_off as f64 / c.tracked as f64;
+        summary.last_update = last.timestamp.value;
+        report_summary.push(summary);
+    }
+    report_summary.sort_by_key(|s| std::cmp::Reverse(s.last_update));
+    Ok(report_summary)
+}
+
+/// Seconds a user is accounted for within a report window.
+#[derive(Debug, Default, PartialEq)]
+struct Coverage {
+    tracked: i64,
+    electricity_off: i64,
+    network_off: i64,
+}
+
+impl Coverage {
+    fn add(&mut self, tracked: i64, electricity_off: i64, network_off: i64) {
+        self.tracked += tracked;
+        self.electricity_off += electricity_off;
+        self.network_off += network_off;
+    }
+
+    fn add_state(&mut self, answer: &report::Report, seconds: i64) {
+        self.add(
+            seconds,
+            if answer.electricity.value { 0 } else { seconds },
+            if answer.network.value { 0 } else { seconds },
+        );
+    }
+}
+
+/// Accounts `[start, stop)` from the answers, every answer lasts until the next one of its user.
+/// `seeds` are the last answers before `start`, `answers` may reach outside of the range.
+fn add_coverage(
+    covered: &mut HashMap<i64, Coverage>,
+    seeds: &[report::Report],
+    answers: &[report::Report],
+    start: DateTime<Utc>,
+    stop: DateTime<Utc>,
+) {
+    let mut current: HashMap<i64, (DateTime<Utc>, &report::Report)> = seeds
+        .iter()
+        .map(|seed| (seed.chat_id.value, (start, seed)))
+        .collect();
+    let mut inside: Vec<&report::Report> = answers
+        .iter()
+        .filter(|a| a.timestamp.value >= start && a.timestamp.value < stop)
+        .collect();
+    inside.sort_by_key(|a| a.timestamp.value);
+    for answer in inside {
+        let chat_id = answer.chat_id.value;
+        if let Some((since, previous)) = current.insert(chat_id, (answer.timestamp.value, answer)) {
+            let seconds = (answer.timestamp.value - since).num_seconds();
+            covered
+                .entry(chat_id)
+                .or_default()
+                .add_state(previous, seconds);
+        }
+    }
+    for (chat_id, (since, previous)) in current {
+        covered
+            .entry(chat_id)
+            .or_default()
+            .add_state(previous, (stop - since).num_seconds());
+    }
+}
+
+fn reply_availability(
+    storage: &dyn Storage,
+    report_type: ReportType,
+    offset: report::TimeOffset,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> ReplyEnum {
+    let summary = make_report(storage, report_type, offset);
+    let mut table = table!();
+    if summary.is_ok() {
+        table.set_titles(row!["#", "Full Name", "Electricity", "Network", "Updated"]);
+        table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+
+        let mut idx = 0;
+        for s in summary.unwrap() {
+            idx += 1;
+            let days = (Utc::now().date() - s.last_update.date()).num_days();
+            table.add_row(row![
+                format!("{idx}"),
+                format!("{}", s.name),
+                format!("{:.1} %", s.availele * 100.0),
+                format!("{:.1} %", s.availnet * 100.0),
+                format!(
+                    "{}",
+                    if days != 0 {
+                        format!("{} days ago", days)
+                    } else {
+                        "Today".into()
+                    }
+                )
+            ]);
+        }
+    }
+
+    utils::make_reply_text(
+        format!(
+            "<pre>Report: [{} - {}]:\n{}\n</pre>",
+            from.date().format("%Y-%m-%d"),
+            to.date().format("%Y-%m-%d"),
+            table.to_string(),
+        )
+        .as_str(),
+    )
+}
+
+fn reply_heatmap(
+    storage: &dyn Storage,
+    chat_id: i64,
+    report_type: ReportType,
+    offset: report::TimeOffset,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> ReplyEnum {
+    let start = offset.start_from(to);
+    let seeds = storage.latest_reports_before(&report_type, start);
+    let (seeds, dataset) = match (seeds, select_dataset(storage, report_type, &offset, to)) {
+        (Ok(seeds), Ok(dataset)) => (seeds, dataset),
+        (Err(e), _) | (_, Err(e)) => {
+            return utils::make_reply_text(&format!("Can't build heatmap: {}", e))
+        }
+    };
+    let utc_offset = Config::report_utc_offset();
+    let heatmap = heatmap::Heatmap::from_collection(&seeds, &dataset, start, to, utc_offset);
+    let caption = format!(
+        "<pre>No electricity, weekday x hour (UTC{}) [{} - {}]:\n{}\n</pre>",
+        utc_offset,
+        from.date().format("%Y-%m-%d"),
+        to.date().format("%Y-%m-%d"),
+        heatmap.to_text(),
+    );
+
+    #[cfg(feature = "png")]
+    {
+        // Removed once sent, a name per request keeps queued pictures apart
+        let path = std::env::temp_dir().join(format!(
+            "guardian_heatmap_{}_{}.png",
+            chat_id,
+            Utc::now().timestamp_nanos()
+        ));
+        match heatmap.to_png(&path) {
+            Ok(_) => return utils::make_reply_photo(path, &caption),
+            Err(e) => log::error!("Can't render heatmap png: {}", e),
+        }
+    }
+    #[cfg(not(feature = "png"))]
+    let _ = chat_id;
+
+    utils::make_reply_text(&caption)
+}
+
+fn reply_silent(
+    storage: &dyn Storage,
+    report_type: ReportType,
+    offset: report::TimeOffset,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> ReplyEnum {
+    let silent = storage.silent(&report_type, &offset);
+    let silent = match silent {
+        Ok(silent) => silent,
+        Err(e) => return utils::make_reply_text(&format!("Can't build report: {}", e)),
+    };
+
+    let mut table = table!();
+    table.set_titles(row!["#", "Full Name", "Manager", "Last answer"]);
+    table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+    for (idx, s) in silent.iter().enumerate() {
+        table.add_row(row![
+            format!("{}", idx + 1),
+            with_username(&s.name, s.username.as_deref()),
+            s.manager,
+            since_text(s.last_answer),
+        ]);
+    }
+
+    utils::make_reply_text(
+        format!(
+            "<pre>Silent: [{} - {}]:\n{}\n</pre>",
+            from.date().format("%Y-%m-%d"),
+            to.date().format("%Y-%m-%d"),
+            if silent.is_empty() {
+                "Everybody has answered".to_string()
+            } else {
+                table.to_string()
+            },
+        )
+        .as_str(),
+    )
+}
+
+/// Compares the period which ends now with the adjacent previous one.
+fn reply_trend(
+    storage: &dyn Storage,
+    report_type: ReportType,
+    offset: report::TimeOffset,
+    to: DateTime<Utc>,
+) -> ReplyEnum {
+    let from = offset.start_from(to);
+    let previous_from = offset.start_from(from);
+    let current =
+        make_report_until(storage, report_type.clone(), offset.clone(), to).unwrap_or_default();
+    let previous = make_report_until(storage, report_type, offset, from).unwrap_or_default();
+
+    let mut table = table!();
+    table.set_titles(row!["Full Name", "Electricity", "", "Network", ""]);
+    table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+    for s in current.iter() {
+        let before = previous.iter().find(|p| p.name == s.name);
+        table.add_row(row![
+            s.name,
+            format!("{:.1} %", s.availele * 100.0),
+            delta_text(s.availele, before.map(|p| p.availele)),
+            format!("{:.1} %", s.availnet * 100.0),
+            delta_text(s.availnet, before.map(|p| p.availnet)),
+        ]);
+    }
+    if let Some((availele, availnet)) = average_availability(&current) {
+        let before = average_availability(&previous);
+        table.add_row(row![
+            "Team",
+            format!("{:.1} %", availele * 100.0),
+            delta_text(availele, before.map(|b| b.0)),
+            format!("{:.1} %", availnet * 100.0),
+            delta_text(availnet, before.map(|b| b.1)),
+        ]);
+    }
+
+    utils::make_reply_text(
+        format!(
+            "<pre>Trend: [{} - {}] vs [{} - {}]:\n{}\n</pre>",
+            from.date().format("%Y-%m-%d"),
+            to.date().format("%Y-%m-%d"),
+            previous_from.date().format("%Y-%m-%d"),
+            from.date().format("%Y-%m-%d"),
+            if current.is_empty() {
+                "No answers in this period".to_string()
+            } else {
+                table.to_string()
+            },
+        )
+        .as_str(),
+    )
+}
+
+/// Average (electricity, network) availability over the report rows.
+pub(crate) fn average_availability(summary: &[ReportSummary]) -> Option<(f64, f64)> {
+    if summary.is_empty() {
+        return None;
+    }
+    let count = summary.len() as f64;
+    Some((
+        summary.iter().map(|s| s.availele).sum::<f64>() / count,
+        summary.iter().map(|s| s.availnet).sum::<f64>() / count,
+    ))
+}
+
+/// Change against the previous period in percentage points, `new` when there is nothing to compare.
+fn delta_text(now: f64, before: Option<f64>) -> String {
+    match before {
+        None => "new".into(),
+        Some(before) => {
+            let delta = (now - before) * 100.0;
+            if delta >= 0.05 {
+                format!("▲ {:.1}", delta)
+            } else if delta <= -0.05 {
+                format!("▼ {:.1}", -delta)
+            } else {
+                "=".into()
+            }
+        }
+    }
+}
+
+/// The name followed by the Telegram `@username` when it is known.
+pub(crate) fn with_username(name: &str, username: Option<&str>) -> String {
+    match username {
+        Some(username) => format!("{} @{}", name, username),
+        None => name.to_string(),
+    }
+}
+
+pub(crate) fn since_text(ts: Option<DateTime<Utc>>) -> String {
+    match ts {
+        None => "never".into(),
+        Some(ts) => {
+            let since = Utc::now() - ts;
+            if since.num_days() > 0 {
+                format!("{} days ago", since.num_days())
+            } else {
+                format!("{} hours ago", since.num_hours())
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use crate::{
+        bot::telecom::ReplyEnum,
+        db::{
+            repo::{MemoryStorage, SurveyRepository, UserRepository},
+            report::TimeOffset,
+            survey_table::SurveyEntry,
+            user_table::User,
+        },
+    };
+
+    use super::ReportType;
+
     #[test]
     pub fn test_fsm_alt() {}
+
+    #[test]
+    pub fn test_trend_adjacent_windows() {
+        let storage = MemoryStorage::new();
+        let mut user = User::new();
+        user.chat_id.value = 7;
+        user.name.value = "John Doe".to_string();
+        let user = storage.save_user(&user).unwrap();
+        for (ts, electricity, network) in [
+            (Utc.ymd(2022, 11, 6).and_hms(12, 0, 0), false, true),
+            (Utc.ymd(2022, 11, 10).and_hms(12, 0, 0), true, false),
+            (Utc.ymd(2022, 11, 14).and_hms(6, 0, 0), false, true),
+            (Utc.ymd(2022, 11, 15).and_hms(6, 0, 0), true, true),
+        ] {
+            let mut s = SurveyEntry::new();
+            s.user_id.value = user.id.value;
+            s.timestamp.value = ts;
+            s.electricity.value = electricity;
+            s.network.value = network;
+            storage.add_survey(&s).unwrap();
+        }
+        let week = 7.0 * 24.0 * 3600.0;
+        let end = Utc.ymd(2022, 11, 21).and_hms(12, 0, 0);
+        let from = TimeOffset::Day(7).start_from(end);
+
+        // [11-07 12:00, 11-14 12:00): the answer before the window counts from its start,
+        // the one after it is left out
+        let previous =
+            super::make_report_until(&storage, ReportType::All, TimeOffset::Day(7), from).unwrap();
+        assert_eq!(previous.len(), 1);
+        assert!((previous[0].availele - (1.0 - 280800.0 / week)).abs() < 1e-9);
+        assert!((previous[0].availnet - (1.0 - 324000.0 / week)).abs() < 1e-9);
+        assert_eq!(
+            previous[0].last_update,
+            Utc.ymd(2022, 11, 14).and_hms(6, 0, 0)
+        );
+
+        // [11-14 12:00, 11-21 12:00): electricity is off until 11-15 06:00
+        let current =
+            super::make_report_until(&storage, ReportType::All, TimeOffset::Day(7), end).unwrap();
+        assert_eq!(current.len(), 1);
+        assert!((current[0].availele - (1.0 - 64800.0 / week)).abs() < 1e-9);
+        assert!((current[0].availnet - 1.0).abs() < 1e-9);
+
+        match super::reply_trend(&storage, ReportType::All, TimeOffset::Day(7), end) {
+            ReplyEnum::Text(text) => {
+                assert!(text.contains("89.3 %"), "{}", text);
+                assert!(text.contains("▲ 35.7"), "{}", text);
+                assert!(text.contains("▲ 53.6"), "{}", text);
+            }
+            _ => panic!("Trend is a text reply"),
+        }
+    }
+
+    #[test]
+    pub fn test_trend_delta_text() {
+        assert_eq!(super::delta_text(0.9, Some(0.8)), "▲ 10.0");
+        assert_eq!(super::delta_text(0.75, Some(0.8)), "▼ 5.0");
+        assert_eq!(super::delta_text(0.8, Some(0.8)), "=");
+        assert_eq!(super::delta_text(0.8, None), "new");
+    }
 }
@@ -0,0 +1,245 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, NaiveDateTime, Timelike, Utc};
+use frankenstein::{CallbackQuery, Chat, ChatType, Message};
+use prettytable::{format, row, table};
+
+use crate::{
+    config::Config,
+    db::{repo, report, team_chat_table::TeamChat, user_table::User},
+};
+
+use super::{
+    board,
+    fsm::{self, Event, ReportType, UserDisplay},
+    outbox,
+    telapi::Telapi,
+    telecom::{ReplyEnum, Telecom},
+    utils,
+};
+
+const ADMINS_ONLY: &str = "Only the admins of this chat can do this";
+const NOT_LINKED: &str = "This chat is not linked to a team yet, please use /link_team";
+
+/// Group chats and channels are served without the per-user state machine,
+/// `chat_id` of a group is never treated as a user.
+pub fn is_group_chat(chat: &Chat) -> bool {
+    matches!(
+        chat.type_field,
+        ChatType::Group | ChatType::Supergroup | ChatType::Channel
+    )
+}
+
+pub fn handle_message(api: Arc<Telapi>, msg: &Message) {
+    let text = msg.text.clone().unwrap_or_default();
+    let reply = match command(&text) {
+        "/link_team" if !may_manage(&api, &msg.chat, msg.from.as_deref()) => {
+            Some(utils::make_reply_text(ADMINS_ONLY))
+        }
+        "/link_team" => Some(utils::make_reply_inline(
+            "Which team does this chat belong to?",
+            Some(&[
+                &[Event::LMElina],
+                &[Event::LMMikhail],
+                &[Event::LMOleksandr],
+                &[Event::LMVladyslav],
+                &[Event::LMYevgen],
+            ]),
+        )),
+        "/team_status" => Some(match TeamChat::select_by_chat_id(msg.chat.id) {
+            Ok(Some(team_chat)) => utils::make_reply_text(&team_status(&team_chat.manager.value)),
+            Ok(None) => utils::make_reply_text(NOT_LINKED),
+            Err(e) => utils::make_reply_text(&format!("Can't find the team: {}", e)),
+        }),
+        "/board" if !may_manage(&api, &msg.chat, msg.from.as_deref()) => {
+            Some(utils::make_reply_text(ADMINS_ONLY))
+        }
+        "/board" => match TeamChat::select_by_chat_id(msg.chat.id) {
+            Ok(Some(team_chat)) => board::create(&api, msg.chat.id, &team_chat.manager.value)
+                .err()
+                .map(|e| utils::make_reply_text(&format!("Can't create the board: {:?}", e))),
+            Ok(None) => Some(utils::make_reply_text(NOT_LINKED)),
+            Err(e) => Some(utils::make_reply_text(&format!(
+                "Can't find the team: {}",
+                e
+            ))),
+        },
+        _ => None,
+    };
+    if let Some(reply) = reply {
+        Telecom::reply(api, msg.chat.id, reply);
+    }
+}
+
+pub fn handle_query(api: Arc<Telapi>, query: &CallbackQuery) {
+    let (chat, message_id) = match &query.message {
+        Some(message) => (&message.chat, message.message_id),
+        None => return,
+    };
+    let chat_id = chat.id;
+    let event = Event::from_string(&query.data.clone().unwrap_or_default());
+    if event.is_some() && !may_manage(&api, chat, Some(&query.from)) {
+        log::info!("{} is not an admin of chat {}", query.from.id, chat_id);
+        return;
+    }
+    let reply = match event {
+        Some(
+            e @ (Event::LMElina
+            | Event::LMMikhail
+            | Event::LMOleksandr
+            | Event::LMVladyslav
+            | Event::LMYevgen),
+        ) => {
+            let mut team_chat = TeamChat::new();
+            team_chat.chat_id.value = chat_id;
+            team_chat.manager.value = e.to_user_string();
+            match team_chat.insert_or_update() {
+                Ok(_) => utils::make_reply_text(&format!(
+                    "This chat is linked to the team of {}, see /team_status",
+                    team_chat.manager.value
+                )),
+                Err(e) => utils::make_reply_text(&format!("Can't link the team: {}", e)),
+            }
+        }
+        _ => return,
+    };
+    Telecom::replace(api, chat_id, message_id, reply, outbox::default_ttl());
+}
+
+/// Posts the daily summary into every linked group, once a day after `TEAM_SUMMARY_HOUR`.
+pub fn send_due_summaries() {
+    let offset = Config::report_utc_offset();
+    let now = Utc::now().with_timezone(&offset);
+    if now.hour() < Config::team_summary_hour() {
+        return;
+    }
+    let team_chats = match TeamChat::new().select_all() {
+        Ok(team_chats) => team_chats,
+        Err(e) => {
+            log::error!("Can't read team chats: {}", e);
+            return;
+        }
+    };
+    for mut team_chat in team_chats {
+        let sent_today =
+            NaiveDateTime::parse_from_str(&team_chat.last_summary.value, Config::time_format())
+                .map(|last| {
+                    DateTime::<Utc>::from_utc(last, Utc)
+                        .with_timezone(&offset)
+                        .date()
+                        == now.date()
+                })
+                .unwrap_or(false);
+        if sent_today {
+            continue;
+        }
+
+        let text = daily_summary(&team_chat.manager.value);
+        match outbox::enqueue(
+            team_chat.chat_id.value,
+            ReplyEnum::Text(text),
+            outbox::default_ttl(),
+        ) {
+            Ok(()) => {
+                team_chat.last_summary.value = Utc::now().format(Config::time_format()).to_string();
+                if let Err(e) = team_chat.insert_or_update() {
+                    log::error!("Can't store team summary delivery: {}", e);
+                }
+            }
+            Err(e) => log::error!(
+                "Can't queue team summary for {}: {}",
+                team_chat.chat_id.value,
+                e
+            ),
+        }
+    }
+}
+
+/// Current state of every team member, taken from their latest answer.
+pub fn team_status(manager: &str) -> String {
+    let storage = repo::storage();
+    let members: Vec<User> = storage
+        .all_users()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|u| u.manager.value == manager)
+        .collect();
+    let latest = storage
+        .latest_reports(&ReportType::Team(manager.to_string()))
+        .unwrap_or_default();
+
+    let mut table = table!();
+    table.set_titles(row!["Full Name", "Electricity", "Network", "Updated"]);
+    table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+    for member in members.iter() {
+        match latest
+            .iter()
+            .find(|r| r.chat_id.value == member.chat_id.value)
+        {
+            Some(r) => table.add_row(row![
+                member.name.value,
+                utils::on_off(r.electricity.value),
+                utils::on_off(r.network.value),
+                fsm::since_text(Some(r.timestamp.value)),
+            ]),
+            None => table.add_row(row![member.name.value, "?", "?", fsm::since_text(None)]),
+        };
+    }
+
+    format!(
+        "<pre>Team of {}, now:\n{}\n</pre>",
+        utils::escape_html(manager),
+        if members.is_empty() {
+            "Nobody is registered yet".to_string()
+        } else {
+            utils::escape_html(&table.to_string())
+        }
+    )
+}
+
+fn daily_summary(manager: &str) -> String {
+    let summary = fsm::make_report(
+        repo::storage().as_ref(),
+        ReportType::Team(manager.to_string()),
+        report::TimeOffset::Day(1),
+    )
+    .unwrap_or_default();
+    let mut text = team_status(manager);
+    if let Some((availele, availnet)) = fsm::average_availability(&summary) {
+        text.push_str(&format!(
+            "Last 24h availability: electricity {:.1} %, network {:.1} %",
+            availele * 100.0,
+            availnet * 100.0,
+        ));
+    }
+    text
+}
+
+/// Team links and boards are set up by the group admins or the owner of the bot,
+/// channel posts come from the admins anyway.
+fn may_manage(api: &Telapi, chat: &Chat, from: Option<&frankenstein::User>) -> bool {
+    if chat.type_field == ChatType::Channel {
+        return true;
+    }
+    let user = match from {
+        Some(user) => user,
+        None => return false,
+    };
+    if Config::owner_telegram_id() == Some(user.id as i64) {
+        return true;
+    }
+    match api.is_chat_admin(chat.id, user.id) {
+        Ok(admin) => admin,
+        Err(e) => {
+            log::warn!("Can't check the admins of chat {}: {:?}", chat.id, e);
+            false
+        }
+    }
+}
+
+/// Commands in groups come as `/command@bot_name args`.
+fn command(text: &str) -> &str {
+    text.split(|c: char| c == '@' || c.is_whitespace())
+        .next()
+        .unwrap_or_default()
+}
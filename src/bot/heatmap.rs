@@ -0,0 +1,223 @@
+use chrono::{DateTime, Datelike, Duration, DurationRound, FixedOffset, Timelike, Utc};
+use std::collections::HashMap;
+
+use crate::db::report;
+
+const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+const SHADES: [char; 5] = ['·', '░', '▒', '▓', '█'];
+
+/// Outage minutes bucketed by weekday (rows, Monday first) and hour of day (columns),
+/// in the local time of `offset`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heatmap {
+    minutes: [[u32; 24]; 7],
+    offset: FixedOffset,
+}
+
+impl Heatmap {
+    pub fn new(offset: FixedOffset) -> Self {
+        Self {
+            minutes: [[0; 24]; 7],
+            offset,
+        }
+    }
+
+    /// Builds the heatmap from raw survey rows within `[start, end)`, counting the time spent
+    /// without electricity. `seeds` are the last answers before `start`, an outage already
+    /// going on counts from `start`.
+    pub fn from_collection(
+        seeds: &[report::Report],
+        collection: &[report::Report],
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        offset: FixedOffset,
+    ) -> Self {
+        let mut heatmap = Self::new(offset);
+        let seeds: Vec<report::Report> = seeds
+            .iter()
+            .cloned()
+            .map(|mut seed| {
+                seed.timestamp.value = start;
+                seed
+            })
+            .collect();
+        let mut per_user: HashMap<i64, Vec<&report::Report>> = HashMap::new();
+        for r in seeds.iter().chain(collection) {
+            per_user.entry(r.chat_id.value).or_default().push(r);
+        }
+        for entries in per_user.values_mut() {
+            entries.sort_by_key(|r| r.timestamp.value);
+            for (from, to) in outage_intervals(entries, end, |r| r.electricity.value) {
+                heatmap.add_interval(from, to);
+            }
+        }
+        heatmap
+    }
+
+    /// Spreads `[from, to)` over the hour buckets it touches.
+    pub fn add_interval(&mut self, from: DateTime<Utc>, to: DateTime<Utc>) {
+        let mut cursor = from.with_timezone(&self.offset);
+        let to = to.with_timezone(&self.offset);
+        while cursor < to {
+            let hour_end = cursor.duration_trunc(Duration::hours(1)).unwrap() + Duration::hours(1);
+            let chunk_end = if hour_end < to { hour_end } else { to };
+            let day = cursor.weekday().num_days_from_monday() as usize;
+            let hour = cursor.hour() as usize;
+            self.minutes[day][hour] += (chunk_end - cursor).num_minutes() as u32;
+            cursor = chunk_end;
+        }
+    }
+
+    pub fn minutes(&self, weekday: usize, hour: usize) -> u32 {
+        self.minutes[weekday][hour]
+    }
+
+    pub fn max(&self) -> u32 {
+        self.minutes
+            .iter()
+            .flat_map(|row| row.iter())
+            .copied()
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn total(&self) -> u32 {
+        self.minutes.iter().flat_map(|row| row.iter()).sum()
+    }
+
+    fn level(&self, value: u32) -> usize {
+        let max = self.max();
+        if value == 0 || max == 0 {
+            0
+        } else {
+            1 + ((value as usize * (SHADES.len() - 1) - 1) / max as usize)
+        }
+    }
+
+    /// Compact monospace rendering, meant to be wrapped into `<pre>`.
+    pub fn to_text(&self) -> String {
+        let mut text = String::from("    0     6     12    18    \n");
+        for (day, name) in WEEKDAYS.iter().enumerate() {
+            text.push_str(name);
+            text.push(' ');
+            for hour in 0..24 {
+                text.push(SHADES[self.level(self.minutes[day][hour])]);
+            }
+            text.push('\n');
+        }
+        text.push_str(&format!(
+            "{} none .. {} {} min/h, total {} h",
+            SHADES[0],
+            SHADES[SHADES.len() - 1],
+            self.max(),
+            self.total() / 60
+        ));
+        text
+    }
+
+    #[cfg(feature = "png")]
+    pub fn to_png(&self, path: &std::path::Path) -> Result<(), png::EncodingError> {
+        const CELL: usize = 20;
+        let (width, height) = (24 * CELL, 7 * CELL);
+        let mut pixels = vec![255u8; width * height * 3];
+        let max = self.max().max(1) as f64;
+        for day in 0..7 {
+            for hour in 0..24 {
+                let ratio = self.minutes[day][hour] as f64 / max;
+                let fade = (255.0 * (1.0 - ratio)) as u8;
+                for y in day * CELL..(day + 1) * CELL - 1 {
+                    for x in hour * CELL..(hour + 1) * CELL - 1 {
+                        let idx = (y * width + x) * 3;
+                        pixels[idx..idx + 3].copy_from_slice(&[255, fade, fade]);
+                    }
+                }
+            }
+        }
+        let file = std::fs::File::create(path)?;
+        let mut encoder =
+            png::Encoder::new(std::io::BufWriter::new(file), width as u32, height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()?.write_image_data(&pixels)
+    }
+}
+
+/// Time ranges during which `func` was false, taken from entries sorted by time.
+/// The last open range is closed at `end`.
+pub fn outage_intervals<F: Fn(&report::Report) -> bool>(
+    entries: &[&report::Report],
+    end: DateTime<Utc>,
+    func: F,
+) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    let mut intervals = Vec::new();
+    let mut outage_start: Option<DateTime<Utc>> = None;
+    for r in entries {
+        let ts = r.timestamp.value;
+        match (outage_start, func(r)) {
+            (None, false) => outage_start = Some(ts),
+            (Some(start), true) => {
+                intervals.push((start, ts));
+                outage_start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(start) = outage_start {
+        if start < end {
+            intervals.push((start, end));
+        }
+    }
+    intervals
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, FixedOffset, TimeZone, Utc};
+
+    use crate::db::report::Report;
+
+    use super::Heatmap;
+
+    #[test]
+    pub fn test_heatmap_split_by_hour() {
+        let mut heatmap = Heatmap::new(FixedOffset::east_opt(0).unwrap());
+        // 2022-11-07 is a Monday
+        let from = Utc.ymd(2022, 11, 7).and_hms(10, 30, 0);
+        let to = Utc.ymd(2022, 11, 7).and_hms(12, 15, 0);
+        heatmap.add_interval(from, to);
+        assert_eq!(heatmap.minutes(0, 10), 30);
+        assert_eq!(heatmap.minutes(0, 11), 60);
+        assert_eq!(heatmap.minutes(0, 12), 15);
+        assert_eq!(heatmap.total(), 105);
+
+        let mut shifted = Heatmap::new(FixedOffset::east_opt(2 * 3600).unwrap());
+        shifted.add_interval(
+            Utc.ymd(2022, 11, 6).and_hms(23, 0, 0),
+            Utc.ymd(2022, 11, 7).and_hms(0, 0, 0),
+        );
+        assert_eq!(shifted.minutes(0, 1), 60);
+    }
+
+    #[test]
+    pub fn test_heatmap_outage_before_start() {
+        let start = Utc.ymd(2022, 11, 7).and_hms(10, 0, 0);
+        let mut seed = Report::new();
+        seed.timestamp.value = start - Duration::days(2);
+        seed.electricity.value = false;
+        let mut back = seed.clone();
+        back.timestamp.value = start + Duration::minutes(90);
+        back.electricity.value = true;
+
+        // Only the part of the outage within the window counts
+        let end = start + Duration::days(1);
+        let utc = FixedOffset::east_opt(0).unwrap();
+        let heatmap = Heatmap::from_collection(&[seed.clone()], &[back.clone()], start, end, utc);
+        assert_eq!(heatmap.total(), 90);
+        assert_eq!(heatmap.minutes(0, 10), 60);
+        let kyiv = FixedOffset::east_opt(2 * 3600).unwrap();
+        let heatmap = Heatmap::from_collection(&[seed], &[back], start, end, kyiv);
+        assert_eq!(heatmap.minutes(0, 12), 60);
+        let heatmap = Heatmap::from_collection(&[], &[], start, end, utc);
+        assert_eq!(heatmap.total(), 0);
+    }
+}
@@ -0,0 +1,181 @@
+use std::sync::Arc;
+
+use frankenstein::{
+    InlineQuery, InlineQueryResult, InlineQueryResultArticle, InputMessageContent,
+    InputTextMessageContent, ParseMode,
+};
+
+use crate::db::{repo, report, user_table::User};
+
+use super::{
+    fsm::{self, ReportType},
+    group,
+    telapi::Telapi,
+    utils,
+};
+
+const MAX_RESULTS: usize = 20;
+
+/// Answers `@bot <name or team>` with status cards the querying user is allowed to see.
+pub fn handle(api: Arc<Telapi>, query: &InlineQuery) {
+    let results = match cards(query.from.id as i64, &query.query) {
+        Ok(results) => results,
+        Err(e) => {
+            log::error!("Can't answer inline query: {}", e);
+            Vec::new()
+        }
+    };
+    if let Err(e) = api.answer_inline(&query.id, results) {
+        log::error!("Can't answer inline query {}: {:?}", query.id, e);
+    }
+}
+
+fn cards(chat_id: i64, text: &str) -> repo::Result<Vec<InlineQueryResult>> {
+    let users = repo::storage().all_users()?;
+    // A private chat id is the telegram user id, unregistered users see nothing
+    let me = match users.iter().find(|u| u.chat_id.value == chat_id) {
+        Some(me) => me,
+        None => return Ok(Vec::new()),
+    };
+    let text = text.trim().to_lowercase();
+
+    let mut results = Vec::new();
+    let mut teams: Vec<&str> = vec![&me.manager.value];
+    if users.iter().any(|u| u.manager.value == me.name.value) {
+        teams.push(&me.name.value);
+    }
+    for team in teams {
+        if team.to_lowercase().contains(&text) {
+            results.push(team_card(team));
+        }
+    }
+    for user in users
+        .iter()
+        .filter(|u| is_visible(me, u) && u.name.value.to_lowercase().contains(&text))
+    {
+        // One broken card doesn't hide the others
+        match user_card(user) {
+            Ok(card) => results.push(card),
+            Err(e) => log::error!("Can't make the card of {}: {}", user.chat_id.value, e),
+        }
+    }
+    results.truncate(MAX_RESULTS);
+    Ok(results)
+}
+
+/// Everybody sees themselves, their teammates and the people they manage.
+fn is_visible(me: &User, other: &User) -> bool {
+    other.chat_id.value == me.chat_id.value
+        || other.manager.value == me.manager.value
+        || other.manager.value == me.name.value
+}
+
+fn user_card(user: &User) -> repo::Result<InlineQueryResult> {
+    let latest = repo::storage().latest_reports(&ReportType::Me(user.chat_id.value))?;
+    let status = match latest.first() {
+        Some(r) => format!(
+            "electricity {}, network {}, updated {}",
+            utils::on_off(r.electricity.value),
+            utils::on_off(r.network.value),
+            fsm::since_text(Some(r.timestamp.value))
+        ),
+        None => "no answers yet".to_string(),
+    };
+    let availability = fsm::make_report(
+        repo::storage().as_ref(),
+        ReportType::Me(user.chat_id.value),
+        report::TimeOffset::Day(7),
+    )
+    .ok()
+    .and_then(|summary| fsm::average_availability(&summary))
+    .map(|(availele, availnet)| {
+        format!(
+            "7 days: electricity {:.1} %, network {:.1} %",
+            availele * 100.0,
+            availnet * 100.0
+        )
+    })
+    .unwrap_or_else(|| "7 days: no data".to_string());
+
+    Ok(article(
+        format!("user_{}", user.chat_id.value),
+        user.name.value.clone(),
+        format!("{}\n{}", status, availability),
+        format!(
+            "<b>{}</b>\nNow: {}\n{}",
+            utils::escape_html(&user.name.value),
+            status,
+            availability
+        ),
+    ))
+}
+
+fn team_card(manager: &str) -> InlineQueryResult {
+    let availability = fsm::make_report(
+        repo::storage().as_ref(),
+        ReportType::Team(manager.to_string()),
+        report::TimeOffset::Day(7),
+    )
+    .ok()
+    .and_then(|summary| fsm::average_availability(&summary))
+    .map(|(availele, availnet)| {
+        format!(
+            "7 days: electricity {:.1} %, network {:.1} %",
+            availele * 100.0,
+            availnet * 100.0
+        )
+    })
+    .unwrap_or_else(|| "7 days: no data".to_string());
+
+    article(
+        format!("team_{}", manager.replace(' ', "_")),
+        format!("Team of {}", manager),
+        availability.clone(),
+        format!("{}{}", group::team_status(manager), availability),
+    )
+}
+
+fn article(id: String, title: String, description: String, text: String) -> InlineQueryResult {
+    let content = InputTextMessageContent::builder()
+        .message_text(text)
+        .parse_mode(ParseMode::Html)
+        .build();
+    InlineQueryResult::Article(
+        InlineQueryResultArticle::builder()
+            .id(id)
+            .title(title)
+            .description(description)
+            .input_message_content(InputMessageContent::Text(content))
+            .build(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::user_table::User;
+
+    use super::is_visible;
+
+    fn user(chat_id: i64, name: &str, manager: &str) -> User {
+        let mut u = User::new();
+        u.chat_id.value = chat_id;
+        u.name.value = name.to_string();
+        u.manager.value = manager.to_string();
+        u
+    }
+
+    #[test]
+    pub fn test_inline_visibility() {
+        let boss = user(1, "Boss", "Top");
+        let me = user(2, "Me", "Boss");
+        let mate = user(3, "Mate", "Boss");
+        let stranger = user(4, "Stranger", "Other");
+
+        assert!(is_visible(&me, &me));
+        assert!(is_visible(&me, &mate));
+        assert!(!is_visible(&me, &stranger));
+        assert!(!is_visible(&me, &boss));
+        assert!(is_visible(&boss, &me));
+        assert!(!is_visible(&boss, &stranger));
+    }
+}
@@ -0,0 +1,204 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Condvar, Mutex},
+    thread, time,
+};
+
+use chrono::{Duration, Utc};
+
+use crate::{
+    config::Config,
+    db::outbox_table::{OutboxMessage, STATUS_FAILED, STATUS_PENDING, STATUS_SENT},
+};
+
+use super::{
+    telapi::Telapi,
+    telecom::{ReplyEnum, Telecom},
+    throttle,
+};
+
+/// Messages read from the outbox at once.
+const BATCH: usize = 50;
+
+/// How long the sender sleeps when nothing is due, unless woken by a new message.
+const IDLE_WAIT: time::Duration = time::Duration::from_secs(30);
+
+/// Deliveries failing in a row wait twice as long as the previous one, up to a minute.
+const BACKOFF_MIN: time::Duration = time::Duration::from_secs(1);
+const BACKOFF_MAX: time::Duration = time::Duration::from_secs(60);
+
+/// Sent, failed and expired messages are kept this many days for troubleshooting.
+const KEEP_DONE_DAYS: i64 = 7;
+const CLEANUP_INTERVAL: time::Duration = time::Duration::from_secs(60 * 60);
+
+/// Set when a message is queued, so the sender doesn't wait for `IDLE_WAIT`.
+static QUEUED: (Mutex<bool>, Condvar) = (Mutex::new(false), Condvar::new());
+
+enum Delivery {
+    /// Everything due is sent.
+    Done,
+    /// A full batch went out, more may be pending.
+    More,
+    /// Telegram doesn't answer at all, the rest stays queued.
+    Unreachable,
+}
+
+/// Replies are useful for a day by default, see `Config::outbox_ttl_in_hours`.
+pub fn default_ttl() -> Duration {
+    Duration::hours(Config::outbox_ttl_in_hours())
+}
+
+/// Survey prompts go stale quickly, the timer asks again when nobody answered.
+pub fn survey_ttl() -> Duration {
+    Duration::hours(1).min(default_ttl())
+}
+
+/// Stores `reply` to be sent into `chat_id` by the sender thread, dropped if still unsent after `ttl`.
+pub fn enqueue(chat_id: i64, reply: ReplyEnum, ttl: Duration) -> rusqlite::Result<()> {
+    let (method, payload) = match Telapi::request_of(chat_id, reply) {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+    OutboxMessage::pending(chat_id, method, payload, Utc::now() + ttl).insert()?;
+    let (queued, wake) = &QUEUED;
+    *queued.lock().unwrap() = true;
+    wake.notify_one();
+    Ok(())
+}
+
+/// Delivers the queued messages in order, those left pending by a previous run first.
+pub fn start_thread(api: Arc<Telapi>) {
+    thread::spawn(move || {
+        let mut failures = 0;
+        let mut last_cleanup: Option<time::Instant> = None;
+        loop {
+            if !matches!(last_cleanup, Some(t) if t.elapsed() < CLEANUP_INTERVAL) {
+                last_cleanup = Some(time::Instant::now());
+                cleanup();
+            }
+            match deliver(&api) {
+                Delivery::More => failures = 0,
+                Delivery::Done => {
+                    failures = 0;
+                    wait_for_queued(idle_wait());
+                }
+                Delivery::Unreachable => {
+                    let delay = BACKOFF_MIN
+                        .checked_mul(2u32.saturating_pow(failures))
+                        .map_or(BACKOFF_MAX, |d| d.min(BACKOFF_MAX));
+                    failures += 1;
+                    log::warn!("Delivering the outbox again in {:?}", delay);
+                    thread::sleep(delay);
+                }
+            }
+        }
+    });
+}
+
+fn deliver(api: &Telapi) -> Delivery {
+    let now = Utc::now();
+    match OutboxMessage::expire(now) {
+        Ok(0) => (),
+        Ok(expired) => log::warn!("Dropped {} messages not sent in time", expired),
+        Err(e) => log::error!("Can't expire outbox messages: {}", e),
+    }
+    let messages = match OutboxMessage::select_pending(now, BATCH) {
+        Ok(messages) => messages,
+        Err(e) => {
+            log::error!("Can't read the outbox: {}", e);
+            return Delivery::Unreachable;
+        }
+    };
+    let count = messages.len();
+
+    // Chats with a message put off, their later messages wait to keep the order
+    let mut put_off = HashSet::new();
+    for mut message in messages {
+        if put_off.contains(&message.chat_id.value) {
+            continue;
+        }
+        message.attempts.value += 1;
+        let result = api.send_request(&message.method.value, &message.payload.value);
+        match &result {
+            Ok(()) => message.status.value = STATUS_SENT.to_string(),
+            Err(e) => {
+                message.last_error.value = format!("{:?}", e);
+                // Rate limits and server errors are over with time, anything else won't be.
+                // Without an answer the message is left as it is, see `Delivery::Unreachable`.
+                if !e.is_unreachable() {
+                    let retries = (message.attempts.value - 1).min(throttle::MAX_RETRIES);
+                    match throttle::retry_delay(e, retries) {
+                        Some(delay) => {
+                            message.next_attempt.value = Utc::now()
+                                + Duration::from_std(delay)
+                                    .unwrap_or_else(|_| Duration::minutes(1));
+                        }
+                        None => message.status.value = STATUS_FAILED.to_string(),
+                    }
+                }
+            }
+        }
+        // A message sent but not marked is sent again, there's nothing better to do
+        if let Err(e) = message.update() {
+            log::error!("Can't update outbox message {}: {}", message.id.value, e);
+        }
+        match result {
+            Ok(()) => (),
+            Err(e) if e.is_unreachable() => {
+                log::warn!(
+                    "Can't reach Telegram, message {} stays queued: {:?}",
+                    message.id.value,
+                    e
+                );
+                return Delivery::Unreachable;
+            }
+            Err(e) if message.status.value == STATUS_PENDING => {
+                log::warn!(
+                    "Message {} is sent again at {}: {:?}",
+                    message.id.value,
+                    message.next_attempt.value,
+                    e
+                );
+                put_off.insert(message.chat_id.value);
+            }
+            Err(e) => Telecom::on_send_error(message.chat_id.value, &e),
+        }
+    }
+
+    if count == BATCH {
+        Delivery::More
+    } else {
+        Delivery::Done
+    }
+}
+
+/// Until the earliest put off message is due, `IDLE_WAIT` at most.
+fn idle_wait() -> time::Duration {
+    match OutboxMessage::select_next_attempt() {
+        Ok(Some(due)) => (due - Utc::now())
+            .to_std()
+            .map_or(time::Duration::ZERO, |wait| wait.min(IDLE_WAIT)),
+        Ok(None) => IDLE_WAIT,
+        Err(e) => {
+            log::error!("Can't read the outbox: {}", e);
+            IDLE_WAIT
+        }
+    }
+}
+
+fn wait_for_queued(timeout: time::Duration) {
+    let (queued, wake) = &QUEUED;
+    let mut queued = wake
+        .wait_timeout_while(queued.lock().unwrap(), timeout, |queued| !*queued)
+        .unwrap()
+        .0;
+    *queued = false;
+}
+
+fn cleanup() {
+    match OutboxMessage::delete_done_before(Utc::now() - Duration::days(KEEP_DONE_DAYS)) {
+        Ok(0) => (),
+        Ok(deleted) => log::info!("Deleted {} old outbox messages", deleted),
+        Err(e) => log::error!("Can't clean up the outbox: {}", e),
+    }
+}
@@ -1,6 +1,9 @@
 use std::{
     collections::VecDeque,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
     thread, time,
 };
 
@@ -8,30 +11,48 @@ use frankenstein::{AllowedUpdate, GetUpdatesParams, TelegramApi, Update};
 
 use super::telapi::Telapi;
 use crate::{
-    bot::{fsm, telapi, telorker::Telorker},
+    bot::{board, digest, fsm, group, outbox, telapi, telorker::Telorker, webhook::Webhook},
     config::Config,
-    db::{survey_table, user_table},
+    db::{aggregate_table, backup, daily_table, migration, pool, repo},
     user_data::UserData,
 };
 use log::*;
 
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/// Polls failing in a row wait twice as long as the previous one, up to a minute.
+const BACKOFF_MIN: time::Duration = time::Duration::from_secs(1);
+const BACKOFF_MAX: time::Duration = time::Duration::from_secs(60);
+
+/// How long the handlers of the last updates get to finish on shutdown.
+const SHUTDOWN_GRACE: time::Duration = time::Duration::from_secs(10);
+
 pub struct Teladler {
     api: Arc<Telapi>,
     update_params: GetUpdatesParams,
     user_data: Arc<Mutex<UserData>>,
     buffer: VecDeque<Update>,
+    /// Updates handed to the thread pool and not handled yet.
+    in_flight: Arc<AtomicUsize>,
 }
 
 impl Teladler {
+    /// Migrates the database first, the state of the users is read from it.
     pub fn new() -> Teladler {
+        if let Err(e) = prepare_database() {
+            error!("{}", e);
+            std::process::exit(1);
+        }
         let api = Arc::new(telapi::api().clone());
-        let user_data = Arc::new(Mutex::new(UserData::new()));
+        let user_data = Arc::new(Mutex::new(UserData::new(repo::storage())));
         let buffer = VecDeque::new();
         let update_params = GetUpdatesParams::builder()
+            .timeout(Config::poll_timeout_in_seconds().seconds() as u32)
             .allowed_updates(vec![
                 AllowedUpdate::Message,
                 AllowedUpdate::ChannelPost,
                 AllowedUpdate::CallbackQuery,
+                AllowedUpdate::InlineQuery,
             ])
             .build();
 
@@ -40,6 +61,7 @@ impl Teladler {
             update_params,
             user_data,
             buffer,
+            in_flight: Arc::new(AtomicUsize::new(0)),
         }
     }
 
@@ -55,6 +77,10 @@ impl Teladler {
             if  !chat_id_collection.is_empty() {
                 Self::initiate_survey(api.clone(), user_data.clone(), chat_id_collection);
             }
+            digest::send_due();
+            group::send_due_summaries();
+            backup::run_due();
+            aggregate_table::run_due();
             thread::sleep(time::Duration::from_secs(60));
         });
     }
@@ -85,57 +111,209 @@ impl Teladler {
                 update_id: 0,
                 content: frankenstein::UpdateContent::Message(msg),
             };
-            Telorker::new(api.clone(), update, user_data.clone()).run();
+            Telorker::new(api.clone(), update, user_data.clone())
+                .within(outbox::survey_ttl())
+                .run();
         }
     }
 
-    pub fn exec(&mut self) -> ! {
+    /// Runs until Ctrl-C or SIGTERM, then lets the handlers of the last updates finish.
+    pub fn exec(&mut self) {
         let thread_pool = rayon::ThreadPoolBuilder::new()
             .num_threads(Config::telegram_pool_thread_number() as usize)
             .build()
             .unwrap();
-        let _ = user_table::User::new().create_table();
-        let _ = survey_table::SurveyEntry::new().create_table();
-
         info!("Starting the Guardian bot");
-        let interval = time::Duration::from_millis(100);
-
+        handle_signals();
         self.start_timer_thread();
-        loop {
-            while let Some(update) = self.fetch() {
-                let api = self.api.clone();
-                let user_data = self.user_data.clone();
-                thread_pool.spawn(move || {
-                    Telorker::new(api, update, user_data).run();
-                });
+        board::start_thread(self.api.clone());
+        outbox::start_thread(self.api.clone());
+        match Config::webhook_url() {
+            Some(url) => self.listen(&url, &thread_pool),
+            None => self.poll(&thread_pool),
+        }
+        self.wait_for_handlers();
+        info!("Stopped the Guardian bot");
+    }
+
+    fn poll(&mut self, thread_pool: &rayon::ThreadPool) {
+        if let Err(e) = self.api.remove_webhook() {
+            warn!("Can't delete the webhook: {:?}", e);
+        }
+        let mut failures = 0;
+        while !SHUTDOWN.load(Ordering::SeqCst) {
+            match self.fetch() {
+                Ok(update) => {
+                    failures = 0;
+                    if let Some(update) = update {
+                        self.dispatch(thread_pool, update);
+                    }
+                }
+                Err(err) => {
+                    let delay = (BACKOFF_MIN * 2u32.pow(failures.min(6))).min(BACKOFF_MAX);
+                    failures += 1;
+                    error!("Failed to fetch updates, retrying in {:?}: {:?}", delay, err);
+                    sleep_unless_shutdown(delay);
+                }
+            }
+        }
+        // The offset is already past the buffered updates
+        while let Some(update) = self.buffer.pop_front() {
+            self.dispatch(thread_pool, update);
+        }
+        self.confirm_updates();
+    }
+
+    /// Telegram drops the updates before `offset` only on the next `getUpdates`,
+    /// without it the handled ones would come again after a restart.
+    fn confirm_updates(&self) {
+        if let Some(offset) = self.update_params.offset {
+            let params = GetUpdatesParams::builder()
+                .offset(offset)
+                .limit(1u32)
+                .timeout(0u32)
+                .build();
+            if let Err(e) = self.api.get_updates(&params) {
+                warn!("Can't confirm the handled updates: {:?}", e);
+            }
+        }
+    }
+
+    fn wait_for_handlers(&self) {
+        let started = time::Instant::now();
+        while self.in_flight.load(Ordering::SeqCst) > 0 {
+            if started.elapsed() > SHUTDOWN_GRACE {
+                warn!("Gave up waiting for {} updates", self.in_flight.load(Ordering::SeqCst));
+                return;
+            }
+            thread::sleep(time::Duration::from_millis(100));
+        }
+    }
+
+    /// Serves the webhook, the timer and board threads keep running as with polling.
+    fn listen(&self, url: &str, thread_pool: &rayon::ThreadPool) {
+        let secret = Config::webhook_secret().unwrap_or_else(|| {
+            error!("WEBHOOK_SECRET must be set with WEBHOOK_URL");
+            std::process::exit(1);
+        });
+        let webhook = Webhook::bind(&secret).unwrap_or_else(|e| {
+            error!("Can't start the webhook server: {}", e);
+            std::process::exit(1);
+        });
+        let allowed_updates = self.update_params.allowed_updates.clone().unwrap_or_default();
+        let certificate = Config::webhook_certificate();
+        match self.api.set_webhook_url(url, &secret, allowed_updates, certificate) {
+            Ok(()) => info!("Receiving updates at {}", url),
+            Err(e) => error!("Can't set the webhook to {}: {:?}", url, e),
+        }
+        info!("Listening on {}", Config::webhook_listen());
+        while !SHUTDOWN.load(Ordering::SeqCst) {
+            if let Some(update) = webhook.recv() {
+                self.dispatch(thread_pool, update);
             }
-            thread::sleep(interval);
         }
     }
 
-    fn fetch(&mut self) -> Option<Update> {
+    fn dispatch(&self, thread_pool: &rayon::ThreadPool, update: Update) {
+        let api = self.api.clone();
+        let user_data = self.user_data.clone();
+        let in_flight = self.in_flight.clone();
+        in_flight.fetch_add(1, Ordering::SeqCst);
+        thread_pool.spawn(move || {
+            Telorker::new(api, update, user_data).run();
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+
+    /// The next update, long-polling Telegram when none is buffered. `None` if the poll timed out.
+    fn fetch(&mut self) -> Result<Option<Update>, telapi::Error> {
         if let Some(update) = self.buffer.pop_front() {
-            return Some(update);
+            return Ok(Some(update));
         }
 
-        match self.api.get_updates(&self.update_params) {
-            Ok(updates) => {
-                updates
-                    .result
-                    .into_iter()
-                    .for_each(|u| self.buffer.push_back(u));
+        let updates = self.api.get_updates(&self.update_params)?;
+        updates
+            .result
+            .into_iter()
+            .for_each(|u| self.buffer.push_back(u));
 
-                if let Some(last_update) = self.buffer.back() {
-                    self.update_params.offset = Some((last_update.update_id + 1).into());
-                }
+        if let Some(last_update) = self.buffer.back() {
+            self.update_params.offset = Some((last_update.update_id + 1).into());
+        }
 
-                self.buffer.pop_front()
-            }
+        Ok(self.buffer.pop_front())
+    }
+}
 
-            Err(err) => {
-                error!("Failed to fetch updates {:?}", err);
-                None
-            }
+/// Opens and migrates the database, the daily rollup is built on the first start after the upgrade.
+fn prepare_database() -> Result<(), String> {
+    if repo::is_postgres() && Config::retention_days() > 0 {
+        return Err("RETENTION_DAYS only purges SQLite, set it to 0 with POSTGRES_URL".into());
+    }
+    pool::init().map_err(|e| format!("Can't open the database: {}", e))?;
+    let version = migration::run().map_err(|e| format!("Can't migrate the database: {}", e))?;
+    info!("Database schema version {}", version);
+    if matches!(daily_table::DailyAvailability::is_empty(), Ok(true)) {
+        info!("Building daily availability from the survey history");
+        if let Err(e) = daily_table::DailyAvailability::rebuild() {
+            error!("Can't build daily availability: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// The first Ctrl-C or SIGTERM stops taking updates, a second one exits right away.
+fn handle_signals() {
+    let result = ctrlc::set_handler(|| {
+        if SHUTDOWN.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
         }
+        info!("Shutting down after the current poll, press Ctrl-C again to exit right away");
+    });
+    if let Err(e) = result {
+        warn!("Can't handle Ctrl-C: {}", e);
+    }
+}
+
+fn sleep_unless_shutdown(delay: time::Duration) {
+    let until = time::Instant::now() + delay;
+    while !SHUTDOWN.load(Ordering::SeqCst) && time::Instant::now() < until {
+        thread::sleep(time::Duration::from_millis(100));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use crate::{db::repo, user_data::UserData};
+
+    use super::prepare_database;
+
+    #[test]
+    pub fn test_teladler_starts_on_old_schema() {
+        let path = std::env::temp_dir().join(format!("guardian_v0_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        // Tables as they were before the schema was versioned
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE user (id INTEGER PRIMARY KEY, name TEXT, manager TEXT,
+                    chat_id INTEGER);
+                CREATE TABLE survey (id INTEGER PRIMARY KEY, user_id INTEGER NOT NULL,
+                    timestamp TEXT NOT NULL, electricity INTEGER, network INTEGER);
+                INSERT INTO user VALUES (1, 'John Doe', 'Richard Roe', 10);
+                INSERT INTO survey VALUES (1, 1, '2022-11-20 10:00:00', 1, 0);",
+            )
+            .unwrap();
+        // The only test opening the global pool
+        std::env::set_var("DATABASE_LOCATION", &path);
+
+        // As in `Teladler::new`, the answers are read after the upgrade
+        prepare_database().unwrap();
+        let user_data = UserData::new(repo::storage());
+        assert_eq!(user_data.collect_chat_ids(), vec![10]);
+
+        let _ = std::fs::remove_file(&path);
     }
 }
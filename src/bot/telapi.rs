@@ -1,13 +1,27 @@
 use crate::config::Config;
 use crate::http_client;
+use frankenstein::api_params::File;
+use frankenstein::AllowedUpdate;
+use frankenstein::AnswerCallbackQueryParams;
+use frankenstein::AnswerInlineQueryParams;
+use frankenstein::ChatMember;
+use frankenstein::DeleteWebhookParams;
+use frankenstein::EditMessageReplyMarkupParams;
+use frankenstein::EditMessageTextParams;
 use frankenstein::ErrorResponse;
+use frankenstein::GetChatMemberParams;
 use frankenstein::InlineKeyboardButton;
 use frankenstein::InlineKeyboardMarkup;
+use frankenstein::InlineQueryResult;
 use frankenstein::KeyboardButton;
+use frankenstein::MethodResponse;
 use frankenstein::ParseMode;
+use frankenstein::PinChatMessageParams;
 use frankenstein::ReplyKeyboardMarkup;
 use frankenstein::ReplyMarkup;
 use frankenstein::SendMessageParams;
+use frankenstein::SendPhotoParams;
+use frankenstein::SetWebhookParams;
 use frankenstein::TelegramApi;
 use isahc::prelude::*;
 use isahc::Request;
@@ -15,8 +29,11 @@ use log::*;
 use once_cell::sync::OnceCell;
 use std::path::PathBuf;
 
+use super::telecom::ReplyEnum;
 use super::telecom::ReplyInline;
 use super::telecom::ReplyMenu;
+use super::telecom::ReplyPhoto;
+use super::throttle;
 
 static API: OnceCell<Telapi> = OnceCell::new();
 
@@ -43,6 +60,36 @@ pub struct HttpError {
     pub message: String,
 }
 
+impl Error {
+    /// Telegram refuses to deliver to the chat, the user blocked the bot or deleted the account.
+    pub fn is_blocked(&self) -> bool {
+        matches!(self, Error::ApiError(e) if e.error_code == 403)
+    }
+
+    /// No answer from Telegram at all, the network or a proxy in front of it failed.
+    /// Telegram's own errors, server ones included, come as `ApiError`.
+    pub fn is_unreachable(&self) -> bool {
+        matches!(self, Error::HttpError(e) if e.code >= 500)
+    }
+
+    /// Telegram asks to wait `retry_after` seconds before sending again.
+    pub fn is_rate_limited(&self) -> bool {
+        matches!(self, Error::ApiError(e) if e.error_code == 429)
+    }
+
+    /// An edit that would leave the message as it is, nothing to do.
+    pub fn is_not_modified(&self) -> bool {
+        matches!(self, Error::ApiError(e)
+            if e.error_code == 400 && e.description.contains("message is not modified"))
+    }
+
+    /// The message or the chat is gone, e.g. deleted by an admin.
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, Error::ApiError(e)
+            if e.error_code == 400 && e.description.contains("not found"))
+    }
+}
+
 impl Default for Telapi {
     fn default() -> Self {
         Self::new()
@@ -61,20 +108,16 @@ impl Telapi {
         self.reply_with_text_message(chat_id, message, None)
     }
 
-    pub fn reply_with_keyboard(&self, chat_id: i64, menu: ReplyMenu) -> Result<(), Error> {
-        let keyboard_markup = ReplyKeyboardMarkup::builder()
-            .resize_keyboard(true)
-            .keyboard(menu.keyboard)
-            .build();
-
+    /// Sends a message and returns its id, so it can be edited later.
+    pub fn post_text_message(&self, chat_id: i64, message: String) -> Result<i32, Error> {
         let send_message_params = SendMessageParams::builder()
             .chat_id(chat_id)
-            .text(menu.text)
-            .reply_markup(ReplyMarkup::ReplyKeyboardMarkup(keyboard_markup))
+            .text(message)
+            .parse_mode(ParseMode::Html)
             .build();
 
         match self.send_message(&send_message_params) {
-            Ok(_) => Ok(()),
+            Ok(response) => Ok(response.result.message_id),
             Err(err) => {
                 error!(
                     "Failed to send message {:?}: {:?}",
@@ -85,31 +128,169 @@ impl Telapi {
         }
     }
 
-    pub fn reply_with_keyboard_inline(
+    pub fn edit_text_message(
+        &self,
+        chat_id: i64,
+        message_id: i32,
+        message: String,
+    ) -> Result<(), Error> {
+        let edit_message_params = EditMessageTextParams::builder()
+            .chat_id(chat_id)
+            .message_id(message_id)
+            .text(message)
+            .parse_mode(ParseMode::Html)
+            .build();
+
+        match self.edit_message_text(&edit_message_params) {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                error!(
+                    "Failed to edit message {:?}: {:?}",
+                    err, edit_message_params
+                );
+                Err(err)
+            }
+        }
+    }
+
+    /// Replaces the text and the inline keyboard of a message the bot sent.
+    pub fn edit_keyboard_inline(
         &self,
         chat_id: i64,
+        message_id: i32,
         reply: ReplyInline,
     ) -> Result<(), Error> {
         let keyboard_markup = InlineKeyboardMarkup::builder()
             .inline_keyboard(reply.keyboard)
             .build();
 
-        let send_message_params = SendMessageParams::builder()
+        let edit_message_params = EditMessageTextParams::builder()
             .chat_id(chat_id)
+            .message_id(message_id)
             .text(reply.text)
-            .reply_markup(ReplyMarkup::InlineKeyboardMarkup(keyboard_markup))
+            .reply_markup(keyboard_markup)
             .build();
 
-        match self.send_message(&send_message_params) {
+        self.edit_message_text(&edit_message_params).map(|_| ())
+    }
+
+    /// Leaves the message text as it is, so its buttons can't be tapped again.
+    pub fn remove_keyboard_inline(&self, chat_id: i64, message_id: i32) -> Result<(), Error> {
+        let edit_markup_params = EditMessageReplyMarkupParams::builder()
+            .chat_id(chat_id)
+            .message_id(message_id)
+            .build();
+
+        self.edit_message_reply_markup(&edit_markup_params)
+            .map(|_| ())
+    }
+
+    /// Stops the spinner on the tapped button.
+    pub fn answer_callback(&self, query_id: &str) -> Result<(), Error> {
+        let answer_params = AnswerCallbackQueryParams::builder()
+            .callback_query_id(query_id)
+            .build();
+
+        self.answer_callback_query(&answer_params).map(|_| ())
+    }
+
+    /// Whether `user_id` created the group `chat_id` or is one of its administrators.
+    pub fn is_chat_admin(&self, chat_id: i64, user_id: u64) -> Result<bool, Error> {
+        let member_params = GetChatMemberParams::builder()
+            .chat_id(chat_id)
+            .user_id(user_id)
+            .build();
+
+        self.get_chat_member(&member_params).map(|response| {
+            matches!(
+                response.result,
+                ChatMember::Owner(_) | ChatMember::Administrator(_)
+            )
+        })
+    }
+
+    pub fn pin_message(&self, chat_id: i64, message_id: i32) -> Result<(), Error> {
+        let pin_message_params = PinChatMessageParams::builder()
+            .chat_id(chat_id)
+            .message_id(message_id)
+            .disable_notification(true)
+            .build();
+
+        self.pin_chat_message(&pin_message_params).map(|_| ())
+    }
+
+    /// Results depend on who asks, so they are cached per user only.
+    pub fn answer_inline(
+        &self,
+        query_id: &str,
+        results: Vec<InlineQueryResult>,
+    ) -> Result<(), Error> {
+        let answer_params = AnswerInlineQueryParams::builder()
+            .inline_query_id(query_id)
+            .results(results)
+            .cache_time(60u32)
+            .is_personal(true)
+            .build();
+
+        self.answer_inline_query(&answer_params).map(|_| ())
+    }
+
+    /// Points Telegram at the webhook, a self-signed certificate is uploaded along.
+    pub fn set_webhook_url(
+        &self,
+        url: &str,
+        secret: &str,
+        allowed_updates: Vec<AllowedUpdate>,
+        certificate: Option<PathBuf>,
+    ) -> Result<(), Error> {
+        let set_webhook_params = SetWebhookParams::builder()
+            .url(url)
+            .secret_token(secret)
+            .allowed_updates(allowed_updates)
+            .build();
+
+        match certificate {
+            Some(path) => self
+                .request_with_form_data::<_, MethodResponse<bool>>(
+                    "setWebhook",
+                    set_webhook_params,
+                    vec![("certificate", path)],
+                )
+                .map(|_| ()),
+            None => self.set_webhook(&set_webhook_params).map(|_| ()),
+        }
+    }
+
+    /// `getUpdates` fails while a webhook is set, so polling starts with this.
+    pub fn remove_webhook(&self) -> Result<(), Error> {
+        self.delete_webhook(&DeleteWebhookParams::builder().build())
+            .map(|_| ())
+    }
+
+    pub fn reply_with_keyboard(&self, chat_id: i64, menu: ReplyMenu) -> Result<(), Error> {
+        self.send_logged(&Self::keyboard_params(chat_id, menu))
+    }
+
+    pub fn reply_with_keyboard_inline(
+        &self,
+        chat_id: i64,
+        reply: ReplyInline,
+    ) -> Result<(), Error> {
+        self.send_logged(&Self::keyboard_inline_params(chat_id, reply))
+    }
+
+    pub fn reply_with_photo(&self, chat_id: i64, photo: ReplyPhoto) -> Result<(), Error> {
+        let send_photo_params = Self::photo_params(chat_id, photo);
+
+        let result = match self.send_photo(&send_photo_params) {
             Ok(_) => Ok(()),
             Err(err) => {
-                error!(
-                    "Failed to send message {:?}: {:?}",
-                    err, send_message_params
-                );
+                error!("Failed to send photo {:?}: {:?}", err, send_photo_params);
                 Err(err)
             }
-        }
+        };
+        Self::remove_sent_photo(&send_photo_params, &result);
+        result
     }
 
     pub fn reply_with_text_message(
@@ -118,7 +299,84 @@ impl Telapi {
         message: String,
         message_id: Option<i32>,
     ) -> Result<(), Error> {
-        let send_message_params = match message_id {
+        self.send_logged(&Self::text_params(chat_id, message, message_id))
+    }
+
+    /// The method and JSON params sending `reply` into `chat_id`, `None` if there's nothing to send.
+    pub fn request_of(chat_id: i64, reply: ReplyEnum) -> Option<(&'static str, String)> {
+        let params = match reply {
+            ReplyEnum::Text(text) => Self::text_params(chat_id, text, None),
+            ReplyEnum::KeyboardMenu(menu) => Self::keyboard_params(chat_id, menu),
+            ReplyEnum::KeyboardInline(reply) => Self::keyboard_inline_params(chat_id, reply),
+            ReplyEnum::Photo(photo) => {
+                let params = Self::photo_params(chat_id, photo);
+                return serde_json::to_string(&params)
+                    .ok()
+                    .map(|p| ("sendPhoto", p));
+            }
+            ReplyEnum::None => return None,
+        };
+        serde_json::to_string(&params)
+            .ok()
+            .map(|p| ("sendMessage", p))
+    }
+
+    /// Sends a request made by `request_of`.
+    pub fn send_request(&self, method: &str, payload: &str) -> Result<(), Error> {
+        match method {
+            "sendMessage" => self
+                .send_message(&serde_json::from_str(payload)?)
+                .map(|_| ()),
+            "sendPhoto" => {
+                let params: SendPhotoParams = serde_json::from_str(payload)?;
+                // The picture is a temporary file, a reboot may have removed it
+                if let File::InputFile(file) = &params.photo {
+                    if !file.path.exists() {
+                        return Err(Error::HttpError(HttpError {
+                            code: 410,
+                            message: format!("{} is gone", file.path.display()),
+                        }));
+                    }
+                }
+                let result = self.send_photo(&params).map(|_| ());
+                Self::remove_sent_photo(&params, &result);
+                result
+            }
+            _ => Err(Error::HttpError(HttpError {
+                code: 400,
+                message: format!("Can't send {}", method),
+            })),
+        }
+    }
+
+    /// Pictures are rendered into temporary files for a single message, they are
+    /// removed once sent or refused for good. A retried message still needs its file.
+    fn remove_sent_photo(params: &SendPhotoParams, result: &Result<(), Error>) {
+        if matches!(result, Err(e) if throttle::retry_delay(e, 0).is_some()) {
+            return;
+        }
+        if let File::InputFile(file) = &params.photo {
+            if let Err(e) = std::fs::remove_file(&file.path) {
+                warn!("Can't remove {}: {}", file.path.display(), e);
+            }
+        }
+    }
+
+    fn send_logged(&self, send_message_params: &SendMessageParams) -> Result<(), Error> {
+        match self.send_message(send_message_params) {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                error!(
+                    "Failed to send message {:?}: {:?}",
+                    err, send_message_params
+                );
+                Err(err)
+            }
+        }
+    }
+
+    fn text_params(chat_id: i64, message: String, message_id: Option<i32>) -> SendMessageParams {
+        match message_id {
             None => SendMessageParams::builder()
                 .chat_id(chat_id)
                 .text(message)
@@ -131,19 +389,42 @@ impl Telapi {
                 .parse_mode(ParseMode::Html)
                 .reply_to_message_id(message_id_value)
                 .build(),
-        };
-
-        match self.send_message(&send_message_params) {
-            Ok(_) => Ok(()),
-            Err(err) => {
-                error!(
-                    "Failed to send message {:?}: {:?}",
-                    err, send_message_params
-                );
-                Err(err)
-            }
         }
     }
+
+    fn keyboard_params(chat_id: i64, menu: ReplyMenu) -> SendMessageParams {
+        let keyboard_markup = ReplyKeyboardMarkup::builder()
+            .resize_keyboard(true)
+            .keyboard(menu.keyboard)
+            .build();
+
+        SendMessageParams::builder()
+            .chat_id(chat_id)
+            .text(menu.text)
+            .reply_markup(ReplyMarkup::ReplyKeyboardMarkup(keyboard_markup))
+            .build()
+    }
+
+    fn keyboard_inline_params(chat_id: i64, reply: ReplyInline) -> SendMessageParams {
+        let keyboard_markup = InlineKeyboardMarkup::builder()
+            .inline_keyboard(reply.keyboard)
+            .build();
+
+        SendMessageParams::builder()
+            .chat_id(chat_id)
+            .text(reply.text)
+            .reply_markup(ReplyMarkup::InlineKeyboardMarkup(keyboard_markup))
+            .build()
+    }
+
+    fn photo_params(chat_id: i64, photo: ReplyPhoto) -> SendPhotoParams {
+        SendPhotoParams::builder()
+            .chat_id(chat_id)
+            .photo(photo.path)
+            .caption(photo.caption)
+            .parse_mode(ParseMode::Html)
+            .build()
+    }
 }
 
 impl TelegramApi for Telapi {
@@ -155,22 +436,130 @@ impl TelegramApi for Telapi {
         params: Option<T1>,
     ) -> Result<T2, Error> {
         let url = format!("{}/{}", self.api_url, method);
+        let json = params
+            .as_ref()
+            .map(|data| serde_json::to_string(data).unwrap())
+            .unwrap_or_default();
+
+        if method == "getUpdates" {
+            // Long polling, the answer may take the whole poll timeout.
+            // The poll loop has its own backoff, so no limits and retries here.
+            let request = Request::post(url)
+                .header("Content-Type", "application/json")
+                .timeout(
+                    Config::request_timeout_in_seconds().duration()
+                        + Config::poll_timeout_in_seconds().duration(),
+                )
+                .body(json)?;
+            return Self::parse_response(&mut http_client::client().send(request)?);
+        }
 
-        let request_builder = Request::post(url).header("Content-Type", "application/json");
+        self.send_limited(method, params.as_ref().and_then(chat_id_of), || {
+            let request = Request::post(&url)
+                .header("Content-Type", "application/json")
+                .body(json.clone())?;
+            Self::parse_response(&mut http_client::client().send(request)?)
+        })
+    }
 
-        let mut response = match params {
-            None => {
-                let request = request_builder.body(())?;
-                http_client::client().send(request)?
+    // isahc doesn't support multipart uploads
+    // https://github.com/sagebind/isahc/issues/14
+    // so the body is assembled by hand, it's enough for sending a photo
+    fn request_with_form_data<T1: serde::ser::Serialize, T2: serde::de::DeserializeOwned>(
+        &self,
+        method: &str,
+        params: T1,
+        files: Vec<(&str, PathBuf)>,
+    ) -> Result<T2, Error> {
+        let url = format!("{}/{}", self.api_url, method);
+        let boundary = format!("----guardian{}", chrono::Utc::now().timestamp_nanos());
+        let mut body: Vec<u8> = Vec::new();
+
+        if let Ok(serde_json::Value::Object(fields)) = serde_json::to_value(&params) {
+            for (name, value) in fields {
+                if files.iter().any(|(file_name, _)| *file_name == name) {
+                    continue;
+                }
+                let value = match value {
+                    serde_json::Value::String(text) => text,
+                    other => other.to_string(),
+                };
+                body.extend_from_slice(
+                    format!(
+                        "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                        boundary, name, value
+                    )
+                    .as_bytes(),
+                );
             }
-            Some(data) => {
-                let json = serde_json::to_string(&data).unwrap();
-                let request = request_builder.body(json)?;
-                //log::warn!("{:?}", request);
-                http_client::client().send(request)?
+        }
+        for (name, path) in files {
+            let file_name = path
+                .file_name()
+                .map(|f| f.to_string_lossy().to_string())
+                .unwrap_or_else(|| name.to_string());
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+                    boundary, name, file_name
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(&std::fs::read(&path)?);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+
+        self.send_limited(method, chat_id_of(&params), || {
+            let request = Request::post(&url)
+                .header(
+                    "Content-Type",
+                    format!("multipart/form-data; boundary={}", boundary),
+                )
+                .body(body.clone())?;
+            Self::parse_response(&mut http_client::client().send(request)?)
+        })
+    }
+}
+
+/// The chat a request is sent into, for the per-chat rate limit.
+fn chat_id_of<T: serde::ser::Serialize>(params: &T) -> Option<i64> {
+    serde_json::to_value(params)
+        .ok()?
+        .get("chat_id")
+        .and_then(serde_json::Value::as_i64)
+}
+
+impl Telapi {
+    /// Sends within the rate limits, retrying on server errors. A 429 is returned
+    /// at once, the caller knows better when to send again, see `throttle::retry_delay`.
+    fn send_limited<T2>(
+        &self,
+        method: &str,
+        chat_id: Option<i64>,
+        attempt: impl Fn() -> Result<T2, Error>,
+    ) -> Result<T2, Error> {
+        let mut retries = 0;
+        loop {
+            throttle::wait(chat_id);
+            let error = match attempt() {
+                Err(error) if retries < throttle::MAX_RETRIES && !error.is_rate_limited() => error,
+                result => return result,
+            };
+            match throttle::retry_delay(&error, retries) {
+                Some(delay) => {
+                    warn!("{} failed, retrying in {:?}: {:?}", method, delay, error);
+                    std::thread::sleep(delay);
+                    retries += 1;
+                }
+                None => return Err(error),
             }
-        };
+        }
+    }
 
+    fn parse_response<T2: serde::de::DeserializeOwned>(
+        response: &mut isahc::Response<isahc::Body>,
+    ) -> Result<T2, Error> {
         let mut bytes = Vec::new();
         response.copy_to(&mut bytes)?;
 
@@ -186,8 +575,11 @@ impl TelegramApi for Telapi {
                     Ok(result) => Err(Error::ApiError(result)),
                     Err(error) => {
                         let message = format!("{:?} {:?}", error, std::str::from_utf8(&bytes));
+                        // A proxy in front of Telegram may answer 502 with a page instead of JSON.
+                        // A 200 that doesn't parse was delivered, sending it again would duplicate it.
+                        let code = response.status().as_u16();
 
-                        let error = HttpError { code: 500, message };
+                        let error = HttpError { code, message };
 
                         Err(Error::HttpError(error))
                     }
@@ -195,23 +587,6 @@ impl TelegramApi for Telapi {
             }
         }
     }
-
-    // isahc doesn't support multipart uploads
-    // https://github.com/sagebind/isahc/issues/14
-    // but it's fine because this bot doesn't need this feature
-    fn request_with_form_data<T1: serde::ser::Serialize, T2: serde::de::DeserializeOwned>(
-        &self,
-        _method: &str,
-        _params: T1,
-        _files: Vec<(&str, PathBuf)>,
-    ) -> Result<T2, Error> {
-        let error = HttpError {
-            code: 500,
-            message: "isahc doesn't support form data requests".to_string(),
-        };
-
-        Err(Error::HttpError(error))
-    }
 }
 
 pub fn api() -> &'static Telapi {
@@ -238,6 +613,17 @@ impl From<std::io::Error> for Error {
     }
 }
 
+/// A request that can't be encoded, sending it again won't help.
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        let message = format!("{:?}", error);
+
+        let error = HttpError { code: 400, message };
+
+        Error::HttpError(error)
+    }
+}
+
 impl From<isahc::Error> for Error {
     fn from(error: isahc::Error) -> Self {
         let message = format!("{:?}", error);
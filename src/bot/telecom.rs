@@ -1,17 +1,22 @@
-use frankenstein::{CallbackQuery, Message};
-use std::str::FromStr;
+use chrono::Duration;
+use frankenstein::{CallbackQuery, InlineQuery, Message};
+use std::path::PathBuf;
 use std::sync::Mutex;
 use std::{fmt, sync::Arc};
 
-use crate::bot::telapi::Telapi;
+use crate::bot::telapi::{self, Telapi};
+use crate::db::audit_table::{self, AuditEntry};
+use crate::db::repo;
 use crate::user_data::UserData;
 use typed_builder::TypedBuilder as Builder;
 
 use super::telapi::{Teleboard, TeleboardInline};
+use super::{group, inline, outbox};
 
 pub enum Update {
     Message(Message),
     CallbackQuery(CallbackQuery),
+    InlineQuery(InlineQuery),
     None,
 }
 
@@ -20,6 +25,7 @@ pub enum ReplyEnum {
     Text(String),
     KeyboardMenu(ReplyMenu),
     KeyboardInline(ReplyInline),
+    Photo(ReplyPhoto),
     None,
 }
 
@@ -37,6 +43,19 @@ impl ReplyInline {
     }
 }
 #[derive(Clone, Debug)]
+pub struct ReplyPhoto {
+    pub path: PathBuf,
+    pub caption: String,
+}
+impl ReplyPhoto {
+    pub fn new(path: PathBuf, caption: &str) -> Self {
+        Self {
+            path,
+            caption: caption.to_string(),
+        }
+    }
+}
+#[derive(Clone, Debug)]
 pub struct ReplyMenu {
     pub text: String,
     pub keyboard: Teleboard,
@@ -50,7 +69,8 @@ impl ReplyMenu {
     }
 }
 
-#[derive(Builder, Debug, Default)]
+/// Telegram profile of the sender, saved with the registration on every message.
+#[derive(Builder, Debug, Default, Clone, PartialEq)]
 pub struct User {
     pub first_name: String,
     #[builder(setter(into, strip_option), default)]
@@ -58,12 +78,28 @@ pub struct User {
     #[builder(setter(into, strip_option), default)]
     pub username: Option<String>,
 }
+
+impl From<&frankenstein::User> for User {
+    fn from(user: &frankenstein::User) -> Self {
+        Self {
+            first_name: user.first_name.clone(),
+            last_name: user.last_name.clone(),
+            username: user.username.clone(),
+        }
+    }
+}
+
 #[derive(Builder, Debug)]
 pub struct UserInput {
     pub chat_id: i64,
     pub text: String,
     pub date: u64,
-    // pub user: User,
+    /// Messages sent on behalf of a channel have no sender.
+    #[builder(default)]
+    pub user: Option<User>,
+    /// The message with the tapped inline keyboard, the reply replaces it.
+    #[builder(default)]
+    pub message_id: Option<i32>,
 }
 
 impl fmt::Display for UserInput {
@@ -78,6 +114,7 @@ impl UserInput {
             .chat_id(msg.chat.id)
             .text(msg.text.clone().unwrap())
             .date(msg.date)
+            .user(msg.from.as_deref().map(User::from))
             .build()
     }
     pub fn from_query(query: &CallbackQuery) -> Self {
@@ -85,7 +122,8 @@ impl UserInput {
             .chat_id(query.message.clone().unwrap().chat.id)
             .text(query.data.clone().unwrap())
             .date(query.message.clone().unwrap().date)
-            // .user(User::default())
+            .user(Some(User::from(&query.from)))
+            .message_id(query.message.as_ref().map(|m| m.message_id))
             .build()
     }
 }
@@ -93,52 +131,138 @@ impl UserInput {
 pub struct Telecom {}
 
 impl Telecom {
-    fn handle_user_input(api: Arc<Telapi>, user_data: Arc<Mutex<UserData>>, user_input: UserInput) {
+    fn handle_user_input(
+        api: Arc<Telapi>,
+        user_data: Arc<Mutex<UserData>>,
+        user_input: UserInput,
+        ttl: Duration,
+    ) {
         log::debug!("Got input: {}", user_input);
         let result = user_data.lock().unwrap().handle_incoming_v2(&user_input);
-        if result.is_ok() {
-            Self::reply(api, &user_input, result.unwrap())
-        } else {
-            log::error!(
+        match result {
+            Ok(reply) => match user_input.message_id {
+                Some(message_id) => Self::replace(api, user_input.chat_id, message_id, reply, ttl),
+                None => Self::reply_within(api, user_input.chat_id, reply, ttl),
+            },
+            Err(e) => log::error!(
                 "Error: `{}",
-                result
-                    .err()
-                    .unwrap()
-                    .msg()
-                    .unwrap_or_else(|| String::from_str("unknonw").unwrap())
-            );
+                e.msg().unwrap_or_else(|| String::from("unknonw"))
+            ),
         }
     }
 
-    pub fn handle(api: Arc<Telapi>, update: Update, user_data: Arc<Mutex<UserData>>) {
+    /// Replies to a user wait in the outbox for `ttl` at most, group and inline answers don't.
+    pub fn handle(
+        api: Arc<Telapi>,
+        update: Update,
+        user_data: Arc<Mutex<UserData>>,
+        ttl: Duration,
+    ) {
+        if let Update::CallbackQuery(query) = &update {
+            if let Err(e) = api.answer_callback(&query.id) {
+                log::warn!("Can't answer callback query {}: {:?}", query.id, e);
+            }
+        }
         match update {
+            Update::Message(msg) if group::is_group_chat(&msg.chat) => {
+                group::handle_message(api, &msg)
+            }
+            Update::CallbackQuery(query) if matches!(&query.message, Some(m) if group::is_group_chat(&m.chat)) => {
+                group::handle_query(api, &query)
+            }
             Update::Message(msg) if msg.text.is_some() => {
-                Self::handle_user_input(api, user_data, UserInput::from_msg(&msg))
+                Self::handle_user_input(api, user_data, UserInput::from_msg(&msg), ttl)
             }
             Update::CallbackQuery(query) => {
-                Self::handle_user_input(api, user_data, UserInput::from_query(&query))
+                Self::handle_user_input(api, user_data, UserInput::from_query(&query), ttl)
             }
+            Update::InlineQuery(query) => inline::handle(api, &query),
             _ => {}
         }
     }
-    pub fn reply(api: Arc<Telapi>, user_input: &UserInput, reply: ReplyEnum) {
-        match reply {
-            ReplyEnum::Text(txt) => {
-                api.reply_with_text_message(user_input.chat_id, txt, None)
-                    .map_err(|e| log::error!("error: {:?}", e))
-                    .unwrap();
+    pub fn reply(api: Arc<Telapi>, chat_id: i64, reply: ReplyEnum) {
+        Self::reply_within(api, chat_id, reply, outbox::default_ttl())
+    }
+
+    /// Edits the message with the tapped keyboard into the next step of the flow,
+    /// so the chat isn't filled with dead keyboards. Photos and menus can't replace
+    /// a text message, they are sent anew and the old keyboard is removed.
+    pub fn replace(
+        api: Arc<Telapi>,
+        chat_id: i64,
+        message_id: i32,
+        reply: ReplyEnum,
+        ttl: Duration,
+    ) {
+        let result = match &reply {
+            ReplyEnum::Text(text) => api.edit_text_message(chat_id, message_id, text.clone()),
+            ReplyEnum::KeyboardInline(kbrd) => {
+                api.edit_keyboard_inline(chat_id, message_id, kbrd.clone())
             }
-            ReplyEnum::KeyboardMenu(menu) => {
-                api.reply_with_keyboard(user_input.chat_id, menu)
-                    .map_err(|e| log::error!("error: {:?}", e))
-                    .unwrap();
+            ReplyEnum::None => return,
+            _ => {
+                if let Err(e) = api.remove_keyboard_inline(chat_id, message_id) {
+                    if !e.is_not_modified() {
+                        log::warn!("Can't remove the keyboard of {}: {:?}", message_id, e);
+                    }
+                }
+                return Self::reply_within(api, chat_id, reply, ttl);
             }
-            ReplyEnum::KeyboardInline(kbrd) => {
-                api.reply_with_keyboard_inline(user_input.chat_id, kbrd)
-                    .map_err(|e| log::error!("error: {:?}", e))
-                    .unwrap();
+        };
+        match result {
+            Ok(()) => (),
+            Err(e) if e.is_not_modified() => (),
+            Err(e) => {
+                log::warn!(
+                    "Can't edit {} in {}, sending anew: {:?}",
+                    message_id,
+                    chat_id,
+                    e
+                );
+                Self::reply_within(api, chat_id, reply, ttl)
+            }
+        }
+    }
+
+    /// Queues the reply in the outbox, it's sent right away only if the outbox can't be written.
+    pub fn reply_within(api: Arc<Telapi>, chat_id: i64, reply: ReplyEnum, ttl: Duration) {
+        let reply = match outbox::enqueue(chat_id, reply.clone(), ttl) {
+            Ok(()) => return,
+            Err(e) => {
+                log::error!("Can't queue a reply to {}: {}", chat_id, e);
+                reply
+            }
+        };
+        let result = match reply {
+            ReplyEnum::Text(txt) => api.reply_with_text_message(chat_id, txt, None),
+            ReplyEnum::KeyboardMenu(menu) => api.reply_with_keyboard(chat_id, menu),
+            ReplyEnum::KeyboardInline(kbrd) => api.reply_with_keyboard_inline(chat_id, kbrd),
+            ReplyEnum::Photo(photo) => api.reply_with_photo(chat_id, photo),
+            ReplyEnum::None => Ok(()),
+        };
+        if let Err(e) = result {
+            Self::on_send_error(chat_id, &e);
+        }
+    }
+
+    /// Logs a failed send, a user who blocked the bot is deactivated so they aren't surveyed anymore.
+    pub fn on_send_error(chat_id: i64, e: &telapi::Error) {
+        log::error!("Can't send to {}: {:?}", chat_id, e);
+        if !e.is_blocked() {
+            return;
+        }
+        let storage = repo::storage();
+        match storage.set_active(chat_id, false) {
+            Ok(true) => {
+                log::info!("Deactivated {}, the bot is blocked", chat_id);
+                audit_table::record(
+                    storage.as_ref(),
+                    AuditEntry::admin(chat_id, "deactivate", "blocked the bot"),
+                );
             }
-            ReplyEnum::None => {}
+            // Not a registered user
+            Ok(false) => (),
+            Err(e) => log::error!("Can't deactivate {}: {}", chat_id, e),
         }
     }
 }
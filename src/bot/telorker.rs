@@ -1,10 +1,12 @@
 use std::sync::{Arc, Mutex};
 
+use chrono::Duration;
 use frankenstein::{Update, UpdateContent};
 
 use crate::user_data::UserData;
 
 use super::{
+    outbox,
     telapi::Telapi,
     telecom::{self, Telecom},
 };
@@ -13,6 +15,8 @@ pub struct Telorker {
     api: Arc<Telapi>,
     update: Update,
     user_data: Arc<Mutex<UserData>>,
+    /// How long the reply may wait in the outbox.
+    ttl: Duration,
 }
 
 impl Telorker {
@@ -21,9 +25,16 @@ impl Telorker {
             api: api,
             update,
             user_data,
+            ttl: outbox::default_ttl(),
         }
     }
 
+    /// Replies to updates the bot makes up itself, like the survey prompt, go stale sooner.
+    pub fn within(mut self, ttl: Duration) -> Telorker {
+        self.ttl = ttl;
+        self
+    }
+
     pub fn run(&self) {
         let update = match &self.update.content {
             UpdateContent::Message(message) => telecom::Update::Message(message.clone()),
@@ -33,9 +44,12 @@ impl Telorker {
             UpdateContent::CallbackQuery(callback_query) => {
                 telecom::Update::CallbackQuery(callback_query.clone())
             }
+            UpdateContent::InlineQuery(inline_query) => {
+                telecom::Update::InlineQuery(inline_query.clone())
+            }
             _ => return,
         };
 
-        Telecom::handle(self.api.clone(), update, self.user_data.clone());
+        Telecom::handle(self.api.clone(), update, self.user_data.clone(), self.ttl);
     }
 }
@@ -0,0 +1,211 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::OnceCell;
+
+use crate::config::Config;
+
+use super::telapi::Error;
+
+/// Telegram allows about one message a second into a private chat and 20 a minute into a group.
+const PRIVATE_CHAT_RATE: f64 = 1.0;
+const GROUP_CHAT_RATE: f64 = 20.0 / 60.0;
+const CHAT_BURST: f64 = 3.0;
+
+/// Failed requests are sent again this many times at most.
+pub const MAX_RETRIES: u32 = 3;
+const RETRY_BASE: Duration = Duration::from_secs(1);
+
+/// Chats are forgotten once their buckets are full again, checked past this many chats.
+const CHATS_KEPT: usize = 1024;
+
+static LIMITER: OnceCell<Mutex<Limiter>> = OnceCell::new();
+
+/// Tokens refill at `rate` a second up to `capacity`. Taking one from an empty bucket
+/// reserves it, the caller waits until it would have been refilled.
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: f64, rate: f64, now: Instant) -> Self {
+        Self {
+            capacity,
+            rate,
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Takes a token, returns how long to wait before using it.
+    fn take(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+}
+
+/// The global bucket of the bot and one bucket per chat.
+struct Limiter {
+    global: TokenBucket,
+    chats: HashMap<i64, TokenBucket>,
+}
+
+impl Limiter {
+    fn new(global_rate: f64, now: Instant) -> Self {
+        Self {
+            global: TokenBucket::new(global_rate, global_rate, now),
+            chats: HashMap::new(),
+        }
+    }
+
+    /// Reserves a request, returns how long to wait before sending it.
+    fn reserve(&mut self, chat_id: Option<i64>, now: Instant) -> Duration {
+        let global = self.global.take(now);
+        let chat = match chat_id {
+            Some(chat_id) => {
+                if self.chats.len() > CHATS_KEPT {
+                    self.chats.retain(|_, bucket| !bucket.is_full(now));
+                }
+                // Group and channel ids are negative
+                let rate = if chat_id > 0 {
+                    PRIVATE_CHAT_RATE
+                } else {
+                    GROUP_CHAT_RATE
+                };
+                self.chats
+                    .entry(chat_id)
+                    .or_insert_with(|| TokenBucket::new(CHAT_BURST, rate, now))
+                    .take(now)
+            }
+            None => Duration::ZERO,
+        };
+        global.max(chat)
+    }
+}
+
+/// Blocks until a request into `chat_id` fits into the rate limits, `None` for requests without a chat.
+pub fn wait(chat_id: Option<i64>) {
+    let delay = LIMITER
+        .get_or_init(|| Mutex::new(Limiter::new(Config::send_rate(), Instant::now())))
+        .lock()
+        .unwrap()
+        .reserve(chat_id, Instant::now());
+    if !delay.is_zero() {
+        log::debug!("Throttling a request into {:?} for {:?}", chat_id, delay);
+        thread::sleep(delay);
+    }
+}
+
+/// How long to wait before sending again after `retries` failed retries, `None` if it won't help.
+/// 429 comes with the delay in `retry_after`, server errors back off exponentially with jitter.
+pub fn retry_delay(error: &Error, retries: u32) -> Option<Duration> {
+    let backoff = || {
+        let base = RETRY_BASE * 2u32.pow(retries);
+        base + base.mul_f64(jitter())
+    };
+    match error {
+        Error::ApiError(e) if e.error_code == 429 => Some(
+            e.parameters
+                .as_ref()
+                .and_then(|p| p.retry_after)
+                .map(|seconds| Duration::from_secs(seconds.into()))
+                .unwrap_or_else(backoff),
+        ),
+        Error::ApiError(e) if e.error_code >= 500 => Some(backoff()),
+        Error::HttpError(e) if e.code >= 500 => Some(backoff()),
+        _ => None,
+    }
+}
+
+/// Between 0 and 1, spreads the retries of workers failing at the same time.
+fn jitter() -> f64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or_default();
+    f64::from(nanos % 1000) / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use frankenstein::{ErrorResponse, ResponseParameters};
+
+    use crate::bot::telapi::{Error, HttpError};
+
+    use super::{retry_delay, Limiter};
+
+    fn api_error(error_code: u64, retry_after: Option<u16>) -> Error {
+        Error::ApiError(ErrorResponse {
+            ok: false,
+            description: "Too Many Requests".to_string(),
+            error_code,
+            parameters: retry_after.map(|r| ResponseParameters::builder().retry_after(r).build()),
+        })
+    }
+
+    #[test]
+    pub fn test_throttle_limits() {
+        let now = Instant::now();
+        let mut limiter = Limiter::new(30.0, now);
+        // The burst of a private chat goes at once, then one a second
+        for _ in 0..3 {
+            assert_eq!(limiter.reserve(Some(7), now), Duration::ZERO);
+        }
+        assert_eq!(limiter.reserve(Some(7), now), Duration::from_secs(1));
+        assert_eq!(limiter.reserve(Some(7), now), Duration::from_secs(2));
+        // Other chats aren't held up by it, groups refill slower
+        assert_eq!(limiter.reserve(Some(8), now), Duration::ZERO);
+        for _ in 0..3 {
+            limiter.reserve(Some(-100), now);
+        }
+        assert_eq!(limiter.reserve(Some(-100), now), Duration::from_secs(3));
+        // The global bucket is shared by everybody
+        for _ in 0..21 {
+            limiter.reserve(None, now);
+        }
+        assert!(limiter.reserve(None, now) > Duration::ZERO);
+        assert_eq!(
+            limiter.reserve(None, now + Duration::from_secs(2)),
+            Duration::ZERO
+        );
+
+        assert_eq!(
+            retry_delay(&api_error(429, Some(5)), 0),
+            Some(Duration::from_secs(5))
+        );
+        let backoff = retry_delay(&api_error(502, None), 1).unwrap();
+        assert!(backoff >= Duration::from_secs(2) && backoff < Duration::from_secs(4));
+        assert_eq!(retry_delay(&api_error(403, None), 0), None);
+        let network = Error::HttpError(HttpError {
+            code: 500,
+            message: "Connection refused".to_string(),
+        });
+        assert!(retry_delay(&network, 0).is_some());
+    }
+}
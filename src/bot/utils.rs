@@ -1,11 +1,12 @@
 use core::fmt;
+use std::path::PathBuf;
 
 use frankenstein::{InlineKeyboardButton, KeyboardButton};
 
 use super::{
     fsm::{Event, UserDisplay},
     telapi::{Teleboard, TeleboardInline, Telerow, TelerowInline},
-    telecom::{ReplyEnum, ReplyInline, ReplyMenu},
+    telecom::{ReplyEnum, ReplyInline, ReplyMenu, ReplyPhoto},
 };
 
 pub fn reply_help_event() -> ReplyEnum {
@@ -16,6 +17,11 @@ pub fn reply_help_event() -> ReplyEnum {
         (Event::Report, "Generate report"),
         (Event::Rename, "Change name report"),
         (Event::Menu, "Show the menu"),
+        (Event::Digest, "Subscribe/unsubscribe to the weekly digest"),
+        (
+            Event::Forget,
+            "Delete your registration and all your answers",
+        ),
     ];
     let mut help_text = String::new();
     for (e, s) in help_table {
@@ -44,8 +50,17 @@ pub fn reply_report_period_event() -> ReplyEnum {
     make_reply_inline(
         "What report do you whant",
         Some(&[
-            &[Event::ReportOffsetDay, Event::ReportOffsetWeek],
-            &[Event::ReportOffsetMonth, Event::Back],
+            &[
+                Event::ReportOffsetDay,
+                Event::ReportOffsetWeek,
+                Event::ReportOffsetMonth,
+            ],
+            &[
+                Event::ReportHeatmap,
+                Event::ReportSilent,
+                Event::ReportTrend,
+            ],
+            &[Event::Back],
         ]),
     )
 }
@@ -73,10 +88,30 @@ pub fn reply_not_emplemented() -> ReplyEnum {
     make_reply_text("Not implemented, sorry")
 }
 
+/// Electricity or network state as shown in statuses, the outage stands out.
+pub fn on_off(value: bool) -> &'static str {
+    if value {
+        "on"
+    } else {
+        "OFF"
+    }
+}
+
+/// User supplied text, e.g. names, put into an HTML message.
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
 pub fn make_reply_text(text: &str) -> ReplyEnum {
     make_reply_inline(text, Option::<&[&[Event]]>::None)
 }
 
+pub fn make_reply_photo(path: PathBuf, caption: &str) -> ReplyEnum {
+    ReplyEnum::Photo(ReplyPhoto::new(path, caption))
+}
+
 pub fn make_reply_inline<T: fmt::Display + UserDisplay>(
     text: &str,
     slice: Option<&[&[T]]>,
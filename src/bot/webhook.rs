@@ -0,0 +1,204 @@
+use std::{io::Read, path::PathBuf, time::Duration};
+
+use frankenstein::Update;
+use tiny_http::{Method, Response, Server};
+
+use crate::config::Config;
+
+const SECRET_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+
+/// Updates are a few KiB, anything much larger isn't from Telegram.
+const MAX_BODY: u64 = 1024 * 1024;
+
+/// Embedded HTTP server receiving the updates Telegram posts to the webhook.
+pub struct Webhook {
+    server: Server,
+    secret: String,
+}
+
+impl Webhook {
+    /// Listens on `WEBHOOK_LISTEN`, with HTTPS when `WEBHOOK_CERT` and `WEBHOOK_KEY` are set.
+    pub fn bind(secret: &str) -> Result<Self, String> {
+        if !is_valid_secret(secret) {
+            return Err("WEBHOOK_SECRET must be 1-256 characters of A-Z, a-z, 0-9, _ and -".into());
+        }
+        let addr = Config::webhook_listen();
+        let server = match (Config::webhook_certificate(), Config::webhook_private_key()) {
+            (Some(certificate), Some(private_key)) => https(&addr, certificate, private_key)?,
+            _ => Server::http(&addr).map_err(|e| format!("Can't listen on {}: {}", addr, e))?,
+        };
+        Ok(Self {
+            server,
+            secret: secret.to_string(),
+        })
+    }
+
+    /// Waits a second at most for the next request and answers it, `None` unless it carried an update.
+    pub fn recv(&self) -> Option<Update> {
+        let mut request = match self.server.recv_timeout(Duration::from_secs(1)) {
+            Ok(Some(request)) => request,
+            Ok(None) => return None,
+            Err(e) => {
+                log::error!("Webhook server failed: {}", e);
+                return None;
+            }
+        };
+        let secret = request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv(SECRET_HEADER))
+            .map(|h| h.value.to_string());
+        let mut body = Vec::new();
+        let (status, update) = match request.as_reader().take(MAX_BODY).read_to_end(&mut body) {
+            Ok(_) => accept(request.method(), secret.as_deref(), &self.secret, &body),
+            Err(e) => {
+                log::warn!("Can't read a webhook request: {}", e);
+                (400, None)
+            }
+        };
+        if status != 200 {
+            log::warn!(
+                "Rejected {} {} from {:?} with {}",
+                request.method(),
+                request.url(),
+                request.remote_addr(),
+                status
+            );
+        }
+        if let Err(e) = request.respond(Response::empty(status)) {
+            log::warn!("Can't answer a webhook request: {}", e);
+        }
+        update
+    }
+}
+
+#[cfg(feature = "tls")]
+fn https(addr: &str, certificate: PathBuf, private_key: PathBuf) -> Result<Server, String> {
+    let read = |path: PathBuf| {
+        std::fs::read(&path).map_err(|e| format!("Can't read {}: {}", path.display(), e))
+    };
+    let config = tiny_http::SslConfig {
+        certificate: read(certificate)?,
+        private_key: read(private_key)?,
+    };
+    Server::https(addr, config).map_err(|e| format!("Can't listen on {}: {}", addr, e))
+}
+
+#[cfg(not(feature = "tls"))]
+fn https(_addr: &str, _certificate: PathBuf, _private_key: PathBuf) -> Result<Server, String> {
+    Err("HTTPS needs the tls feature, unset WEBHOOK_CERT and WEBHOOK_KEY behind a TLS proxy".into())
+}
+
+/// The status to answer with and the update to handle. An update that can't be parsed
+/// is acknowledged and dropped, Telegram would redeliver it forever otherwise.
+fn accept(
+    method: &Method,
+    secret: Option<&str>,
+    expected: &str,
+    body: &[u8],
+) -> (u16, Option<Update>) {
+    if *method != Method::Post {
+        return (405, None);
+    }
+    if !matches!(secret, Some(secret) if same_secret(secret, expected)) {
+        return (401, None);
+    }
+    match serde_json::from_slice(body) {
+        Ok(update) => (200, Some(update)),
+        Err(e) => {
+            log::error!("Dropped an update that can't be parsed: {}", e);
+            (200, None)
+        }
+    }
+}
+
+/// Compares in constant time, so the secret can't be guessed byte by byte.
+fn same_secret(secret: &str, expected: &str) -> bool {
+    secret.len() == expected.len()
+        && secret
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// The characters Telegram allows in `secret_token`.
+fn is_valid_secret(secret: &str) -> bool {
+    (1..=256).contains(&secret.len())
+        && secret
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+        thread,
+    };
+
+    use frankenstein::UpdateContent;
+    use tiny_http::{Method, Server};
+
+    use super::{accept, is_valid_secret, Webhook};
+
+    const UPDATE: &str = r#"{"update_id":10,"message":{"message_id":5,"date":1668980000,
+        "chat":{"id":7,"type":"private","first_name":"Jonny"},
+        "from":{"id":7,"is_bot":false,"first_name":"Jonny","username":"jblack"},"text":"/start"}}"#;
+
+    fn post(addr: std::net::SocketAddr, secret: &str, body: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "POST /telegram HTTP/1.1\r\nHost: localhost\r\nX-Telegram-Bot-Api-Secret-Token: {}\r\n\
+             Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            secret,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    pub fn test_webhook_accept() {
+        let (status, update) = accept(&Method::Post, Some("s3cret"), "s3cret", UPDATE.as_bytes());
+        assert_eq!(status, 200);
+        match update.unwrap().content {
+            UpdateContent::Message(msg) => assert_eq!(msg.text, Some("/start".to_string())),
+            _ => panic!("Not a message"),
+        }
+        assert_eq!(
+            accept(&Method::Post, Some("guess"), "s3cret", UPDATE.as_bytes()).0,
+            401
+        );
+        assert_eq!(
+            accept(&Method::Post, None, "s3cret", UPDATE.as_bytes()).0,
+            401
+        );
+        assert_eq!(accept(&Method::Get, Some("s3cret"), "s3cret", b"").0, 405);
+        assert_eq!(
+            accept(&Method::Post, Some("s3cret"), "s3cret", b"{}"),
+            (200, None)
+        );
+        assert!(is_valid_secret("a_B-9"));
+        assert!(!is_valid_secret("") && !is_valid_secret("no spaces"));
+
+        let webhook = Webhook {
+            server: Server::http("127.0.0.1:0").unwrap(),
+            secret: "s3cret".to_string(),
+        };
+        let addr = webhook.server.server_addr().to_ip().unwrap();
+        let client =
+            thread::spawn(move || (post(addr, "guess", UPDATE), post(addr, "s3cret", UPDATE)));
+        // The rejected request yields nothing, so does waiting for the client
+        let update = (0..10).find_map(|_| webhook.recv()).unwrap();
+        assert_eq!(update.update_id, 10);
+        let (rejected, accepted) = client.join().unwrap();
+        assert!(rejected.starts_with("HTTP/1.1 401"));
+        assert!(accepted.starts_with("HTTP/1.1 200"));
+    }
+}
@@ -0,0 +1,490 @@
+use core::fmt;
+use std::{fs, path::PathBuf};
+
+use chrono::Utc;
+use serde_json::json;
+
+use crate::{
+    bot::{
+        fsm::{self, Event, ReportKind, ReportType},
+        telandler::Teladler,
+        telecom::ReplyEnum,
+    },
+    db::{
+        audit_table::{self, AuditEntry},
+        backup, migration, pool,
+        repo::{self, ReportQuery, UserRepository},
+        user_table::User,
+    },
+};
+
+const USAGE: &str = "Usage: guardian [--db <file>] <command>
+
+Commands:
+  run                           Start the bot, the default without a command
+  migrate                       Apply the pending database migrations
+  export <users|surveys> [--format csv|json]
+                                Print a table to stdout
+  import <file>                 Register the users of a roster, CSV lines of name,manager,chat_id
+  report [--type all|me:<chat id>|team:<manager>] [--period day|week|month]
+         [--kind availability|heatmap|silent|trend]
+                                Print a report to stdout
+  backup                        Back the SQLite database up into BACKUP_DIR,
+                                PostgreSQL is not covered
+  restore <file>                Replace the SQLite database with a backup
+  help                          Print this help
+
+Options:
+  --db <file>                   Database file instead of DATABASE_LOCATION";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run,
+    Migrate,
+    Export {
+        table: Table,
+        format: Format,
+    },
+    Import {
+        path: PathBuf,
+    },
+    Report {
+        report_type: ReportType,
+        period: Event,
+        kind: ReportKind,
+    },
+    Backup,
+    Restore {
+        path: PathBuf,
+    },
+    Help,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Table {
+    Users,
+    Surveys,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Format {
+    Csv,
+    Json,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Usage(String),
+    Failed(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Usage(text) => write!(f, "{}\n\n{}", text, USAGE),
+            Error::Failed(text) => write!(f, "{}", text),
+        }
+    }
+}
+
+fn failed<E: fmt::Display>(what: &str) -> impl Fn(E) -> Error + '_ {
+    move |e| Error::Failed(format!("{}: {}", what, e))
+}
+
+/// Parsed command line, `database` overrides `DATABASE_LOCATION`.
+#[derive(Debug, PartialEq)]
+pub struct Cli {
+    pub database: Option<String>,
+    pub command: Command,
+}
+
+impl Cli {
+    /// Parses the arguments without the program name.
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, Error> {
+        let mut database = None;
+        let mut words = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--db" {
+                database = Some(value_of(&arg, args.next())?);
+            } else {
+                words.push(arg);
+            }
+        }
+
+        let mut words = words.into_iter();
+        let command = match words.next().as_deref() {
+            None | Some("run") => Command::Run,
+            Some("migrate") => Command::Migrate,
+            Some("export") => {
+                let table = match words.next().as_deref() {
+                    Some("users") => Table::Users,
+                    Some("surveys") => Table::Surveys,
+                    _ => return Err(Error::Usage("export needs users or surveys".into())),
+                };
+                let mut format = Format::Csv;
+                while let Some(arg) = words.next() {
+                    match (arg.as_str(), value_of(&arg, words.next())?.as_str()) {
+                        ("--format", "csv") => format = Format::Csv,
+                        ("--format", "json") => format = Format::Json,
+                        (option, value) => return Err(unexpected(option, value)),
+                    }
+                }
+                Command::Export { table, format }
+            }
+            Some("import") => Command::Import {
+                path: value_of("import", words.next())?.into(),
+            },
+            Some("report") => {
+                let mut report_type = ReportType::All;
+                let mut period = Event::ReportOffsetWeek;
+                let mut kind = ReportKind::Availability;
+                while let Some(arg) = words.next() {
+                    let value = value_of(&arg, words.next())?;
+                    match (arg.as_str(), value.as_str()) {
+                        ("--type", "all") => report_type = ReportType::All,
+                        ("--type", value) if value.starts_with("me:") => {
+                            report_type = ReportType::Me(
+                                value[3..].parse().map_err(|_| unexpected(&arg, value))?,
+                            )
+                        }
+                        ("--type", value) if value.starts_with("team:") => {
+                            report_type = ReportType::Team(value[5..].to_string())
+                        }
+                        ("--period", "day") => period = Event::ReportOffsetDay,
+                        ("--period", "week") => period = Event::ReportOffsetWeek,
+                        ("--period", "month") => period = Event::ReportOffsetMonth,
+                        ("--kind", "availability") => kind = ReportKind::Availability,
+                        ("--kind", "heatmap") => kind = ReportKind::Heatmap,
+                        ("--kind", "silent") => kind = ReportKind::Silent,
+                        ("--kind", "trend") => kind = ReportKind::Trend,
+                        (option, value) => return Err(unexpected(option, value)),
+                    }
+                }
+                if kind == ReportKind::Trend && period == Event::ReportOffsetDay {
+                    return Err(Error::Usage("trend compares weeks or months".into()));
+                }
+                Command::Report {
+                    report_type,
+                    period,
+                    kind,
+                }
+            }
+            Some("backup") => Command::Backup,
+            Some("restore") => Command::Restore {
+                path: value_of("restore", words.next())?.into(),
+            },
+            Some("help" | "--help" | "-h") => Command::Help,
+            Some(other) => return Err(Error::Usage(format!("Unknown command: {}", other))),
+        };
+        if let Some(extra) = words.next() {
+            return Err(Error::Usage(format!("Unexpected argument: {}", extra)));
+        }
+        Ok(Self { database, command })
+    }
+}
+
+fn value_of(option: &str, value: Option<String>) -> Result<String, Error> {
+    value.ok_or_else(|| Error::Usage(format!("{} needs a value", option)))
+}
+
+fn unexpected(option: &str, value: &str) -> Error {
+    Error::Usage(format!("Unexpected {} {}", option, value))
+}
+
+pub fn run(cli: Cli) -> Result<(), Error> {
+    if let Some(database) = &cli.database {
+        std::env::set_var("DATABASE_LOCATION", database);
+    }
+    match cli.command {
+        Command::Run => Teladler::new().exec(),
+        Command::Help => println!("{}", USAGE),
+        Command::Migrate => println!("Database schema version {}", migrate()?),
+        Command::Export { table, format } => {
+            migrate()?;
+            print!("{}", export(repo::storage().as_ref(), table, format)?);
+        }
+        Command::Import { path } => {
+            migrate()?;
+            let text = fs::read_to_string(&path).map_err(failed("Can't read the roster"))?;
+            let (imported, skipped) = import(repo::storage().as_ref(), &text);
+            let result = format!("Imported {} users, skipped {} lines", imported, skipped);
+            audit("import", &result);
+            println!("{}", result);
+        }
+        Command::Report {
+            report_type,
+            period,
+            kind,
+        } => {
+            migrate()?;
+            let utc = Utc::now();
+            let (offset, from) = fsm::report_period(&period, utc).unwrap();
+            let reply = fsm::render_report(
+                repo::storage().as_ref(),
+                0,
+                report_type,
+                kind,
+                offset,
+                from,
+                utc,
+            );
+            println!("{}", reply_text(reply));
+        }
+        Command::Backup => {
+            warn_sqlite_only();
+            let file = backup::create().map_err(failed("Can't back the database up"))?;
+            let result = format!("{} {} bytes", file.path.display(), file.size);
+            audit("backup", &result);
+            println!("{}", result);
+        }
+        Command::Restore { path } => {
+            warn_sqlite_only();
+            backup::restore(&path).map_err(failed("Can't restore the database"))?;
+            let result = format!(
+                "Restored {}, database schema version {}",
+                path.display(),
+                migrate()?
+            );
+            // Goes into the restored database
+            audit("restore", &result);
+            println!("{}", result);
+        }
+    }
+    Ok(())
+}
+
+/// Backups copy the SQLite file, the users and answers in PostgreSQL are left out.
+fn warn_sqlite_only() {
+    if repo::is_postgres() {
+        eprintln!("POSTGRES_URL is set, only the SQLite database is backed up or restored");
+    }
+}
+
+/// Records an action of the operator running the command line.
+fn audit(action: &str, result: &str) {
+    audit_table::record(
+        repo::storage().as_ref(),
+        AuditEntry::admin(audit_table::OPERATOR, action, result),
+    );
+}
+
+fn migrate() -> Result<u32, Error> {
+    pool::init().map_err(failed("Can't open the database"))?;
+    migration::run().map_err(failed("Can't migrate the database"))
+}
+
+fn export<S: UserRepository + ReportQuery + ?Sized>(
+    storage: &S,
+    table: Table,
+    format: Format,
+) -> Result<String, Error> {
+    let rows: Vec<Vec<serde_json::Value>> = match table {
+        Table::Users => storage
+            .all_users()
+            .map_err(failed("Can't read users"))?
+            .into_iter()
+            .map(|u| {
+                vec![
+                    json!(u.name.value),
+                    json!(u.manager.value),
+                    json!(u.chat_id.value),
+                    json!(u.username.value),
+                    json!(u.first_name.value),
+                    json!(u.last_name.value),
+                    json!(u.differs_from_profile()),
+                ]
+            })
+            .collect(),
+        Table::Surveys => storage
+            .all_reports()
+            .map_err(failed("Can't read surveys"))?
+            .into_iter()
+            .map(|r| {
+                vec![
+                    json!(r.name.value),
+                    json!(r.manager.value),
+                    json!(r.chat_id.value),
+                    json!(r.timestamp.value.to_rfc3339()),
+                    json!(r.electricity.value),
+                    json!(r.network.value),
+                ]
+            })
+            .collect(),
+    };
+    let titles: &[&str] = match table {
+        Table::Users => &[
+            "name",
+            "manager",
+            "chat_id",
+            "username",
+            "first_name",
+            "last_name",
+            "name_differs",
+        ],
+        Table::Surveys => &[
+            "name",
+            "manager",
+            "chat_id",
+            "timestamp",
+            "electricity",
+            "network",
+        ],
+    };
+
+    Ok(match format {
+        Format::Json => {
+            let objects: Vec<serde_json::Value> = rows
+                .into_iter()
+                .map(|row| {
+                    titles
+                        .iter()
+                        .map(|t| t.to_string())
+                        .zip(row)
+                        .collect::<serde_json::Map<_, _>>()
+                        .into()
+                })
+                .collect();
+            format!("{}\n", serde_json::Value::Array(objects))
+        }
+        Format::Csv => {
+            let mut text = format!("{}\n", titles.join(","));
+            for row in rows {
+                let fields: Vec<String> = row
+                    .into_iter()
+                    .map(|v| match v {
+                        serde_json::Value::String(s) => csv_field(&s),
+                        serde_json::Value::Null => String::default(),
+                        other => other.to_string(),
+                    })
+                    .collect();
+                text.push_str(&format!("{}\n", fields.join(",")));
+            }
+            text
+        }
+    })
+}
+
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+/// Registers every `name,manager,chat_id` line, returns the imported and skipped counts.
+/// Empty lines, `#` comments and a header line are ignored, so are the extra columns of an export.
+fn import<S: UserRepository + ?Sized>(storage: &S, text: &str) -> (usize, usize) {
+    let (mut imported, mut skipped) = (0, 0);
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || (idx == 0 && line.starts_with("name,")) {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let user = match fields[..] {
+            [name, manager, chat_id, ..]
+                if name
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || c.is_whitespace()) =>
+            {
+                chat_id.parse().ok().map(|chat_id| {
+                    let mut u = User::new();
+                    u.name.value = name.to_string();
+                    u.manager.value = manager.to_string();
+                    u.chat_id.value = chat_id;
+                    u
+                })
+            }
+            _ => None,
+        };
+        match user.map(|u| storage.save_user(&u)) {
+            Some(Ok(_)) => imported += 1,
+            Some(Err(e)) => {
+                eprintln!("Line {}: {}", idx + 1, e);
+                skipped += 1;
+            }
+            None => {
+                eprintln!("Line {}: expected name,manager,chat_id", idx + 1);
+                skipped += 1;
+            }
+        }
+    }
+    (imported, skipped)
+}
+
+/// Report replies are HTML for Telegram, the terminal gets the bare text.
+fn reply_text(reply: ReplyEnum) -> String {
+    let text = match reply {
+        ReplyEnum::Text(text) => text,
+        ReplyEnum::KeyboardMenu(menu) => menu.text,
+        ReplyEnum::KeyboardInline(inline) => inline.text,
+        ReplyEnum::Photo(photo) => {
+            // Only the caption is printed, the picture isn't needed
+            let _ = fs::remove_file(&photo.path);
+            photo.caption
+        }
+        ReplyEnum::None => String::new(),
+    };
+    text.replace("<pre>", "")
+        .replace("</pre>", "")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bot::fsm::{Event, ReportKind, ReportType},
+        db::repo::{MemoryStorage, UserRepository},
+    };
+
+    use super::{export, import, Cli, Command, Format, Table};
+
+    fn parse(line: &str) -> Result<Cli, super::Error> {
+        Cli::parse(line.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    pub fn test_cli_parse_and_roster() {
+        assert_eq!(parse("").unwrap().command, Command::Run);
+        let cli = parse("export surveys --db other.db --format json").unwrap();
+        assert_eq!(cli.database, Some("other.db".to_string()));
+        assert_eq!(
+            cli.command,
+            Command::Export {
+                table: Table::Surveys,
+                format: Format::Json
+            }
+        );
+        assert_eq!(
+            parse("report --type me:7 --period month --kind silent")
+                .unwrap()
+                .command,
+            Command::Report {
+                report_type: ReportType::Me(7),
+                period: Event::ReportOffsetMonth,
+                kind: ReportKind::Silent,
+            }
+        );
+        assert!(parse("report --kind trend --period day").is_err());
+        assert!(parse("restore").is_err());
+        assert!(parse("dance").is_err());
+
+        let storage = MemoryStorage::new();
+        let roster = "name,manager,chat_id\nJonny Black, Elina Bodzhek, 7\n# left\nJonny White,Elina Bodzhek,x\n";
+        assert_eq!(import(&storage, roster), (1, 1));
+        assert_eq!(
+            storage.find_user(7).unwrap().unwrap().manager.value,
+            "Elina Bodzhek".to_string()
+        );
+        let exported = export(&storage, Table::Users, Format::Csv).unwrap();
+        assert_eq!(
+            exported,
+            "name,manager,chat_id,username,first_name,last_name,name_differs\nJonny Black,Elina Bodzhek,7,,,,false\n"
+        );
+        assert_eq!(import(&storage, &exported), (1, 0));
+    }
+}
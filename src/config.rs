@@ -1,4 +1,6 @@
+use chrono::FixedOffset;
 use std::fmt::{Debug, Display};
+use std::path::PathBuf;
 use std::time;
 use std::{env, str::FromStr};
 
@@ -22,7 +24,24 @@ impl Timeout {
 
 impl Config {
     pub fn database_location() -> DatabaseSource {
-        DatabaseSource::File("guardian.db".into())
+        let location: String = Self::read_var_with_default("DATABASE_LOCATION", "guardian.db");
+        match location.as_str() {
+            ":memory:" => DatabaseSource::Memory,
+            _ => DatabaseSource::File(location),
+        }
+    }
+
+    /// Connection string of the PostgreSQL database for users and answers, see `db::repo::storage`.
+    pub fn postgres_url() -> Option<String> {
+        Self::read_optional_var("POSTGRES_URL")
+    }
+
+    pub fn database_pool_size() -> u64 {
+        Self::read_var_with_default("DATABASE_POOL_SIZE", 4)
+    }
+
+    pub fn database_busy_timeout_in_seconds() -> Timeout {
+        Timeout::new(Self::read_var_with_default("DATABASE_BUSY_TIMEOUT", 5))
     }
 
     pub fn time_format() -> &'static str {
@@ -45,8 +64,83 @@ impl Config {
         Timeout::new(Self::read_var_with_default("REQUEST_TIMEOUT", 5))
     }
 
+    /// Requests a second to Telegram from the whole bot, the per-chat limits come on top.
+    pub fn send_rate() -> f64 {
+        Self::read_var_with_default("SEND_RATE", 30)
+    }
+
+    /// Hours an outgoing message is retried for, it's dropped unsent afterwards.
+    pub fn outbox_ttl_in_hours() -> i64 {
+        Self::read_var_with_default("OUTBOX_TTL", 24)
+    }
+
+    /// Telegram holds `getUpdates` open this long waiting for updates, `REQUEST_TIMEOUT` comes on top.
+    pub fn poll_timeout_in_seconds() -> Timeout {
+        Timeout::new(Self::read_var_with_default("POLL_TIMEOUT", 25))
+    }
+
+    pub fn report_utc_offset() -> FixedOffset {
+        let hours: i32 = Self::read_var_with_default("REPORT_UTC_OFFSET", 2);
+        FixedOffset::east_opt(hours * 3600).expect("REPORT_UTC_OFFSET is out of range")
+    }
+
+    pub fn digest_hour() -> u32 {
+        Self::read_var_with_default("DIGEST_HOUR", 9)
+    }
+
+    pub fn team_summary_hour() -> u32 {
+        Self::read_var_with_default("TEAM_SUMMARY_HOUR", 10)
+    }
+
+    /// Public HTTPS address Telegram posts updates to, `getUpdates` is polled without it.
+    pub fn webhook_url() -> Option<String> {
+        Self::read_optional_var("WEBHOOK_URL")
+    }
+
+    pub fn webhook_listen() -> String {
+        Self::read_var_with_default("WEBHOOK_LISTEN", "0.0.0.0:8443")
+    }
+
+    /// Sent back by Telegram in `X-Telegram-Bot-Api-Secret-Token`, required in webhook mode.
+    pub fn webhook_secret() -> Option<String> {
+        Self::read_optional_var("WEBHOOK_SECRET")
+    }
+
+    /// PEM certificate and private key, the webhook server speaks HTTPS when both are set.
+    pub fn webhook_certificate() -> Option<PathBuf> {
+        Self::read_optional_var("WEBHOOK_CERT")
+    }
+
+    pub fn webhook_private_key() -> Option<PathBuf> {
+        Self::read_optional_var("WEBHOOK_KEY")
+    }
+
+    pub fn board_throttle_in_seconds() -> Timeout {
+        Timeout::new(Self::read_var_with_default("BOARD_THROTTLE", 30))
+    }
+
+    /// Telegram id of the admin allowed to run maintenance commands like `/backup`.
     pub fn owner_telegram_id() -> Option<i64> {
-        panic!("Not implemented");
+        Self::read_optional_var("OWNER_TELEGRAM_ID")
+    }
+
+    pub fn backup_dir() -> PathBuf {
+        Self::read_var_with_default::<String, _>("BACKUP_DIR", "backups").into()
+    }
+
+    /// Number of backups kept in `backup_dir`, older ones are removed.
+    pub fn backup_retention() -> usize {
+        Self::read_var_with_default("BACKUP_RETENTION", 7)
+    }
+
+    /// Hours between two scheduled backups, 0 disables the schedule.
+    pub fn backup_interval_in_hours() -> i64 {
+        Self::read_var_with_default("BACKUP_INTERVAL", 24)
+    }
+
+    /// Answers older than this many days are rolled into anonymous aggregates, 0 keeps them forever.
+    pub fn retention_days() -> i64 {
+        Self::read_var_with_default("RETENTION_DAYS", 365)
     }
 
     fn read_var_with_default<T, V>(name: &str, default_value: V) -> T
@@ -61,6 +155,17 @@ impl Config {
             .unwrap_or_else(|_| panic!("{} can not be parsed", name))
     }
 
+    fn read_optional_var<T>(name: &str) -> Option<T>
+    where
+        T: FromStr + Debug,
+    {
+        env::var(name).ok().map(|value| {
+            value
+                .parse()
+                .unwrap_or_else(|_| panic!("{} can not be parsed", name))
+        })
+    }
+
     fn read_var<T>(name: &str) -> T
     where
         T: FromStr + Debug,
@@ -1,4 +1,17 @@
+pub mod aggregate_table;
+pub mod audit_table;
+pub mod backup;
+pub mod board_table;
+pub mod daily_table;
+pub mod migration;
+pub mod outbox_table;
+pub mod pool;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+pub mod repo;
 pub mod report;
+pub mod subscription_table;
 pub mod survey_table;
+pub mod team_chat_table;
 pub mod user_table;
 pub mod utils;
@@ -0,0 +1,238 @@
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::{Connection, Result};
+use typed_builder::TypedBuilder as Builder;
+
+use crate::{config::Config, db::utils::query_wrapper};
+
+use super::{
+    audit_table::{self, AuditEntry},
+    daily_table::{self, DailyAvailability},
+    pool, repo,
+    survey_table::{self, SurveyEntry},
+    utils::Header,
+};
+
+pub const TABLE_NAME: &str = "daily_aggregate";
+
+/// Purges run at most once per this many seconds.
+const PURGE_PERIOD: i64 = 3600;
+static LAST_PURGE: AtomicI64 = AtomicI64::new(0);
+
+/// What is left of a UTC day once its answers are older than the retention
+/// window: totals of all users together, nothing points back to a person.
+#[derive(Debug, Builder)]
+pub struct DailyAggregate {
+    pub id: Header<u64>,
+    pub day: Header<String>,
+    pub users: Header<i64>,
+    pub answers: Header<i64>,
+    pub tracked: Header<i64>,
+    pub electricity_off: Header<i64>,
+    pub network_off: Header<i64>,
+}
+
+impl DailyAggregate {
+    pub fn new() -> Self {
+        Self::builder()
+            .id(Header::new(0, "id"))
+            .day(Header::new(String::default(), "day"))
+            .users(Header::new(0, "users"))
+            .answers(Header::new(0, "answers"))
+            .tracked(Header::new(0, "tracked"))
+            .electricity_off(Header::new(0, "electricity_off"))
+            .network_off(Header::new(0, "network_off"))
+            .build()
+    }
+
+    /// Rolls the answers and the daily rollup older than `cutoff` into the
+    /// aggregates and deletes them, returns the number of deleted answers.
+    pub fn purge_before(cutoff: DateTime<Utc>) -> Result<usize> {
+        let mut conn = pool::get()?;
+        Self::purge_in(&mut conn, cutoff)
+    }
+
+    fn purge_in(conn: &mut Connection, cutoff: DateTime<Utc>) -> Result<usize> {
+        let a = Self::new();
+        let s = SurveyEntry::new();
+        let d = DailyAvailability::new();
+        // The latest answer of a user stays, it is their current state and
+        // the start of the next interval in the daily rollup.
+        let purged_answers = format!(
+            "{} < ?1 AND {} NOT IN (SELECT MAX({}) FROM {} GROUP BY {})",
+            s.timestamp.name,
+            s.id.name,
+            s.id.name,
+            survey_table::TABLE_NAME,
+            s.user_id.name,
+        );
+        let day = cutoff.format(daily_table::DAY_FORMAT).to_string();
+
+        let tx = conn.transaction()?;
+        tx.execute(
+            &query_wrapper(format!(
+                "INSERT INTO {} ({},{},{},{},{},{})
+                SELECT date({}, 'unixepoch'), 0, COUNT(*), 0, 0, 0 FROM {} WHERE {} GROUP BY 1
+                ON CONFLICT({}) DO UPDATE SET {}={}+excluded.{}",
+                TABLE_NAME,
+                a.day.name,
+                a.users.name,
+                a.answers.name,
+                a.tracked.name,
+                a.electricity_off.name,
+                a.network_off.name,
+                s.timestamp.name,
+                survey_table::TABLE_NAME,
+                purged_answers,
+                a.day.name,
+                a.answers.name,
+                a.answers.name,
+                a.answers.name,
+            )),
+            [cutoff.timestamp()],
+        )?;
+        // A day can come back when a kept answer is followed by a new one,
+        // its users were counted already.
+        tx.execute(
+            &query_wrapper(format!(
+                "INSERT INTO {} ({},{},{},{},{},{})
+                SELECT {}, COUNT(*), 0, SUM({}), SUM({}), SUM({}) FROM {} WHERE {} < ?1 GROUP BY {}
+                ON CONFLICT({}) DO UPDATE SET {}=MAX({},excluded.{}),
+                    {}={}+excluded.{}, {}={}+excluded.{}, {}={}+excluded.{}",
+                TABLE_NAME,
+                a.day.name,
+                a.users.name,
+                a.answers.name,
+                a.tracked.name,
+                a.electricity_off.name,
+                a.network_off.name,
+                d.day.name,
+                d.tracked.name,
+                d.electricity_off.name,
+                d.network_off.name,
+                daily_table::TABLE_NAME,
+                d.day.name,
+                d.day.name,
+                a.day.name,
+                a.users.name,
+                a.users.name,
+                a.users.name,
+                a.tracked.name,
+                a.tracked.name,
+                a.tracked.name,
+                a.electricity_off.name,
+                a.electricity_off.name,
+                a.electricity_off.name,
+                a.network_off.name,
+                a.network_off.name,
+                a.network_off.name,
+            )),
+            [&day],
+        )?;
+        let deleted = tx.execute(
+            &query_wrapper(format!(
+                "DELETE FROM {} WHERE {}",
+                survey_table::TABLE_NAME,
+                purged_answers
+            )),
+            [cutoff.timestamp()],
+        )?;
+        tx.execute(
+            &query_wrapper(format!(
+                "DELETE FROM {} WHERE {} < ?1",
+                daily_table::TABLE_NAME,
+                d.day.name
+            )),
+            [&day],
+        )?;
+        tx.commit()?;
+        Ok(deleted)
+    }
+}
+
+impl Default for DailyAggregate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Called from the timer thread, purges whole UTC days older than `Config::retention_days`
+/// together with the audit entries of that age.
+pub fn run_due() {
+    let days = Config::retention_days();
+    let now = Utc::now();
+    if days <= 0 || now.timestamp() - LAST_PURGE.load(Ordering::SeqCst) < PURGE_PERIOD {
+        return;
+    }
+    LAST_PURGE.store(now.timestamp(), Ordering::SeqCst);
+
+    let cutoff = DateTime::<Utc>::from_utc(
+        (now - Duration::days(days)).date_naive().and_hms(0, 0, 0),
+        Utc,
+    );
+    let storage = repo::storage();
+    match DailyAggregate::purge_before(cutoff) {
+        Ok(0) => (),
+        Ok(deleted) => {
+            log::info!("Purged {} answers older than {}", deleted, cutoff);
+            audit_table::record(
+                storage.as_ref(),
+                AuditEntry::deletion(
+                    audit_table::OPERATOR,
+                    "retention",
+                    &format!("{} answers before {}", deleted, cutoff),
+                ),
+            );
+        }
+        Err(e) => log::error!("Can't purge old answers: {}", e),
+    }
+    match storage.prune_audit(cutoff) {
+        Ok(0) => (),
+        Ok(deleted) => log::info!("Pruned {} audit entries older than {}", deleted, cutoff),
+        Err(e) => log::error!("Can't prune the audit trail: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use rusqlite::Connection;
+
+    use crate::db::migration;
+
+    use super::DailyAggregate;
+
+    #[test]
+    pub fn test_aggregate_purge() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migration::run_on(&mut conn).unwrap();
+        // 2022-11-20 10:00, 11:00 and 2022-11-22 10:00
+        conn.execute_batch(
+            "INSERT INTO user (id, name, manager, chat_id) VALUES (1, 'John Doe', 'Richard Roe', 10);
+            INSERT INTO survey VALUES (1, 1, 1668938400, 1, 1);
+            INSERT INTO survey VALUES (2, 1, 1668942000, 0, 1);
+            INSERT INTO survey VALUES (3, 1, 1669111200, 1, 1);
+            INSERT INTO daily_availability VALUES (1, 1, '2022-11-20', 3600, 0, 0);",
+        )
+        .unwrap();
+
+        let cutoff = Utc.ymd(2022, 11, 23).and_hms(0, 0, 0);
+        assert_eq!(DailyAggregate::purge_in(&mut conn, cutoff).unwrap(), 2);
+        // Nothing is counted twice
+        assert_eq!(DailyAggregate::purge_in(&mut conn, cutoff).unwrap(), 0);
+
+        let (answers, users, tracked): (i64, i64, i64) = conn
+            .query_row(
+                "SELECT answers, users, tracked FROM daily_aggregate WHERE day = '2022-11-20'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!((answers, users, tracked), (2, 1, 3600));
+        let left: i64 = conn
+            .query_row("SELECT COUNT(*) FROM survey", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(left, 1);
+    }
+}
@@ -0,0 +1,153 @@
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, Result};
+use typed_builder::TypedBuilder as Builder;
+
+use crate::db::utils::query_wrapper;
+
+use super::{
+    repo::Storage,
+    utils::{self, Header, Query},
+};
+
+pub const TABLE_NAME: &str = "audit";
+
+pub const KIND_TRANSITION: &str = "transition";
+pub const KIND_ADMIN: &str = "admin";
+pub const KIND_DELETION: &str = "deletion";
+
+/// Chat id of the entries made from the command line or the timer thread,
+/// and of the deletions asked by users, their chat ids are not kept.
+pub const OPERATOR: i64 = 0;
+
+/// One FSM transition, admin action or data deletion. Transitions fill the
+/// states, the other kinds keep the action in `event` and leave them empty.
+#[derive(Debug, Clone, Builder)]
+pub struct AuditEntry {
+    pub id: Header<u64>,
+    pub chat_id: Header<i64>,
+    pub timestamp: Header<DateTime<Utc>>,
+    pub kind: Header<String>,
+    pub from_state: Header<String>,
+    pub event: Header<String>,
+    pub to_state: Header<String>,
+    pub result: Header<String>,
+}
+
+impl Default for AuditEntry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AuditEntry {
+    pub fn new() -> Self {
+        Self::builder()
+            .id(Header::new(0, "id"))
+            .chat_id(Header::new(0, "chat_id"))
+            .timestamp(Header::new(Utc::now(), "timestamp"))
+            .kind(Header::new(KIND_TRANSITION.to_string(), "kind"))
+            .from_state(Header::new(String::default(), "from_state"))
+            .event(Header::new(String::default(), "event"))
+            .to_state(Header::new(String::default(), "to_state"))
+            .result(Header::new(String::default(), "result"))
+            .build()
+    }
+
+    pub fn transition(chat_id: i64, from: &str, event: &str, to: &str, result: &str) -> Self {
+        let mut a = Self::new();
+        a.chat_id.value = chat_id;
+        a.from_state.value = from.to_string();
+        a.event.value = event.to_string();
+        a.to_state.value = to.to_string();
+        a.result.value = result.to_string();
+        a
+    }
+
+    pub fn admin(chat_id: i64, action: &str, result: &str) -> Self {
+        Self::action(KIND_ADMIN, chat_id, action, result)
+    }
+
+    pub fn deletion(chat_id: i64, action: &str, result: &str) -> Self {
+        Self::action(KIND_DELETION, chat_id, action, result)
+    }
+
+    fn action(kind: &str, chat_id: i64, action: &str, result: &str) -> Self {
+        let mut a = Self::new();
+        a.kind.value = kind.to_string();
+        a.chat_id.value = chat_id;
+        a.event.value = action.to_string();
+        a.result.value = result.to_string();
+        a
+    }
+
+    pub fn insert_in(&self, conn: &Connection) -> Result<()> {
+        let query = query_wrapper(format!(
+            "INSERT INTO {} ({},{},{},{},{},{},{})
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            TABLE_NAME,
+            self.chat_id.name,
+            self.timestamp.name,
+            self.kind.name,
+            self.from_state.name,
+            self.event.name,
+            self.to_state.name,
+            self.result.name,
+        ));
+        conn.execute(
+            &query,
+            (
+                &self.chat_id.value,
+                &self.timestamp.value.timestamp(),
+                &self.kind.value,
+                &self.from_state.value,
+                &self.event.value,
+                &self.to_state.value,
+                &self.result.value,
+            ),
+        )?;
+        Ok(())
+    }
+
+    /// The `limit` newest entries about `chat_id`, the newest first.
+    pub fn select_latest_by_chat_id_in(
+        conn: &Connection,
+        chat_id: i64,
+        limit: usize,
+    ) -> Result<Vec<Self>> {
+        let a = Self::new();
+        Query::new(&format!("SELECT * FROM {} WHERE", TABLE_NAME))
+            .cond(&Header::new(chat_id, a.chat_id.name))
+            .push(&format!("ORDER BY {} DESC LIMIT ", a.id.name))
+            .bind(limit as i64)
+            .query_map(conn, Self::from_row)
+    }
+
+    pub fn delete_before_in(conn: &Connection, before: DateTime<Utc>) -> Result<usize> {
+        let query = query_wrapper(format!(
+            "DELETE FROM {} WHERE {} < ?1",
+            TABLE_NAME,
+            Self::new().timestamp.name
+        ));
+        conn.execute(&query, [before.timestamp()])
+    }
+
+    fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+        let mut a = Self::new();
+        a.id.value = row.get(0)?;
+        a.chat_id.value = row.get(1)?;
+        a.timestamp.value = utils::from_epoch(row.get(2)?);
+        a.kind.value = row.get(3)?;
+        a.from_state.value = row.get(4)?;
+        a.event.value = row.get(5)?;
+        a.to_state.value = row.get(6)?;
+        a.result.value = row.get(7)?;
+        Ok(a)
+    }
+}
+
+/// Writes the entry, a failure is only logged so it never breaks the action itself.
+pub fn record(storage: &dyn Storage, entry: AuditEntry) {
+    if let Err(e) = storage.add_audit(&entry) {
+        log::error!("Can't record {:?}: {}", entry, e);
+    }
+}
@@ -0,0 +1,204 @@
+use core::fmt;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time,
+};
+
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use rusqlite::{backup::Backup, Connection};
+
+use crate::config::Config;
+
+use super::pool;
+
+const FILE_PREFIX: &str = "guardian-";
+const FILE_SUFFIX: &str = ".db";
+const NAME_FORMAT: &str = "%Y%m%d-%H%M%S";
+const PAGES_PER_STEP: i32 = 100;
+/// Writers get the database between two backup steps.
+const STEP_PAUSE: time::Duration = time::Duration::from_millis(50);
+
+#[derive(Debug)]
+pub enum Error {
+    Sqlite(rusqlite::Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Sqlite(e) => write!(f, "{}", e),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::Sqlite(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// Copy of the database written into `Config::backup_dir`.
+#[derive(Debug)]
+pub struct BackupFile {
+    pub path: PathBuf,
+    pub created: DateTime<Utc>,
+    pub size: u64,
+}
+
+/// Backs the live database up now and drops the copies beyond `Config::backup_retention`.
+pub fn create() -> Result<BackupFile, Error> {
+    let conn = pool::get()?;
+    create_from(
+        &conn,
+        &Config::backup_dir(),
+        Config::backup_retention(),
+        Utc::now(),
+    )
+}
+
+/// Called from the timer thread, backs up when the newest copy is older than `Config::backup_interval_in_hours`.
+pub fn run_due() {
+    let interval = Config::backup_interval_in_hours();
+    if interval <= 0 {
+        return;
+    }
+    let latest = match list(&Config::backup_dir()) {
+        Ok(files) => files.last().map(|f| f.created),
+        Err(e) => {
+            log::error!("Can't list backups: {}", e);
+            return;
+        }
+    };
+    if matches!(latest, Some(created) if Utc::now() - created < Duration::hours(interval)) {
+        return;
+    }
+    match create() {
+        Ok(file) => log::info!("Database backed up to {}", file.path.display()),
+        Err(e) => log::error!("Can't back the database up: {}", e),
+    }
+}
+
+/// Backups in `dir`, the oldest first.
+pub fn list(dir: &Path) -> Result<Vec<BackupFile>, Error> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let created = name
+            .strip_prefix(FILE_PREFIX)
+            .and_then(|name| name.strip_suffix(FILE_SUFFIX))
+            .and_then(|stamp| NaiveDateTime::parse_from_str(stamp, NAME_FORMAT).ok());
+        if let Some(created) = created {
+            files.push(BackupFile {
+                path: entry.path(),
+                created: DateTime::<Utc>::from_utc(created, Utc),
+                size: entry.metadata()?.len(),
+            });
+        }
+    }
+    files.sort_by_key(|f| f.created);
+    Ok(files)
+}
+
+/// Replaces the whole database with the backup at `path`, meant to run before the bot starts.
+pub fn restore(path: &Path) -> Result<(), Error> {
+    let mut conn = pool::get()?;
+    restore_into(&mut conn, path)
+}
+
+fn create_from(
+    conn: &Connection,
+    dir: &Path,
+    retention: usize,
+    now: DateTime<Utc>,
+) -> Result<BackupFile, Error> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!(
+        "{}{}{}",
+        FILE_PREFIX,
+        now.format(NAME_FORMAT),
+        FILE_SUFFIX
+    ));
+    {
+        let mut dst = Connection::open(&path)?;
+        Backup::new(conn, &mut dst)?.run_to_completion(PAGES_PER_STEP, STEP_PAUSE, None)?;
+    }
+    let file = BackupFile {
+        size: fs::metadata(&path)?.len(),
+        path,
+        created: now,
+    };
+    rotate(dir, retention)?;
+    Ok(file)
+}
+
+/// Keeps the `retention` newest backups, the one just written always stays.
+fn rotate(dir: &Path, retention: usize) -> Result<(), Error> {
+    let files = list(dir)?;
+    let stale = files.len().saturating_sub(retention.max(1));
+    for file in files.into_iter().take(stale) {
+        log::info!("Removing old backup {}", file.path.display());
+        fs::remove_file(&file.path)?;
+    }
+    Ok(())
+}
+
+fn restore_into(conn: &mut Connection, path: &Path) -> Result<(), Error> {
+    // Opening a missing file would create it and restore an empty database
+    if !path.is_file() {
+        return Err(Error::Io(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not a backup file", path.display()),
+        )));
+    }
+    let src = Connection::open(path)?;
+    Backup::new(&src, conn)?.run_to_completion(PAGES_PER_STEP, STEP_PAUSE, None)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+    use rusqlite::Connection;
+
+    use super::{create_from, list, restore_into};
+
+    #[test]
+    pub fn test_backup_rotation_and_restore() {
+        let dir = std::env::temp_dir().join(format!("guardian_backup_{}", std::process::id()));
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE answer (id INTEGER); INSERT INTO answer VALUES (1);")
+            .unwrap();
+
+        let start = Utc.ymd(2022, 11, 20).and_hms(10, 0, 0);
+        for hour in 0..3 {
+            create_from(&conn, &dir, 2, start + Duration::hours(hour)).unwrap();
+        }
+        let files = list(&dir).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].created, start + Duration::hours(1));
+        assert!(files[1].size > 0);
+
+        let mut restored = Connection::open_in_memory().unwrap();
+        restore_into(&mut restored, &files[1].path).unwrap();
+        let count: i64 = restored
+            .query_row("SELECT COUNT(*) FROM answer", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+        assert!(restore_into(&mut restored, &dir.join("missing.db")).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
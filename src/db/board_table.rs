@@ -0,0 +1,87 @@
+use rusqlite::Result;
+use typed_builder::TypedBuilder as Builder;
+
+use crate::db::utils::query_wrapper;
+
+use super::{pool, utils::Header};
+
+pub const TABLE_NAME: &str = "board";
+
+/// Live status message of a group or channel, edited in place.
+/// An empty `manager` means the board lists everybody.
+#[derive(Debug, Builder)]
+pub struct Board {
+    pub id: Header<u64>,
+    pub chat_id: Header<i64>,
+    pub message_id: Header<i32>,
+    pub manager: Header<String>,
+}
+
+impl Board {
+    pub fn new() -> Self {
+        Self::builder()
+            .id(Header::new(0, "id"))
+            .chat_id(Header::new(0, "chat_id"))
+            .message_id(Header::new(0, "message_id"))
+            .manager(Header::new(String::default(), "manager"))
+            .build()
+    }
+
+    pub fn insert_or_update(&self) -> Result<()> {
+        let conn = pool::get()?;
+        let query = query_wrapper(format!(
+            "INSERT INTO {} ({},{},{}) VALUES (?1, ?2, ?3)
+            ON CONFLICT({}) DO UPDATE SET {}=?2, {}=?3",
+            TABLE_NAME,
+            self.chat_id.name,
+            self.message_id.name,
+            self.manager.name,
+            self.chat_id.name,
+            self.message_id.name,
+            self.manager.name,
+        ));
+        conn.execute(
+            &query,
+            (
+                &self.chat_id.value,
+                &self.message_id.value,
+                &self.manager.value,
+            ),
+        )?;
+        Ok(())
+    }
+
+    pub fn delete(&self) -> Result<()> {
+        let conn = pool::get()?;
+        let query = query_wrapper(format!(
+            "DELETE FROM {} WHERE {}=?1",
+            TABLE_NAME, self.chat_id.name
+        ));
+        conn.execute(&query, [self.chat_id.value])?;
+        Ok(())
+    }
+
+    pub fn select_all(&self) -> Result<Vec<Self>> {
+        let conn = pool::get()?;
+        let query = query_wrapper(format!("SELECT * FROM {}", TABLE_NAME));
+
+        let mut stmt = conn.prepare(&query)?;
+        let iter = stmt.query_map([], Self::from_row)?;
+        iter.collect()
+    }
+
+    fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+        let mut b = Self::new();
+        b.id.value = row.get(0)?;
+        b.chat_id.value = row.get(1)?;
+        b.message_id.value = row.get(2)?;
+        b.manager.value = row.get(3)?;
+        Ok(b)
+    }
+}
+
+impl Default for Board {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,236 @@
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use rusqlite::{Connection, Result};
+use typed_builder::TypedBuilder as Builder;
+
+use crate::db::utils::query_wrapper;
+
+use super::{
+    pool,
+    survey_table::{self, SurveyEntry},
+    user_table::{self, User},
+    utils::{self, Header, Query, SqlValue},
+};
+
+pub const TABLE_NAME: &str = "daily_availability";
+pub(crate) const DAY_FORMAT: &str = "%Y-%m-%d";
+
+/// Seconds per user and UTC day covered by answers, and how many of them were
+/// without electricity or network. Kept in sync on every survey insert.
+#[derive(Debug, Builder)]
+pub struct DailyAvailability {
+    pub id: Header<u64>,
+    pub user_id: Header<u64>,
+    pub day: Header<String>,
+    pub tracked: Header<i64>,
+    pub electricity_off: Header<i64>,
+    pub network_off: Header<i64>,
+}
+
+/// Sums of the rollup rows of one user within a report window.
+#[derive(Debug)]
+pub struct DailyTotal {
+    pub name: String,
+    pub manager: String,
+    pub chat_id: i64,
+    pub tracked: i64,
+    pub electricity_off: i64,
+    pub network_off: i64,
+}
+
+impl DailyAvailability {
+    pub fn new() -> Self {
+        Self::builder()
+            .id(Header::new(0, "id"))
+            .user_id(Header::new(0, "user_id"))
+            .day(Header::new(String::default(), "day"))
+            .tracked(Header::new(0, "tracked"))
+            .electricity_off(Header::new(0, "electricity_off"))
+            .network_off(Header::new(0, "network_off"))
+            .build()
+    }
+
+    /// Accounts the state a user reported at `from` until their next answer at `to`.
+    pub fn add_with(
+        conn: &Connection,
+        user_id: u64,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        electricity: bool,
+        network: bool,
+    ) -> Result<()> {
+        let d = Self::new();
+        let query = query_wrapper(format!(
+            "INSERT INTO {} ({},{},{},{},{}) VALUES (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT({}, {}) DO UPDATE SET {}={}+?3, {}={}+?4, {}={}+?5",
+            TABLE_NAME,
+            d.user_id.name,
+            d.day.name,
+            d.tracked.name,
+            d.electricity_off.name,
+            d.network_off.name,
+            d.day.name,
+            d.user_id.name,
+            d.tracked.name,
+            d.tracked.name,
+            d.electricity_off.name,
+            d.electricity_off.name,
+            d.network_off.name,
+            d.network_off.name,
+        ));
+        let mut stmt = conn.prepare(&query)?;
+        for (day, seconds) in split_by_day(from, to) {
+            stmt.execute((
+                user_id,
+                day.format(DAY_FORMAT).to_string(),
+                seconds,
+                if electricity { 0 } else { seconds },
+                if network { 0 } else { seconds },
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Drops the rollup and computes it again from the raw survey answers.
+    pub fn rebuild() -> Result<()> {
+        let mut conn = pool::get()?;
+        let tx = conn.transaction()?;
+        tx.execute(&query_wrapper(format!("DELETE FROM {}", TABLE_NAME)), ())?;
+        {
+            let s = SurveyEntry::new();
+            let query = query_wrapper(format!(
+                "SELECT {},{},{},{} FROM {} ORDER BY {}, {}",
+                s.user_id.name,
+                s.timestamp.name,
+                s.electricity.name,
+                s.network.name,
+                survey_table::TABLE_NAME,
+                s.user_id.name,
+                s.id.name,
+            ));
+            let mut stmt = tx.prepare(&query)?;
+            let rows = stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, u64>(0)?,
+                    utils::from_epoch(row.get(1)?),
+                    row.get::<_, bool>(2)?,
+                    row.get::<_, bool>(3)?,
+                ))
+            })?;
+
+            let mut previous: Option<(u64, DateTime<Utc>, bool, bool)> = None;
+            for row in rows {
+                let (user_id, utc, electricity, network) = row?;
+                if let Some((prev_user, prev_utc, prev_ele, prev_net)) = previous {
+                    if prev_user == user_id {
+                        Self::add_with(&tx, user_id, prev_utc, utc, prev_ele, prev_net)?;
+                    }
+                }
+                previous = Some((user_id, utc, electricity, network));
+            }
+        }
+        tx.commit()
+    }
+
+    pub fn is_empty() -> Result<bool> {
+        let conn = pool::get()?;
+        let query = query_wrapper(format!("SELECT COUNT(*) FROM {}", TABLE_NAME));
+        conn.query_row(&query, [], |row| row.get::<_, i64>(0))
+            .map(|count| count == 0)
+    }
+
+    /// Per-user sums for the days `from..=to`, optionally limited to users matching `h`.
+    pub fn select_totals<T: SqlValue>(
+        conn: &Connection,
+        h: Option<&Header<T>>,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> Result<Vec<DailyTotal>> {
+        let u = User::new();
+        let d = Self::new();
+        let mut query = Query::new(&format!(
+            "SELECT {},{},{},SUM({}),SUM({}),SUM({}) FROM {}
+                INNER JOIN {}
+                ON {}.{}={}.{}
+                WHERE {} >= ",
+            u.name.name,
+            u.manager.name,
+            u.chat_id.name,
+            d.tracked.name,
+            d.electricity_off.name,
+            d.network_off.name,
+            user_table::TABLE_NAME,
+            TABLE_NAME,
+            TABLE_NAME,
+            d.user_id.name,
+            user_table::TABLE_NAME,
+            u.id.name,
+            d.day.name,
+        ))
+        .bind(from.format(DAY_FORMAT).to_string())
+        .push(&format!(" AND {} <= ", d.day.name))
+        .bind(to.format(DAY_FORMAT).to_string());
+        if let Some(h) = h {
+            query = query.push(" AND").cond(h);
+        }
+        query
+            .push(&format!(
+                " GROUP BY {}.{}",
+                user_table::TABLE_NAME,
+                u.id.name
+            ))
+            .query_map(conn, |row| {
+                Ok(DailyTotal {
+                    name: row.get(0)?,
+                    manager: row.get(1)?,
+                    chat_id: row.get(2)?,
+                    tracked: row.get(3)?,
+                    electricity_off: row.get(4)?,
+                    network_off: row.get(5)?,
+                })
+            })
+    }
+}
+
+impl Default for DailyAvailability {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Seconds of `from..to` falling into every UTC day it touches.
+pub(crate) fn split_by_day(from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<(NaiveDate, i64)> {
+    let mut parts = Vec::new();
+    let mut start = from;
+    while start < to {
+        let midnight = DateTime::<Utc>::from_utc(
+            (start.date_naive() + Duration::days(1)).and_hms(0, 0, 0),
+            Utc,
+        );
+        let end = if midnight < to { midnight } else { to };
+        parts.push((start.date_naive(), (end - start).num_seconds()));
+        start = end;
+    }
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, TimeZone, Utc};
+
+    use super::split_by_day;
+
+    #[test]
+    pub fn test_daily_split_by_day() {
+        let from = Utc.ymd(2022, 11, 20).and_hms(22, 30, 0);
+        let to = Utc.ymd(2022, 11, 22).and_hms(1, 0, 0);
+        assert_eq!(
+            split_by_day(from, to),
+            vec![
+                (NaiveDate::from_ymd(2022, 11, 20), 90 * 60),
+                (NaiveDate::from_ymd(2022, 11, 21), 24 * 3600),
+                (NaiveDate::from_ymd(2022, 11, 22), 3600),
+            ]
+        );
+        assert!(split_by_day(to, from).is_empty());
+    }
+}
@@ -0,0 +1,361 @@
+use core::fmt;
+
+use rusqlite::{Connection, Transaction};
+
+use super::pool;
+
+/// Schema change applied once, `version` is stored in `PRAGMA user_version` afterwards.
+/// The SQL is written out instead of taken from the table modules, so a migration
+/// does the same on every database whatever the tables look like today.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    apply: fn(&Transaction) -> rusqlite::Result<()>,
+}
+
+/// Ordered by version, never edit an applied migration, append a new one instead.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "tables created before versioning",
+        apply: baseline,
+    },
+    Migration {
+        version: 2,
+        description: "survey.user_id references user.id",
+        apply: survey_foreign_key,
+    },
+    Migration {
+        version: 3,
+        description: "survey.timestamp as indexed epoch seconds",
+        apply: survey_epoch_timestamp,
+    },
+    Migration {
+        version: 4,
+        description: "anonymous daily aggregates of purged answers",
+        apply: daily_aggregate,
+    },
+    Migration {
+        version: 5,
+        description: "audit trail of transitions and admin actions",
+        apply: audit,
+    },
+    Migration {
+        version: 6,
+        description: "telegram profile of users",
+        apply: user_profile,
+    },
+    Migration {
+        version: 7,
+        description: "active flag of users",
+        apply: user_active,
+    },
+    Migration {
+        version: 8,
+        description: "outbox of outgoing messages",
+        apply: outbox,
+    },
+    Migration {
+        version: 9,
+        description: "weekly digest subscriptions",
+        apply: subscription,
+    },
+    Migration {
+        version: 10,
+        description: "team group chats",
+        apply: team_chat,
+    },
+    Migration {
+        version: 11,
+        description: "live status boards",
+        apply: board,
+    },
+    Migration {
+        version: 12,
+        description: "daily availability rollup",
+        apply: daily_availability,
+    },
+    Migration {
+        version: 13,
+        description: "next attempt of outbox messages",
+        apply: outbox_next_attempt,
+    },
+];
+
+#[derive(Debug)]
+pub enum Error {
+    Sqlite(rusqlite::Error),
+    NewerSchema { found: u32, supported: u32 },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Sqlite(e) => write!(f, "{}", e),
+            Error::NewerSchema { found, supported } => write!(
+                f,
+                "database schema version {} is newer than the supported {}, please upgrade the bot",
+                found, supported
+            ),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::Sqlite(e)
+    }
+}
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or_default()
+}
+
+pub fn run() -> Result<u32, Error> {
+    let mut conn = pool::get()?;
+    run_on(&mut conn)
+}
+
+/// Applies the pending migrations, each one in its own transaction, returns the resulting version.
+pub fn run_on(conn: &mut Connection) -> Result<u32, Error> {
+    let current = user_version(conn)?;
+    if current > latest_version() {
+        return Err(Error::NewerSchema {
+            found: current,
+            supported: latest_version(),
+        });
+    }
+    let mut version = current;
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        log::info!(
+            "Migrating the database to version {}: {}",
+            migration.version,
+            migration.description
+        );
+        let tx = conn.transaction()?;
+        (migration.apply)(&tx)?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+        version = migration.version;
+    }
+    Ok(version)
+}
+
+pub fn user_version(conn: &Connection) -> rusqlite::Result<u32> {
+    conn.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+/// The tables of the first release, created on startup then.
+fn baseline(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS user (
+            id  INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT ,
+            name  TEXT NOT NULL UNIQUE,
+            manager  TEXT NOT NULL,
+            chat_id  INTEGER NOT NULL UNIQUE
+        );
+        CREATE TABLE IF NOT EXISTS survey (
+            id  INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            user_id  INTEGER NOT NULL,
+            timestamp  TEXT NOT NULL,
+            electricity  INTEGER,
+            network  INTEGER,
+            FOREIGN KEY (user_id) REFERENCES user(id)
+        );",
+    )
+}
+
+/// SQLite can't alter a constraint, so the table is recreated. Answers of
+/// users which don't exist any more would violate the key and are dropped.
+fn survey_foreign_key(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE survey RENAME TO survey_old;
+        CREATE TABLE survey (
+            id  INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            user_id  INTEGER NOT NULL,
+            timestamp  TEXT NOT NULL,
+            electricity  INTEGER,
+            network  INTEGER,
+            FOREIGN KEY (user_id) REFERENCES user(id)
+        );
+        INSERT INTO survey SELECT * FROM survey_old WHERE user_id IN (SELECT id FROM user);
+        DROP TABLE survey_old;",
+    )
+}
+
+/// Text timestamps were written in UTC with `Config::time_format`, which `strftime` parses as is.
+fn survey_epoch_timestamp(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE survey RENAME TO survey_old;
+        CREATE TABLE survey (
+            id  INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            user_id  INTEGER NOT NULL,
+            timestamp  INTEGER NOT NULL,
+            electricity  INTEGER,
+            network  INTEGER,
+            FOREIGN KEY (user_id) REFERENCES user(id)
+        );
+        INSERT INTO survey (id, user_id, timestamp, electricity, network)
+            SELECT id, user_id,
+                CASE WHEN typeof(timestamp) = 'text'
+                    THEN CAST(strftime('%s', timestamp) AS INTEGER) ELSE timestamp END,
+                electricity, network
+            FROM survey_old;
+        DROP TABLE survey_old;
+        CREATE INDEX IF NOT EXISTS survey_timestamp_idx ON survey (timestamp);
+        CREATE INDEX IF NOT EXISTS survey_user_id_idx ON survey (user_id, timestamp);",
+    )
+}
+
+fn daily_aggregate(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS daily_aggregate (
+            id  INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            day  TEXT NOT NULL UNIQUE,
+            users  INTEGER NOT NULL,
+            answers  INTEGER NOT NULL,
+            tracked  INTEGER NOT NULL,
+            electricity_off  INTEGER NOT NULL,
+            network_off  INTEGER NOT NULL
+        );",
+    )
+}
+
+fn audit(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS audit (
+            id  INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            chat_id  INTEGER NOT NULL,
+            timestamp  INTEGER NOT NULL,
+            kind  TEXT NOT NULL,
+            from_state  TEXT NOT NULL,
+            event  TEXT NOT NULL,
+            to_state  TEXT NOT NULL,
+            result  TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS audit_chat_id_idx ON audit (chat_id, id);",
+    )
+}
+
+fn user_profile(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "ALTER TABLE user ADD COLUMN username TEXT;
+        ALTER TABLE user ADD COLUMN first_name TEXT;
+        ALTER TABLE user ADD COLUMN last_name TEXT;",
+    )
+}
+
+fn user_active(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch("ALTER TABLE user ADD COLUMN active INTEGER NOT NULL DEFAULT 1;")
+}
+
+fn outbox(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS outbox (
+            id  INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            chat_id  INTEGER NOT NULL,
+            method  TEXT NOT NULL,
+            payload  TEXT NOT NULL,
+            created  INTEGER NOT NULL,
+            expires  INTEGER NOT NULL,
+            attempts  INTEGER NOT NULL,
+            status  TEXT NOT NULL,
+            last_error  TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS outbox_status_idx ON outbox (status, id);",
+    )
+}
+
+/// The next tables were created on startup before versioning, hence `IF NOT EXISTS`.
+fn subscription(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS subscription (
+            id  INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            chat_id  INTEGER NOT NULL UNIQUE,
+            enabled  INTEGER NOT NULL,
+            last_sent  TEXT NOT NULL
+        );",
+    )
+}
+
+fn team_chat(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS team_chat (
+            id  INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            chat_id  INTEGER NOT NULL UNIQUE,
+            manager  TEXT NOT NULL,
+            last_summary  TEXT NOT NULL
+        );",
+    )
+}
+
+fn board(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS board (
+            id  INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            chat_id  INTEGER NOT NULL UNIQUE,
+            message_id  INTEGER NOT NULL,
+            manager  TEXT NOT NULL
+        );",
+    )
+}
+
+fn daily_availability(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS daily_availability (
+            id  INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            user_id  INTEGER NOT NULL,
+            day  TEXT NOT NULL,
+            tracked  INTEGER NOT NULL,
+            electricity_off  INTEGER NOT NULL,
+            network_off  INTEGER NOT NULL,
+            UNIQUE (day, user_id)
+        );",
+    )
+}
+
+fn outbox_next_attempt(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch("ALTER TABLE outbox ADD COLUMN next_attempt INTEGER NOT NULL DEFAULT 0;")
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::{latest_version, run_on, user_version, Error};
+
+    #[test]
+    pub fn test_migration_versions() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert_eq!(run_on(&mut conn).unwrap(), latest_version());
+        assert_eq!(user_version(&conn).unwrap(), latest_version());
+        // Applied migrations are skipped
+        assert_eq!(run_on(&mut conn).unwrap(), latest_version());
+
+        conn.pragma_update(None, "user_version", latest_version() + 1)
+            .unwrap();
+        assert!(matches!(run_on(&mut conn), Err(Error::NewerSchema { .. })));
+    }
+
+    #[test]
+    pub fn test_migration_survey_epoch() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE user (id INTEGER PRIMARY KEY, name TEXT, manager TEXT, chat_id INTEGER);
+            CREATE TABLE survey (id INTEGER PRIMARY KEY, user_id INTEGER NOT NULL,
+                timestamp TEXT NOT NULL, electricity INTEGER, network INTEGER);
+            INSERT INTO user VALUES (1, 'John Doe', 'Richard Roe', 10);
+            INSERT INTO survey VALUES (1, 1, '2022-11-20 10:00:00', 1, 0);
+            PRAGMA user_version = 2;",
+        )
+        .unwrap();
+
+        assert_eq!(run_on(&mut conn).unwrap(), latest_version());
+        let timestamp: i64 = conn
+            .query_row("SELECT timestamp FROM survey WHERE id = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(timestamp, 1668938400);
+    }
+}
@@ -0,0 +1,307 @@
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, Result};
+use typed_builder::TypedBuilder as Builder;
+
+use crate::db::utils::query_wrapper;
+
+use super::{
+    pool,
+    utils::{self, Header},
+};
+
+pub const TABLE_NAME: &str = "outbox";
+
+pub const STATUS_PENDING: &str = "pending";
+pub const STATUS_SENT: &str = "sent";
+pub const STATUS_FAILED: &str = "failed";
+pub const STATUS_EXPIRED: &str = "expired";
+
+/// Outgoing message, written before it's sent so it survives a restart.
+/// `payload` holds the JSON params of the Telegram `method`.
+#[derive(Debug, Clone, Builder)]
+pub struct OutboxMessage {
+    pub id: Header<u64>,
+    pub chat_id: Header<i64>,
+    pub method: Header<String>,
+    pub payload: Header<String>,
+    pub created: Header<DateTime<Utc>>,
+    pub expires: Header<DateTime<Utc>>,
+    pub attempts: Header<u32>,
+    pub status: Header<String>,
+    pub last_error: Header<String>,
+    /// A message refused for a while isn't sent again before this, see `select_pending`.
+    pub next_attempt: Header<DateTime<Utc>>,
+}
+
+impl Default for OutboxMessage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OutboxMessage {
+    pub fn new() -> Self {
+        Self::builder()
+            .id(Header::new(0, "id"))
+            .chat_id(Header::new(0, "chat_id"))
+            .method(Header::new(String::default(), "method"))
+            .payload(Header::new(String::default(), "payload"))
+            .created(Header::new(Utc::now(), "created"))
+            .expires(Header::new(Utc::now(), "expires"))
+            .attempts(Header::new(0, "attempts"))
+            .status(Header::new(STATUS_PENDING.to_string(), "status"))
+            .last_error(Header::new(String::default(), "last_error"))
+            .next_attempt(Header::new(DateTime::<Utc>::default(), "next_attempt"))
+            .build()
+    }
+
+    /// A message to send into `chat_id` until `expires`, dropped afterwards.
+    pub fn pending(chat_id: i64, method: &str, payload: String, expires: DateTime<Utc>) -> Self {
+        let mut m = Self::new();
+        m.chat_id.value = chat_id;
+        m.method.value = method.to_string();
+        m.payload.value = payload;
+        m.expires.value = expires;
+        m
+    }
+
+    /// Returns the id of the new row.
+    pub fn insert(&self) -> Result<u64> {
+        let conn = pool::get()?;
+        self.insert_in(&conn)
+    }
+
+    fn insert_in(&self, conn: &Connection) -> Result<u64> {
+        let query = query_wrapper(format!(
+            "INSERT INTO {} ({},{},{},{},{},{},{},{},{})
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            TABLE_NAME,
+            self.chat_id.name,
+            self.method.name,
+            self.payload.name,
+            self.created.name,
+            self.expires.name,
+            self.attempts.name,
+            self.status.name,
+            self.last_error.name,
+            self.next_attempt.name,
+        ));
+        conn.execute(
+            &query,
+            (
+                &self.chat_id.value,
+                &self.method.value,
+                &self.payload.value,
+                &self.created.value.timestamp(),
+                &self.expires.value.timestamp(),
+                &self.attempts.value,
+                &self.status.value,
+                &self.last_error.value,
+                &self.next_attempt.value.timestamp(),
+            ),
+        )?;
+        Ok(conn.last_insert_rowid() as u64)
+    }
+
+    /// Saves the status, attempts, last error and next attempt after a delivery attempt.
+    pub fn update(&self) -> Result<()> {
+        let conn = pool::get()?;
+        self.update_in(&conn)
+    }
+
+    fn update_in(&self, conn: &Connection) -> Result<()> {
+        let query = query_wrapper(format!(
+            "UPDATE {} SET {}=?1, {}=?2, {}=?3, {}=?4 WHERE {}=?5",
+            TABLE_NAME,
+            self.status.name,
+            self.attempts.name,
+            self.last_error.name,
+            self.next_attempt.name,
+            self.id.name,
+        ));
+        conn.execute(
+            &query,
+            (
+                &self.status.value,
+                &self.attempts.value,
+                &self.last_error.value,
+                &self.next_attempt.value.timestamp(),
+                &self.id.value,
+            ),
+        )?;
+        Ok(())
+    }
+
+    /// Up to `limit` pending messages not expired at `now`, the oldest first. A message
+    /// waiting for its next attempt holds back the later ones to the same chat.
+    pub fn select_pending(now: DateTime<Utc>, limit: usize) -> Result<Vec<Self>> {
+        let conn = pool::get()?;
+        Self::select_pending_in(&conn, now, limit)
+    }
+
+    fn select_pending_in(conn: &Connection, now: DateTime<Utc>, limit: usize) -> Result<Vec<Self>> {
+        let m = Self::new();
+        let query = query_wrapper(format!(
+            "SELECT * FROM {} WHERE {}=?1 AND {}>?2 AND {}<=?2
+                AND NOT EXISTS (SELECT 1 FROM {} AS earlier
+                    WHERE earlier.{}={}.{} AND earlier.{}<{}.{}
+                    AND earlier.{}=?1 AND earlier.{}>?2)
+                ORDER BY {} LIMIT ?3",
+            TABLE_NAME,
+            m.status.name,
+            m.expires.name,
+            m.next_attempt.name,
+            TABLE_NAME,
+            m.chat_id.name,
+            TABLE_NAME,
+            m.chat_id.name,
+            m.id.name,
+            TABLE_NAME,
+            m.id.name,
+            m.status.name,
+            m.next_attempt.name,
+            m.id.name
+        ));
+        let mut stmt = conn.prepare(&query)?;
+        let iter = stmt.query_map(
+            (STATUS_PENDING, now.timestamp(), limit as i64),
+            Self::from_row,
+        )?;
+        iter.collect()
+    }
+
+    /// The earliest next attempt of the pending messages put off before, due or not.
+    pub fn select_next_attempt() -> Result<Option<DateTime<Utc>>> {
+        let conn = pool::get()?;
+        Self::select_next_attempt_in(&conn)
+    }
+
+    fn select_next_attempt_in(conn: &Connection) -> Result<Option<DateTime<Utc>>> {
+        let m = Self::new();
+        let query = query_wrapper(format!(
+            "SELECT MIN({}) FROM {} WHERE {}=?1 AND {}>0",
+            m.next_attempt.name, TABLE_NAME, m.status.name, m.next_attempt.name
+        ));
+        let next: Option<i64> = conn.query_row(&query, [STATUS_PENDING], |row| row.get(0))?;
+        Ok(next.map(utils::from_epoch))
+    }
+
+    /// Marks the pending messages which weren't sent by `now` as expired, returns how many.
+    pub fn expire(now: DateTime<Utc>) -> Result<usize> {
+        let conn = pool::get()?;
+        Self::expire_in(&conn, now)
+    }
+
+    fn expire_in(conn: &Connection, now: DateTime<Utc>) -> Result<usize> {
+        let m = Self::new();
+        let query = query_wrapper(format!(
+            "UPDATE {} SET {}=?1 WHERE {}=?2 AND {}<=?3",
+            TABLE_NAME, m.status.name, m.status.name, m.expires.name
+        ));
+        conn.execute(&query, (STATUS_EXPIRED, STATUS_PENDING, now.timestamp()))
+    }
+
+    /// Deletes the messages done with, sent or not, created before `before`.
+    pub fn delete_done_before(before: DateTime<Utc>) -> Result<usize> {
+        let conn = pool::get()?;
+        let m = Self::new();
+        let query = query_wrapper(format!(
+            "DELETE FROM {} WHERE {}<>?1 AND {}<?2",
+            TABLE_NAME, m.status.name, m.created.name
+        ));
+        conn.execute(&query, (STATUS_PENDING, before.timestamp()))
+    }
+
+    /// Drops everything queued or sent to `chat_id`, when the user asks to be forgotten.
+    pub fn delete_by_chat_id_in(conn: &Connection, chat_id: i64) -> Result<usize> {
+        let query = query_wrapper(format!(
+            "DELETE FROM {} WHERE {}=?1",
+            TABLE_NAME,
+            Self::new().chat_id.name
+        ));
+        conn.execute(&query, [chat_id])
+    }
+
+    fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+        let mut m = Self::new();
+        m.id.value = row.get(0)?;
+        m.chat_id.value = row.get(1)?;
+        m.method.value = row.get(2)?;
+        m.payload.value = row.get(3)?;
+        m.created.value = utils::from_epoch(row.get(4)?);
+        m.expires.value = utils::from_epoch(row.get(5)?);
+        m.attempts.value = row.get(6)?;
+        m.status.value = row.get(7)?;
+        m.last_error.value = row.get(8)?;
+        m.next_attempt.value = utils::from_epoch(row.get(9)?);
+        Ok(m)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+    use rusqlite::Connection;
+
+    use crate::db::migration;
+
+    use super::{OutboxMessage, STATUS_EXPIRED, STATUS_SENT};
+
+    #[test]
+    pub fn test_outbox_pending() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migration::run_on(&mut conn).unwrap();
+        let now = Utc.ymd(2022, 11, 20).and_hms(10, 0, 0);
+
+        let stale = OutboxMessage::pending(7, "sendMessage", "{}".into(), now - Duration::hours(1));
+        let first = OutboxMessage::pending(7, "sendMessage", "{}".into(), now + Duration::hours(1));
+        let second = OutboxMessage::pending(8, "sendPhoto", "{}".into(), now + Duration::hours(1));
+        for m in [&stale, &first, &second] {
+            m.insert_in(&conn).unwrap();
+        }
+
+        // Expired messages are never picked up, the others come in order
+        let pending = OutboxMessage::select_pending_in(&conn, now, 10).unwrap();
+        let chats: Vec<i64> = pending.iter().map(|m| m.chat_id.value).collect();
+        assert_eq!(chats, vec![7, 8]);
+        assert_eq!(pending[1].method.value, "sendPhoto");
+        assert_eq!(OutboxMessage::expire_in(&conn, now).unwrap(), 1);
+        assert_eq!(OutboxMessage::expire_in(&conn, now).unwrap(), 0);
+
+        let mut sent = pending[0].clone();
+        sent.status.value = STATUS_SENT.to_string();
+        sent.attempts.value = 1;
+        sent.update_in(&conn).unwrap();
+        let pending = OutboxMessage::select_pending_in(&conn, now, 10).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].chat_id.value, 8);
+
+        let expired: String = conn
+            .query_row("SELECT status FROM outbox WHERE id = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(expired, STATUS_EXPIRED);
+
+        // A message put off holds back the later ones to its chat only
+        let expires = now + Duration::hours(1);
+        let mut put_off = OutboxMessage::pending(9, "sendMessage", "{}".into(), expires);
+        put_off.id.value = put_off.insert_in(&conn).unwrap();
+        OutboxMessage::pending(9, "sendMessage", "{}".into(), expires)
+            .insert_in(&conn)
+            .unwrap();
+        put_off.next_attempt.value = now + Duration::seconds(30);
+        put_off.update_in(&conn).unwrap();
+        let pending = OutboxMessage::select_pending_in(&conn, now, 10).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].chat_id.value, 8);
+        assert_eq!(
+            OutboxMessage::select_next_attempt_in(&conn).unwrap(),
+            Some(now + Duration::seconds(30))
+        );
+        let later = now + Duration::seconds(30);
+        let pending = OutboxMessage::select_pending_in(&conn, later, 10).unwrap();
+        assert_eq!(pending.len(), 3);
+        assert_eq!(pending[1].id.value, put_off.id.value);
+    }
+}
@@ -0,0 +1,171 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Condvar, Mutex,
+    },
+};
+
+use once_cell::sync::OnceCell;
+use rusqlite::{ffi, Connection, OpenFlags, Result};
+
+use crate::config::Config;
+
+use super::utils::DatabaseSource;
+
+static POOL: OnceCell<Pool> = OnceCell::new();
+
+/// Numbers the in-memory databases, two pools never see each other's tables.
+static MEMORY_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Fixed set of connections opened once and handed out to the table modules.
+pub struct Pool {
+    idle: Mutex<Vec<Connection>>,
+    available: Condvar,
+    timeout: std::time::Duration,
+}
+
+/// Connection borrowed from the pool, goes back on drop.
+pub struct PooledConnection {
+    conn: Option<Connection>,
+    pool: &'static Pool,
+}
+
+impl Pool {
+    pub(crate) fn new(src: DatabaseSource, size: usize) -> Result<Self> {
+        let timeout = Config::database_busy_timeout_in_seconds().duration();
+        // The memory database is gone with its last connection and shared cache
+        // locks whole tables, a single connection avoids both.
+        let size = match src {
+            DatabaseSource::Memory => 1,
+            DatabaseSource::File(_) => size.max(1),
+        };
+        // All the connections of an in-memory database must share one cache,
+        // otherwise every connection sees its own empty database.
+        let memory_uri = format!(
+            "file:guardian{}?mode=memory&cache=shared",
+            MEMORY_COUNT.fetch_add(1, Ordering::Relaxed)
+        );
+        let mut idle = Vec::with_capacity(size);
+        for _ in 0..size {
+            idle.push(Self::open(&src, &memory_uri, timeout)?);
+        }
+        Ok(Self {
+            idle: Mutex::new(idle),
+            available: Condvar::new(),
+            timeout,
+        })
+    }
+
+    fn open(
+        src: &DatabaseSource,
+        memory_uri: &str,
+        timeout: std::time::Duration,
+    ) -> Result<Connection> {
+        let conn = match src {
+            DatabaseSource::Memory => Connection::open_with_flags(
+                memory_uri,
+                OpenFlags::default() | OpenFlags::SQLITE_OPEN_URI,
+            )?,
+            DatabaseSource::File(path) => {
+                let conn = Connection::open(path.as_str())?;
+                conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| {
+                    row.get::<_, String>(0)
+                })?;
+                conn
+            }
+        };
+        conn.busy_timeout(timeout)?;
+        // SQLite doesn't enforce foreign keys unless asked on every connection
+        conn.pragma_update(None, "foreign_keys", true)?;
+        Ok(conn)
+    }
+
+    /// Waits up to the busy timeout for a free connection.
+    pub(crate) fn get(&'static self) -> Result<PooledConnection> {
+        let idle = self.idle.lock().unwrap();
+        let (mut idle, _) = self
+            .available
+            .wait_timeout_while(idle, self.timeout, |idle| idle.is_empty())
+            .unwrap();
+        match idle.pop() {
+            Some(conn) => Ok(PooledConnection {
+                conn: Some(conn),
+                pool: self,
+            }),
+            None => Err(rusqlite::Error::SqliteFailure(
+                ffi::Error::new(ffi::SQLITE_BUSY),
+                Some("no free database connection in the pool".into()),
+            )),
+        }
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.idle.lock().unwrap().push(conn);
+            self.pool.available.notify_one();
+        }
+    }
+}
+
+/// Opens the pool for `Config::database_location`, called once at startup.
+pub fn init() -> Result<()> {
+    POOL.get_or_try_init(|| {
+        Pool::new(
+            Config::database_location(),
+            Config::database_pool_size() as usize,
+        )
+    })
+    .map(|_| ())
+}
+
+/// The pool for `Config::database_location`, opened on the first use if `init` wasn't called.
+pub fn shared() -> Result<&'static Pool> {
+    init()?;
+    Ok(POOL.get().unwrap())
+}
+
+/// Borrows a connection from the `shared` pool.
+pub fn get() -> Result<PooledConnection> {
+    shared()?.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::utils::DatabaseSource;
+
+    use super::Pool;
+
+    #[test]
+    pub fn test_pool_memory_is_shared() {
+        let pool: &'static Pool =
+            Box::leak(Box::new(Pool::new(DatabaseSource::Memory, 4).unwrap()));
+        {
+            let conn = pool.get().unwrap();
+            conn.execute("CREATE TABLE pooled (id INTEGER)", ())
+                .unwrap();
+            conn.execute("INSERT INTO pooled VALUES (1)", ()).unwrap();
+        }
+        let conn = pool.get().unwrap();
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM pooled", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+}
@@ -0,0 +1,794 @@
+use std::sync::{Mutex, MutexGuard};
+
+use ::postgres::{types::ToSql, Client, NoTls, Row};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+
+use crate::bot::{fsm::ReportType, telecom};
+
+use super::{
+    audit_table::{self, AuditEntry},
+    daily_table::DailyTotal,
+    outbox_table::OutboxMessage,
+    pool,
+    repo::{self, AuditRepository, ReportQuery, Result, SurveyRepository, UserRepository},
+    report::{Report, Silent, TimeOffset},
+    subscription_table::Subscription,
+    survey_table::{self, SurveyEntry},
+    user_table::{self, User},
+    utils,
+};
+
+/// Users, answers and the audit trail kept in PostgreSQL, the other tables stay in the
+/// SQLite pool. Forgetting a user deletes the SQLite rows first.
+/// There is no daily rollup, `daily_totals` splits the answers on the fly.
+/// Violated unique and foreign keys are reported as `repo::Error::Constraint` like
+/// the other backends.
+pub struct PostgresStorage {
+    url: String,
+    client: Mutex<Client>,
+}
+
+impl PostgresStorage {
+    /// Connects to `url` and creates the missing tables.
+    pub fn connect(url: &str) -> Result<Self> {
+        let mut client = Client::connect(url, NoTls)?;
+        create_tables(&mut client)?;
+        Ok(Self {
+            url: url.to_string(),
+            client: Mutex::new(client),
+        })
+    }
+
+    /// The shared connection, opened again when the server dropped it.
+    fn client(&self) -> Result<MutexGuard<'_, Client>> {
+        let mut client = self.client.lock().unwrap();
+        if client.is_closed() {
+            log::warn!("PostgreSQL connection is closed, reconnecting");
+            *client = Client::connect(&self.url, NoTls)?;
+        }
+        Ok(client)
+    }
+
+    fn reports_where(&self, query: Query) -> Result<Vec<Report>> {
+        let rows = query.query(&mut *self.client()?)?;
+        Ok(rows.iter().map(report_from_row).collect())
+    }
+}
+
+fn create_tables(client: &mut Client) -> Result<()> {
+    let u = User::new();
+    let s = SurveyEntry::new();
+    client
+        .batch_execute(&utils::query_wrapper(format!(
+            "CREATE TABLE IF NOT EXISTS \"{}\" (
+            {}  BIGSERIAL PRIMARY KEY,
+            {}  TEXT NOT NULL UNIQUE,
+            {}  TEXT NOT NULL,
+            {}  BIGINT NOT NULL UNIQUE
+            );
+            CREATE TABLE IF NOT EXISTS {} (
+            {}  BIGSERIAL PRIMARY KEY,
+            {}  BIGINT NOT NULL REFERENCES \"{}\" ({}),
+            \"{}\"  BIGINT NOT NULL,
+            {}  BOOLEAN NOT NULL,
+            {}  BOOLEAN NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS {}_{}_idx ON {} (\"{}\");
+            CREATE INDEX IF NOT EXISTS {}_{}_idx ON {} ({}, \"{}\");
+            ALTER TABLE \"{}\" ADD COLUMN IF NOT EXISTS {} TEXT,
+                ADD COLUMN IF NOT EXISTS {} TEXT,
+                ADD COLUMN IF NOT EXISTS {} TEXT,
+                ADD COLUMN IF NOT EXISTS {} BOOLEAN NOT NULL DEFAULT TRUE;",
+            user_table::TABLE_NAME,
+            u.id.name,
+            u.name.name,
+            u.manager.name,
+            u.chat_id.name,
+            survey_table::TABLE_NAME,
+            s.id.name,
+            s.user_id.name,
+            user_table::TABLE_NAME,
+            u.id.name,
+            s.timestamp.name,
+            s.electricity.name,
+            s.network.name,
+            survey_table::TABLE_NAME,
+            s.timestamp.name,
+            survey_table::TABLE_NAME,
+            s.timestamp.name,
+            survey_table::TABLE_NAME,
+            s.user_id.name,
+            survey_table::TABLE_NAME,
+            s.user_id.name,
+            s.timestamp.name,
+            user_table::TABLE_NAME,
+            u.username.name,
+            u.first_name.name,
+            u.last_name.name,
+            u.active.name,
+        )))
+        .map_err(repo::Error::from)?;
+    let a = AuditEntry::new();
+    client
+        .batch_execute(&utils::query_wrapper(format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+            {}  BIGSERIAL PRIMARY KEY,
+            {}  BIGINT NOT NULL,
+            \"{}\"  BIGINT NOT NULL,
+            {}  TEXT NOT NULL,
+            {}  TEXT NOT NULL,
+            {}  TEXT NOT NULL,
+            {}  TEXT NOT NULL,
+            {}  TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS {}_{}_idx ON {} ({}, {});",
+            audit_table::TABLE_NAME,
+            a.id.name,
+            a.chat_id.name,
+            a.timestamp.name,
+            a.kind.name,
+            a.from_state.name,
+            a.event.name,
+            a.to_state.name,
+            a.result.name,
+            audit_table::TABLE_NAME,
+            a.chat_id.name,
+            audit_table::TABLE_NAME,
+            a.chat_id.name,
+            a.id.name,
+        )))
+        .map_err(repo::Error::from)
+}
+
+/// The digest subscription and the outbox stay in SQLite, see `PostgresStorage`.
+fn forget_in_sqlite(chat_id: i64) -> rusqlite::Result<()> {
+    let mut conn = pool::get()?;
+    let tx = conn.transaction()?;
+    Subscription::delete_by_chat_id_in(&tx, chat_id)?;
+    OutboxMessage::delete_by_chat_id_in(&tx, chat_id)?;
+    tx.commit()
+}
+
+/// SQL text with `$N` placeholders and the values bound to them, like `utils::Query`.
+#[derive(Default)]
+struct Query {
+    sql: String,
+    params: Vec<Box<dyn ToSql + Sync>>,
+}
+
+impl Query {
+    fn new(sql: &str) -> Self {
+        Self {
+            sql: sql.to_string(),
+            params: Vec::new(),
+        }
+    }
+
+    fn push(mut self, sql: &str) -> Self {
+        self.sql.push_str(sql);
+        self
+    }
+
+    /// Appends a placeholder for `value`.
+    fn bind<T: ToSql + Sync + 'static>(mut self, value: T) -> Self {
+        self.params.push(Box::new(value));
+        self.sql.push_str(&format!("${}", self.params.len()));
+        self
+    }
+
+    /// Appends ` AND ` with the condition on the joined user selecting `report_type`.
+    fn selected(self, report_type: &ReportType) -> Self {
+        let u = User::new();
+        match report_type {
+            ReportType::All => self,
+            ReportType::Me(chat_id) => self
+                .push(&format!(" AND u.{}=", u.chat_id.name))
+                .bind(*chat_id),
+            ReportType::Team(manager) => self
+                .push(&format!(" AND u.{}=", u.manager.name))
+                .bind(manager.clone()),
+        }
+    }
+
+    fn query(&self, client: &mut Client) -> Result<Vec<Row>> {
+        let params: Vec<&(dyn ToSql + Sync)> = self.params.iter().map(|p| p.as_ref()).collect();
+        client
+            .query(utils::query_wrapper(self.sql.clone()).as_str(), &params)
+            .map_err(repo::Error::from)
+    }
+}
+
+/// Answers joined with their users, conditions follow after `WHERE TRUE`.
+fn select_reports() -> Query {
+    let u = User::new();
+    let s = SurveyEntry::new();
+    Query::new(&format!(
+        "SELECT u.{}, u.{}, u.{}, s.\"{}\", s.{}, s.{}, u.{} FROM \"{}\" u
+            INNER JOIN {} s ON s.{}=u.{} WHERE TRUE ",
+        u.name.name,
+        u.manager.name,
+        u.chat_id.name,
+        s.timestamp.name,
+        s.electricity.name,
+        s.network.name,
+        u.username.name,
+        user_table::TABLE_NAME,
+        survey_table::TABLE_NAME,
+        s.user_id.name,
+        u.id.name,
+    ))
+}
+
+fn order_by_id(query: Query) -> Query {
+    query.push(&format!(" ORDER BY s.{}", SurveyEntry::new().id.name))
+}
+
+fn report_from_row(row: &Row) -> Report {
+    let mut r = Report::new();
+    r.name.value = row.get(0);
+    r.manager.value = row.get(1);
+    r.chat_id.value = row.get(2);
+    r.timestamp.value = utils::from_epoch(row.get(3));
+    r.electricity.value = row.get(4);
+    r.network.value = row.get(5);
+    r.username.value = row.get(6);
+    r
+}
+
+fn user_from_row(row: &Row) -> User {
+    let mut u = User::new();
+    u.id.value = row.get::<_, i64>(0) as u64;
+    u.name.value = row.get(1);
+    u.manager.value = row.get(2);
+    u.chat_id.value = row.get(3);
+    u.username.value = row.get(4);
+    u.first_name.value = row.get(5);
+    u.last_name.value = row.get(6);
+    u.active.value = row.get(7);
+    u
+}
+
+/// The columns read by `user_from_row`.
+fn user_columns() -> String {
+    let u = User::new();
+    [
+        u.id.name,
+        u.name.name,
+        u.manager.name,
+        u.chat_id.name,
+        u.username.name,
+        u.first_name.name,
+        u.last_name.name,
+        u.active.name,
+    ]
+    .join(", ")
+}
+
+fn select_users() -> String {
+    format!(
+        "SELECT {} FROM \"{}\"",
+        user_columns(),
+        user_table::TABLE_NAME
+    )
+}
+
+impl UserRepository for PostgresStorage {
+    fn save_user(&self, user: &User) -> Result<User> {
+        let u = User::new();
+        let query = utils::query_wrapper(format!(
+            "INSERT INTO \"{}\" ({}, {}, {}) VALUES ($1, $2, $3)
+            ON CONFLICT ({}) DO UPDATE SET {}=excluded.{}, {}=excluded.{}
+            RETURNING {}",
+            user_table::TABLE_NAME,
+            u.name.name,
+            u.manager.name,
+            u.chat_id.name,
+            u.chat_id.name,
+            u.name.name,
+            u.name.name,
+            u.manager.name,
+            u.manager.name,
+            user_columns(),
+        ));
+        let row = self.client()?.query_one(
+            query.as_str(),
+            &[&user.name.value, &user.manager.value, &user.chat_id.value],
+        )?;
+        Ok(user_from_row(&row))
+    }
+
+    fn find_user(&self, chat_id: i64) -> Result<Option<User>> {
+        let query = utils::query_wrapper(format!(
+            "{} WHERE {}=$1",
+            select_users(),
+            User::new().chat_id.name
+        ));
+        let row = self.client()?.query_opt(query.as_str(), &[&chat_id])?;
+        Ok(row.as_ref().map(user_from_row))
+    }
+
+    fn all_users(&self) -> Result<Vec<User>> {
+        let query = utils::query_wrapper(format!(
+            "{} ORDER BY {}",
+            select_users(),
+            User::new().id.name
+        ));
+        let rows = self.client()?.query(query.as_str(), &[])?;
+        Ok(rows.iter().map(user_from_row).collect())
+    }
+
+    fn update_profile(&self, chat_id: i64, profile: &telecom::User) -> Result<bool> {
+        let u = User::new();
+        let query = utils::query_wrapper(format!(
+            "UPDATE \"{}\" SET {}=$1, {}=$2, {}=$3 WHERE {}=$4",
+            user_table::TABLE_NAME,
+            u.username.name,
+            u.first_name.name,
+            u.last_name.name,
+            u.chat_id.name,
+        ));
+        let updated = self.client()?.execute(
+            query.as_str(),
+            &[
+                &profile.username,
+                &profile.first_name,
+                &profile.last_name,
+                &chat_id,
+            ],
+        )?;
+        Ok(updated > 0)
+    }
+
+    fn set_active(&self, chat_id: i64, active: bool) -> Result<bool> {
+        let u = User::new();
+        let query = utils::query_wrapper(format!(
+            "UPDATE \"{}\" SET {}=$1 WHERE {}=$2",
+            user_table::TABLE_NAME,
+            u.active.name,
+            u.chat_id.name,
+        ));
+        let updated = self
+            .client()?
+            .execute(query.as_str(), &[&active, &chat_id])?;
+        Ok(updated > 0)
+    }
+
+    fn inactive_chat_ids(&self) -> Result<Vec<i64>> {
+        let u = User::new();
+        let query = utils::query_wrapper(format!(
+            "SELECT {} FROM \"{}\" WHERE NOT {}",
+            u.chat_id.name,
+            user_table::TABLE_NAME,
+            u.active.name,
+        ));
+        let rows = self.client()?.query(query.as_str(), &[])?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    fn forget_user(&self, chat_id: i64) -> Result<bool> {
+        let u = User::new();
+        let s = SurveyEntry::new();
+        let mut client = self.client()?;
+        let mut tx = client.transaction()?;
+        let row = tx.query_opt(
+            utils::query_wrapper(format!(
+                "SELECT {} FROM \"{}\" WHERE {}=$1",
+                u.id.name,
+                user_table::TABLE_NAME,
+                u.chat_id.name
+            ))
+            .as_str(),
+            &[&chat_id],
+        )?;
+        let id: i64 = match row {
+            Some(row) => row.get(0),
+            None => return Ok(false),
+        };
+        // A failure after this leaves the user registered, so /forget can be repeated
+        forget_in_sqlite(chat_id)?;
+        for (table, column) in [
+            (survey_table::TABLE_NAME, s.user_id.name),
+            (user_table::TABLE_NAME, u.id.name),
+        ] {
+            tx.execute(
+                utils::query_wrapper(format!("DELETE FROM \"{}\" WHERE {}=$1", table, column))
+                    .as_str(),
+                &[&id],
+            )?;
+        }
+        tx.execute(
+            utils::query_wrapper(format!(
+                "DELETE FROM {} WHERE {}=$1",
+                audit_table::TABLE_NAME,
+                AuditEntry::new().chat_id.name
+            ))
+            .as_str(),
+            &[&chat_id],
+        )?;
+        tx.commit()?;
+        Ok(true)
+    }
+}
+
+impl SurveyRepository for PostgresStorage {
+    fn add_survey(&self, entry: &SurveyEntry) -> Result<SurveyEntry> {
+        let s = SurveyEntry::new();
+        let query = utils::query_wrapper(format!(
+            "INSERT INTO {} ({}, \"{}\", {}, {}) VALUES ($1, $2, $3, $4) RETURNING {}",
+            survey_table::TABLE_NAME,
+            s.user_id.name,
+            s.timestamp.name,
+            s.electricity.name,
+            s.network.name,
+            s.id.name,
+        ));
+        let row = self.client()?.query_one(
+            query.as_str(),
+            &[
+                &(entry.user_id.value as i64),
+                &entry.timestamp.value.timestamp(),
+                &entry.electricity.value,
+                &entry.network.value,
+            ],
+        )?;
+        let mut inserted = entry.clone();
+        inserted.id.value = row.get::<_, i64>(0) as u64;
+        Ok(inserted)
+    }
+}
+
+impl ReportQuery for PostgresStorage {
+    fn reports(
+        &self,
+        report_type: &ReportType,
+        offset: &TimeOffset,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Report>> {
+        let timestamp = SurveyEntry::new().timestamp.name;
+        self.reports_where(order_by_id(
+            select_reports()
+                .push(&format!(" AND s.\"{}\" >= ", timestamp))
+                .bind(offset.start_from(end).timestamp())
+                .push(&format!(" AND s.\"{}\" < ", timestamp))
+                .bind(end.timestamp())
+                .selected(report_type),
+        ))
+    }
+
+    fn latest_reports(&self, report_type: &ReportType) -> Result<Vec<Report>> {
+        let s = SurveyEntry::new();
+        self.reports_where(order_by_id(
+            select_reports()
+                .push(&format!(
+                    " AND s.{} IN (SELECT MAX({}) FROM {} GROUP BY {})",
+                    s.id.name,
+                    s.id.name,
+                    survey_table::TABLE_NAME,
+                    s.user_id.name,
+                ))
+                .selected(report_type),
+        ))
+    }
+
+    fn latest_reports_before(
+        &self,
+        report_type: &ReportType,
+        before: DateTime<Utc>,
+    ) -> Result<Vec<Report>> {
+        let s = SurveyEntry::new();
+        self.reports_where(order_by_id(
+            select_reports()
+                .push(&format!(
+                    " AND s.{} IN (SELECT MAX({}) FROM {} WHERE \"{}\" < ",
+                    s.id.name,
+                    s.id.name,
+                    survey_table::TABLE_NAME,
+                    s.timestamp.name,
+                ))
+                .bind(before.timestamp())
+                .push(&format!(" GROUP BY {})", s.user_id.name))
+                .selected(report_type),
+        ))
+    }
+
+    fn last_report(&self, chat_id: i64) -> Result<Option<Report>> {
+        Ok(self
+            .reports_where(
+                select_reports()
+                    .selected(&ReportType::Me(chat_id))
+                    .push(&format!(
+                        " ORDER BY s.{} DESC LIMIT 1",
+                        SurveyEntry::new().id.name
+                    )),
+            )?
+            .pop())
+    }
+
+    fn all_reports(&self) -> Result<Vec<Report>> {
+        self.reports_where(order_by_id(select_reports()))
+    }
+
+    fn daily_totals(
+        &self,
+        report_type: &ReportType,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> Result<Vec<DailyTotal>> {
+        let u = User::new();
+        let s = SurveyEntry::new();
+        let start = from.and_hms(0, 0, 0).timestamp();
+        let end = (to + Duration::days(1)).and_hms(0, 0, 0).timestamp();
+        // Every answer lasts until the next one of the same user
+        let query = Query::new(&format!(
+            "SELECT * FROM (
+                SELECT u.{}, u.{}, u.{}, s.\"{}\", s.{}, s.{}, u.{},
+                    LEAD(s.\"{}\") OVER (PARTITION BY s.{} ORDER BY s.{}) AS until, s.{} AS survey_id
+                FROM \"{}\" u INNER JOIN {} s ON s.{}=u.{} WHERE TRUE ",
+            u.name.name,
+            u.manager.name,
+            u.chat_id.name,
+            s.timestamp.name,
+            s.electricity.name,
+            s.network.name,
+            u.username.name,
+            s.timestamp.name,
+            s.user_id.name,
+            s.id.name,
+            s.id.name,
+            user_table::TABLE_NAME,
+            survey_table::TABLE_NAME,
+            s.user_id.name,
+            u.id.name,
+        ))
+        .selected(report_type)
+        .push(") intervals WHERE until > ")
+        .bind(start)
+        .push(&format!(" AND \"{}\" < ", s.timestamp.name))
+        .bind(end)
+        .push(" ORDER BY survey_id");
+
+        let mut totals = Vec::new();
+        for row in query.query(&mut *self.client()?)? {
+            let until = utils::from_epoch(row.get(7));
+            repo::add_interval(&mut totals, &report_from_row(&row), until, from, to);
+        }
+        Ok(totals)
+    }
+
+    fn silent(&self, report_type: &ReportType, offset: &TimeOffset) -> Result<Vec<Silent>> {
+        let u = User::new();
+        let s = SurveyEntry::new();
+        let max_timestamp = format!("MAX(s.\"{}\")", s.timestamp.name);
+        let query = Query::new(&format!(
+            "SELECT u.{}, u.{}, u.{}, {}, u.{} FROM \"{}\" u
+                LEFT JOIN {} s ON s.{}=u.{} WHERE u.{} ",
+            u.name.name,
+            u.manager.name,
+            u.chat_id.name,
+            max_timestamp,
+            u.username.name,
+            user_table::TABLE_NAME,
+            survey_table::TABLE_NAME,
+            s.user_id.name,
+            u.id.name,
+            u.active.name,
+        ))
+        .selected(report_type)
+        .push(&format!(
+            " GROUP BY u.{} HAVING {} IS NULL OR {} < ",
+            u.id.name, max_timestamp, max_timestamp
+        ))
+        .bind(offset.start_from(Utc::now()).timestamp())
+        // SQLite sorts users without answers first
+        .push(&format!(
+            " ORDER BY {} NULLS FIRST, u.{}",
+            max_timestamp, u.name.name
+        ));
+
+        let rows = query.query(&mut *self.client()?)?;
+        Ok(rows
+            .iter()
+            .map(|row| Silent {
+                name: row.get(0),
+                manager: row.get(1),
+                chat_id: row.get(2),
+                username: row.get(4),
+                last_answer: row.get::<_, Option<i64>>(3).map(utils::from_epoch),
+            })
+            .collect())
+    }
+}
+
+impl AuditRepository for PostgresStorage {
+    fn add_audit(&self, entry: &AuditEntry) -> Result<()> {
+        let a = AuditEntry::new();
+        let query = utils::query_wrapper(format!(
+            "INSERT INTO {} ({}, \"{}\", {}, {}, {}, {}, {})
+            VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            audit_table::TABLE_NAME,
+            a.chat_id.name,
+            a.timestamp.name,
+            a.kind.name,
+            a.from_state.name,
+            a.event.name,
+            a.to_state.name,
+            a.result.name,
+        ));
+        self.client()?.execute(
+            query.as_str(),
+            &[
+                &entry.chat_id.value,
+                &entry.timestamp.value.timestamp(),
+                &entry.kind.value,
+                &entry.from_state.value,
+                &entry.event.value,
+                &entry.to_state.value,
+                &entry.result.value,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn audit_trail(&self, chat_id: i64, limit: usize) -> Result<Vec<AuditEntry>> {
+        let a = AuditEntry::new();
+        let query = utils::query_wrapper(format!(
+            "SELECT {}, {}, \"{}\", {}, {}, {}, {}, {} FROM {}
+            WHERE {}=$1 ORDER BY {} DESC LIMIT $2",
+            a.id.name,
+            a.chat_id.name,
+            a.timestamp.name,
+            a.kind.name,
+            a.from_state.name,
+            a.event.name,
+            a.to_state.name,
+            a.result.name,
+            audit_table::TABLE_NAME,
+            a.chat_id.name,
+            a.id.name,
+        ));
+        let rows = self
+            .client()?
+            .query(query.as_str(), &[&chat_id, &(limit as i64)])?;
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let mut a = AuditEntry::new();
+                a.id.value = row.get::<_, i64>(0) as u64;
+                a.chat_id.value = row.get(1);
+                a.timestamp.value = utils::from_epoch(row.get(2));
+                a.kind.value = row.get(3);
+                a.from_state.value = row.get(4);
+                a.event.value = row.get(5);
+                a.to_state.value = row.get(6);
+                a.result.value = row.get(7);
+                a
+            })
+            .collect())
+    }
+
+    fn prune_audit(&self, before: DateTime<Utc>) -> Result<usize> {
+        let query = utils::query_wrapper(format!(
+            "DELETE FROM {} WHERE \"{}\" < $1",
+            audit_table::TABLE_NAME,
+            AuditEntry::new().timestamp.name
+        ));
+        let deleted = self
+            .client()?
+            .execute(query.as_str(), &[&before.timestamp()])?;
+        Ok(deleted as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::{
+        bot::{fsm::ReportType, telecom},
+        db::{
+            audit_table::{self, AuditEntry},
+            repo::{AuditRepository, ReportQuery, SurveyRepository, UserRepository},
+            report::TimeOffset,
+            survey_table::SurveyEntry,
+            user_table::User,
+        },
+    };
+
+    use super::PostgresStorage;
+
+    /// Runs against the database in `POSTGRES_TEST_URL` and empties its tables, skipped without it.
+    #[test]
+    pub fn test_postgres_storage() {
+        let url = match std::env::var("POSTGRES_TEST_URL") {
+            Ok(url) => url,
+            Err(_) => return,
+        };
+        let storage = PostgresStorage::connect(&url).unwrap();
+        storage
+            .client()
+            .unwrap()
+            .batch_execute("TRUNCATE survey, \"user\" RESTART IDENTITY")
+            .unwrap();
+
+        let mut user = User::new();
+        user.chat_id.value = 10;
+        let user = storage.save_user(&user).unwrap();
+        let mut other = User::new();
+        other.chat_id.value = 11;
+        assert!(storage.save_user(&other).is_err());
+        other.name.value = "Jane Roe".to_string();
+        other.manager.value = "Elina Bodzhek".to_string();
+        storage.save_user(&other).unwrap();
+        let profile = telecom::User::builder()
+            .first_name("Roe".to_string())
+            .last_name("Jane")
+            .username("jroe")
+            .build();
+        assert!(storage.update_profile(11, &profile).unwrap());
+        assert!(!storage.update_profile(12, &profile).unwrap());
+        let other = storage.find_user(11).unwrap().unwrap();
+        assert_eq!(other.username.value, Some("jroe".to_string()));
+        assert!(!other.differs_from_profile());
+
+        let start = Utc.ymd(2022, 11, 20).and_hms(22, 0, 0);
+        for (hours, electricity) in [(0, false), (4, true)] {
+            let mut s = SurveyEntry::new();
+            s.user_id.value = user.id.value;
+            s.timestamp.value = start + Duration::hours(hours);
+            s.electricity.value = electricity;
+            storage.add_survey(&s).unwrap();
+        }
+        let mut orphan = SurveyEntry::new();
+        orphan.user_id.value = 1000;
+        assert!(storage.add_survey(&orphan).is_err());
+
+        let end = start + Duration::hours(5);
+        let reports = storage
+            .reports(&ReportType::All, &TimeOffset::Day(1), end)
+            .unwrap();
+        assert_eq!(reports.len(), 2);
+        assert!(!reports[0].electricity.value);
+        assert_eq!(storage.latest_reports(&ReportType::All).unwrap().len(), 1);
+        let before = storage
+            .latest_reports_before(&ReportType::Me(10), start + Duration::hours(1))
+            .unwrap();
+        assert_eq!(before.len(), 1);
+        assert!(!before[0].electricity.value);
+        assert!(storage.last_report(10).unwrap().unwrap().electricity.value);
+
+        let totals = storage
+            .daily_totals(
+                &ReportType::Me(10),
+                start.date_naive() + Duration::days(1),
+                start.date_naive() + Duration::days(1),
+            )
+            .unwrap();
+        assert_eq!(totals.len(), 1);
+        assert_eq!((totals[0].tracked, totals[0].electricity_off), (7200, 7200));
+
+        let silent = storage
+            .silent(&ReportType::All, &TimeOffset::Day(1))
+            .unwrap();
+        assert_eq!(silent.len(), 2);
+        assert!(silent[0].last_answer.is_none());
+        assert!(storage.set_active(11, false).unwrap());
+        assert!(!storage.find_user(11).unwrap().unwrap().active.value);
+        assert_eq!(storage.inactive_chat_ids().unwrap(), vec![11]);
+        let silent = storage
+            .silent(&ReportType::All, &TimeOffset::Day(1))
+            .unwrap();
+        assert_eq!(silent.len(), 1);
+
+        storage
+            .add_audit(&AuditEntry::admin(10, "backup", "ok"))
+            .unwrap();
+        let trail = storage.audit_trail(10, 5).unwrap();
+        assert_eq!(trail[0].kind.value, audit_table::KIND_ADMIN);
+        assert!(
+            storage
+                .prune_audit(Utc::now() + Duration::hours(1))
+                .unwrap()
+                > 0
+        );
+    }
+}
@@ -0,0 +1,733 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use once_cell::sync::OnceCell;
+
+use crate::bot::{fsm::ReportType, telecom};
+#[cfg(feature = "postgres")]
+use ::postgres::error::SqlState;
+
+#[cfg(feature = "postgres")]
+use crate::config::Config;
+
+use super::{
+    audit_table::AuditEntry,
+    daily_table::{self, DailyAvailability, DailyTotal},
+    pool::{self, Pool, PooledConnection},
+    report::{Report, Silent, TimeOffset},
+    survey_table::SurveyEntry,
+    user_table::User,
+    utils::Header,
+};
+
+/// Failure of a storage backend, the same for all of them.
+#[derive(Debug)]
+pub enum Error {
+    Sqlite(rusqlite::Error),
+    #[cfg(feature = "postgres")]
+    Postgres(::postgres::Error),
+    /// A unique name or a reference to a saved user was violated.
+    Constraint(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Sqlite(e) => write!(f, "{}", e),
+            #[cfg(feature = "postgres")]
+            Error::Postgres(e) => write!(f, "{}", e),
+            Error::Constraint(text) => write!(f, "constraint failed: {}", text),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        match e {
+            rusqlite::Error::SqliteFailure(ref failure, ref text)
+                if failure.code == rusqlite::ErrorCode::ConstraintViolation =>
+            {
+                Error::Constraint(text.clone().unwrap_or_else(|| failure.to_string()))
+            }
+            e => Error::Sqlite(e),
+        }
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl From<::postgres::Error> for Error {
+    fn from(e: ::postgres::Error) -> Self {
+        match e.code() {
+            Some(state)
+                if *state == SqlState::UNIQUE_VIOLATION
+                    || *state == SqlState::FOREIGN_KEY_VIOLATION =>
+            {
+                Error::Constraint(e.to_string())
+            }
+            _ => Error::Postgres(e),
+        }
+    }
+}
+
+/// Registered users, keyed by their chat id.
+pub trait UserRepository: Send + Sync {
+    /// Inserts the user or renames the one with the same chat id, returns the stored row.
+    fn save_user(&self, user: &User) -> Result<User>;
+    fn find_user(&self, chat_id: i64) -> Result<Option<User>>;
+    fn all_users(&self) -> Result<Vec<User>>;
+    /// Deletes the user and everything stored about them, false if there was nobody to delete.
+    fn forget_user(&self, chat_id: i64) -> Result<bool>;
+    /// Saves the Telegram profile of a registered user, false if there is no such user.
+    fn update_profile(&self, chat_id: i64, profile: &telecom::User) -> Result<bool>;
+    /// Marks the user as active or gone, false if there is no such user.
+    /// Inactive users are not surveyed and left out of the silent lists.
+    fn set_active(&self, chat_id: i64, active: bool) -> Result<bool>;
+    /// Chat ids of the users who left or blocked the bot.
+    fn inactive_chat_ids(&self) -> Result<Vec<i64>>;
+}
+
+/// Survey answers, `user_id` of an entry must reference a saved user.
+pub trait SurveyRepository: Send + Sync {
+    fn add_survey(&self, entry: &SurveyEntry) -> Result<SurveyEntry>;
+}
+
+/// Answers joined with the users they belong to.
+pub trait ReportQuery: Send + Sync {
+    /// Answers within `offset` before `end`.
+    fn reports(
+        &self,
+        report_type: &ReportType,
+        offset: &TimeOffset,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Report>>;
+    /// The most recent answer of every user.
+    fn latest_reports(&self, report_type: &ReportType) -> Result<Vec<Report>>;
+    /// The most recent answer of every user given before `before`.
+    fn latest_reports_before(
+        &self,
+        report_type: &ReportType,
+        before: DateTime<Utc>,
+    ) -> Result<Vec<Report>>;
+    fn last_report(&self, chat_id: i64) -> Result<Option<Report>>;
+    fn all_reports(&self) -> Result<Vec<Report>>;
+    /// Per-user sums for the UTC days `from..=to`.
+    fn daily_totals(
+        &self,
+        report_type: &ReportType,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> Result<Vec<DailyTotal>>;
+    /// Users whose last answer is older than `offset`, the oldest first.
+    fn silent(&self, report_type: &ReportType, offset: &TimeOffset) -> Result<Vec<Silent>>;
+}
+
+/// Trail of FSM transitions, admin actions and data deletions.
+pub trait AuditRepository: Send + Sync {
+    fn add_audit(&self, entry: &AuditEntry) -> Result<()>;
+    /// The `limit` newest entries about `chat_id`, the newest first.
+    fn audit_trail(&self, chat_id: i64, limit: usize) -> Result<Vec<AuditEntry>>;
+    /// Deletes the entries older than `before`, returns how many.
+    fn prune_audit(&self, before: DateTime<Utc>) -> Result<usize>;
+}
+
+pub trait Storage: UserRepository + SurveyRepository + ReportQuery + AuditRepository {}
+impl<T: UserRepository + SurveyRepository + ReportQuery + AuditRepository> Storage for T {}
+
+static STORAGE: OnceCell<Arc<dyn Storage>> = OnceCell::new();
+
+/// The configured backend, opened on first use. PostgreSQL when built with the
+/// `postgres` feature and `POSTGRES_URL` is set, the SQLite pool otherwise.
+pub fn storage() -> Arc<dyn Storage> {
+    STORAGE.get_or_init(open).clone()
+}
+
+/// Whether `storage` keeps the data in PostgreSQL. The SQLite file is still opened
+/// then, but the purge and the backups only ever touch that file.
+pub fn is_postgres() -> bool {
+    #[cfg(feature = "postgres")]
+    if Config::postgres_url().is_some() {
+        return true;
+    }
+    false
+}
+
+fn open() -> Arc<dyn Storage> {
+    #[cfg(feature = "postgres")]
+    if let Some(url) = Config::postgres_url() {
+        log::info!("Keeping users and answers in PostgreSQL");
+        return Arc::new(
+            super::postgres::PostgresStorage::connect(&url)
+                .unwrap_or_else(|e| panic!("Can't connect to POSTGRES_URL: {}", e)),
+        );
+    }
+    Arc::new(SqliteStorage::default())
+}
+
+/// The tables behind a connection pool, the `pool::shared` one unless given.
+#[derive(Default)]
+pub struct SqliteStorage {
+    pool: Option<&'static Pool>,
+}
+
+impl SqliteStorage {
+    pub fn with_pool(pool: &'static Pool) -> Self {
+        Self { pool: Some(pool) }
+    }
+
+    /// A fresh migrated in-memory database, nothing is shared between two instances.
+    #[cfg(test)]
+    pub fn memory() -> Self {
+        let pool = Box::leak(Box::new(
+            Pool::new(super::utils::DatabaseSource::Memory, 1).unwrap(),
+        ));
+        super::migration::run_on(&mut pool.get().unwrap()).unwrap();
+        Self::with_pool(pool)
+    }
+
+    fn conn(&self) -> rusqlite::Result<PooledConnection> {
+        match self.pool {
+            Some(pool) => pool.get(),
+            None => pool::get(),
+        }
+    }
+}
+
+impl UserRepository for SqliteStorage {
+    fn save_user(&self, user: &User) -> Result<User> {
+        let conn = self.conn()?;
+        Ok(user.insert_or_update_in(&conn)?)
+    }
+
+    fn find_user(&self, chat_id: i64) -> Result<Option<User>> {
+        let conn = self.conn()?;
+        Ok(optional(
+            User::new().select_one_by_in(&conn, &with_chat_id(chat_id).chat_id),
+        )?)
+    }
+
+    fn all_users(&self) -> Result<Vec<User>> {
+        let conn = self.conn()?;
+        Ok(User::new().select_all_in(&conn)?)
+    }
+
+    fn forget_user(&self, chat_id: i64) -> Result<bool> {
+        let mut conn = self.conn()?;
+        Ok(User::new().delete_with_data_in(&mut conn, chat_id)?)
+    }
+
+    fn update_profile(&self, chat_id: i64, profile: &telecom::User) -> Result<bool> {
+        let conn = self.conn()?;
+        Ok(User::update_profile_in(&conn, chat_id, profile)?)
+    }
+
+    fn set_active(&self, chat_id: i64, active: bool) -> Result<bool> {
+        let conn = self.conn()?;
+        Ok(User::set_active_in(&conn, chat_id, active)?)
+    }
+
+    fn inactive_chat_ids(&self) -> Result<Vec<i64>> {
+        let conn = self.conn()?;
+        Ok(User::new().select_inactive_chat_ids_in(&conn)?)
+    }
+}
+
+impl SurveyRepository for SqliteStorage {
+    /// Keeps the daily rollup in sync, the previous answer lasted until this one.
+    /// The answer and the rollup are written together or not at all.
+    fn add_survey(&self, entry: &SurveyEntry) -> Result<SurveyEntry> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let previous = optional(entry.select_one_by_in(&tx, &entry.user_id))?;
+        let inserted = entry.insert_in(&tx)?;
+        if let Some(previous) = previous {
+            DailyAvailability::add_with(
+                &tx,
+                entry.user_id.value,
+                previous.timestamp.value,
+                entry.timestamp.value,
+                previous.electricity.value,
+                previous.network.value,
+            )?;
+        }
+        tx.commit()?;
+        Ok(inserted)
+    }
+}
+
+impl ReportQuery for SqliteStorage {
+    fn reports(
+        &self,
+        report_type: &ReportType,
+        offset: &TimeOffset,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Report>> {
+        let conn = self.conn()?;
+        let r = Report::new();
+        let rows = match report_type {
+            ReportType::All => r.select_many_windowed(&conn, offset, end),
+            ReportType::Me(chat_id) => {
+                r.select_many_windowed_by(&conn, &with_chat_id(*chat_id).chat_id, offset, end)
+            }
+            ReportType::Team(manager) => {
+                r.select_many_windowed_by(&conn, &with_manager(manager).manager, offset, end)
+            }
+        };
+        Ok(rows?)
+    }
+
+    fn latest_reports(&self, report_type: &ReportType) -> Result<Vec<Report>> {
+        let conn = self.conn()?;
+        let r = Report::new();
+        let rows = match report_type {
+            ReportType::All => r.select_latest(&conn),
+            ReportType::Me(chat_id) => r.select_latest_by(&conn, &with_chat_id(*chat_id).chat_id),
+            ReportType::Team(manager) => r.select_latest_by(&conn, &with_manager(manager).manager),
+        };
+        Ok(rows?)
+    }
+
+    fn latest_reports_before(
+        &self,
+        report_type: &ReportType,
+        before: DateTime<Utc>,
+    ) -> Result<Vec<Report>> {
+        let conn = self.conn()?;
+        let r = Report::new();
+        let rows = match report_type {
+            ReportType::All => r.select_latest_before(&conn, None::<&Header<i64>>, before),
+            ReportType::Me(chat_id) => {
+                r.select_latest_before(&conn, Some(&with_chat_id(*chat_id).chat_id), before)
+            }
+            ReportType::Team(manager) => {
+                r.select_latest_before(&conn, Some(&with_manager(manager).manager), before)
+            }
+        };
+        Ok(rows?)
+    }
+
+    fn last_report(&self, chat_id: i64) -> Result<Option<Report>> {
+        let conn = self.conn()?;
+        Ok(optional(
+            Report::new().select_one_by(&conn, &with_chat_id(chat_id).chat_id),
+        )?)
+    }
+
+    fn all_reports(&self) -> Result<Vec<Report>> {
+        let conn = self.conn()?;
+        Ok(Report::new().select_all(&conn)?)
+    }
+
+    fn daily_totals(
+        &self,
+        report_type: &ReportType,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> Result<Vec<DailyTotal>> {
+        let conn = self.conn()?;
+        let rows = match report_type {
+            ReportType::All => DailyAvailability::select_totals::<i64>(&conn, None, from, to),
+            ReportType::Me(chat_id) => DailyAvailability::select_totals(
+                &conn,
+                Some(&with_chat_id(*chat_id).chat_id),
+                from,
+                to,
+            ),
+            ReportType::Team(manager) => DailyAvailability::select_totals(
+                &conn,
+                Some(&with_manager(manager).manager),
+                from,
+                to,
+            ),
+        };
+        Ok(rows?)
+    }
+
+    fn silent(&self, report_type: &ReportType, offset: &TimeOffset) -> Result<Vec<Silent>> {
+        let conn = self.conn()?;
+        let rows = match report_type {
+            ReportType::All => Silent::select_many(&conn, offset),
+            ReportType::Me(chat_id) => {
+                Silent::select_many_by(&conn, &with_chat_id(*chat_id).chat_id, offset)
+            }
+            ReportType::Team(manager) => {
+                Silent::select_many_by(&conn, &with_manager(manager).manager, offset)
+            }
+        };
+        Ok(rows?)
+    }
+}
+
+impl AuditRepository for SqliteStorage {
+    fn add_audit(&self, entry: &AuditEntry) -> Result<()> {
+        let conn = self.conn()?;
+        Ok(entry.insert_in(&conn)?)
+    }
+
+    fn audit_trail(&self, chat_id: i64, limit: usize) -> Result<Vec<AuditEntry>> {
+        let conn = self.conn()?;
+        Ok(AuditEntry::select_latest_by_chat_id_in(
+            &conn, chat_id, limit,
+        )?)
+    }
+
+    fn prune_audit(&self, before: DateTime<Utc>) -> Result<usize> {
+        let conn = self.conn()?;
+        Ok(AuditEntry::delete_before_in(&conn, before)?)
+    }
+}
+
+fn with_chat_id(chat_id: i64) -> User {
+    let mut u = User::new();
+    u.chat_id.value = chat_id;
+    u
+}
+
+fn with_manager(manager: &str) -> User {
+    let mut u = User::new();
+    u.manager.value = manager.to_string();
+    u
+}
+
+fn optional<T>(result: rusqlite::Result<T>) -> rusqlite::Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Plain vectors behind a mutex, nothing is shared between two instances.
+#[derive(Default)]
+pub struct MemoryStorage {
+    users: Mutex<Vec<User>>,
+    surveys: Mutex<Vec<SurveyEntry>>,
+    audit: Mutex<Vec<AuditEntry>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answers joined with their users in insertion order, like the SQL join.
+    fn joined(&self, report_type: &ReportType) -> Vec<Report> {
+        let users = self.users.lock().unwrap();
+        self.surveys
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|s| {
+                let u = users.iter().find(|u| u.id.value == s.user_id.value)?;
+                if !is_selected(report_type, u) {
+                    return None;
+                }
+                let mut r = Report::new();
+                r.name.value = u.name.value.clone();
+                r.manager.value = u.manager.value.clone();
+                r.chat_id.value = u.chat_id.value;
+                r.username.value = u.username.value.clone();
+                r.timestamp.value = s.timestamp.value;
+                r.electricity.value = s.electricity.value;
+                r.network.value = s.network.value;
+                Some(r)
+            })
+            .collect()
+    }
+}
+
+fn is_selected(report_type: &ReportType, u: &User) -> bool {
+    match report_type {
+        ReportType::All => true,
+        ReportType::Me(chat_id) => u.chat_id.value == *chat_id,
+        ReportType::Team(manager) => &u.manager.value == manager,
+    }
+}
+
+fn constraint_failed(text: &str) -> Error {
+    Error::Constraint(text.to_string())
+}
+
+impl UserRepository for MemoryStorage {
+    fn save_user(&self, user: &User) -> Result<User> {
+        let mut users = self.users.lock().unwrap();
+        if users
+            .iter()
+            .any(|u| u.name.value == user.name.value && u.chat_id.value != user.chat_id.value)
+        {
+            return Err(constraint_failed("UNIQUE constraint failed: user.name"));
+        }
+        if let Some(u) = users
+            .iter_mut()
+            .find(|u| u.chat_id.value == user.chat_id.value)
+        {
+            u.name.value = user.name.value.clone();
+            u.manager.value = user.manager.value.clone();
+            return Ok(u.clone());
+        }
+        let mut u = user.clone();
+        u.id.value = users.iter().map(|u| u.id.value).max().unwrap_or_default() + 1;
+        users.push(u.clone());
+        Ok(u)
+    }
+
+    fn find_user(&self, chat_id: i64) -> Result<Option<User>> {
+        Ok(self
+            .users
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|u| u.chat_id.value == chat_id)
+            .cloned())
+    }
+
+    fn all_users(&self) -> Result<Vec<User>> {
+        Ok(self.users.lock().unwrap().clone())
+    }
+
+    fn forget_user(&self, chat_id: i64) -> Result<bool> {
+        let mut users = self.users.lock().unwrap();
+        let id = match users.iter().find(|u| u.chat_id.value == chat_id) {
+            Some(u) => u.id.value,
+            None => return Ok(false),
+        };
+        users.retain(|u| u.id.value != id);
+        self.surveys
+            .lock()
+            .unwrap()
+            .retain(|s| s.user_id.value != id);
+        self.audit
+            .lock()
+            .unwrap()
+            .retain(|a| a.chat_id.value != chat_id);
+        Ok(true)
+    }
+
+    fn update_profile(&self, chat_id: i64, profile: &telecom::User) -> Result<bool> {
+        let mut users = self.users.lock().unwrap();
+        Ok(
+            match users.iter_mut().find(|u| u.chat_id.value == chat_id) {
+                Some(u) => {
+                    *u = u.clone().with_profile(profile);
+                    true
+                }
+                None => false,
+            },
+        )
+    }
+
+    fn set_active(&self, chat_id: i64, active: bool) -> Result<bool> {
+        let mut users = self.users.lock().unwrap();
+        Ok(
+            match users.iter_mut().find(|u| u.chat_id.value == chat_id) {
+                Some(u) => {
+                    u.active.value = active;
+                    true
+                }
+                None => false,
+            },
+        )
+    }
+
+    fn inactive_chat_ids(&self) -> Result<Vec<i64>> {
+        Ok(self
+            .users
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|u| !u.active.value)
+            .map(|u| u.chat_id.value)
+            .collect())
+    }
+}
+
+impl SurveyRepository for MemoryStorage {
+    fn add_survey(&self, entry: &SurveyEntry) -> Result<SurveyEntry> {
+        if !self
+            .users
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|u| u.id.value == entry.user_id.value)
+        {
+            return Err(constraint_failed("FOREIGN KEY constraint failed"));
+        }
+        let mut surveys = self.surveys.lock().unwrap();
+        let mut s = entry.clone();
+        s.id.value = surveys.len() as u64 + 1;
+        surveys.push(s.clone());
+        Ok(s)
+    }
+}
+
+impl ReportQuery for MemoryStorage {
+    fn reports(
+        &self,
+        report_type: &ReportType,
+        offset: &TimeOffset,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Report>> {
+        let from = offset.start_from(end);
+        Ok(self
+            .joined(report_type)
+            .into_iter()
+            .filter(|r| r.timestamp.value >= from && r.timestamp.value < end)
+            .collect())
+    }
+
+    fn latest_reports(&self, report_type: &ReportType) -> Result<Vec<Report>> {
+        let mut latest: Vec<Report> = Vec::new();
+        for r in self.joined(report_type) {
+            latest.retain(|l| l.chat_id.value != r.chat_id.value);
+            latest.push(r);
+        }
+        Ok(latest)
+    }
+
+    fn latest_reports_before(
+        &self,
+        report_type: &ReportType,
+        before: DateTime<Utc>,
+    ) -> Result<Vec<Report>> {
+        let mut latest: Vec<Report> = Vec::new();
+        for r in self.joined(report_type) {
+            if r.timestamp.value >= before {
+                continue;
+            }
+            latest.retain(|l| l.chat_id.value != r.chat_id.value);
+            latest.push(r);
+        }
+        Ok(latest)
+    }
+
+    fn last_report(&self, chat_id: i64) -> Result<Option<Report>> {
+        Ok(self.joined(&ReportType::Me(chat_id)).pop())
+    }
+
+    fn all_reports(&self) -> Result<Vec<Report>> {
+        Ok(self.joined(&ReportType::All))
+    }
+
+    fn daily_totals(
+        &self,
+        report_type: &ReportType,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> Result<Vec<DailyTotal>> {
+        let mut totals: Vec<DailyTotal> = Vec::new();
+        let reports = self.joined(report_type);
+        for (idx, previous) in reports.iter().enumerate() {
+            if let Some(next) = reports[idx + 1..]
+                .iter()
+                .find(|r| r.chat_id.value == previous.chat_id.value)
+            {
+                add_interval(&mut totals, previous, next.timestamp.value, from, to);
+            }
+        }
+        Ok(totals)
+    }
+
+    fn silent(&self, report_type: &ReportType, offset: &TimeOffset) -> Result<Vec<Silent>> {
+        let since = offset.start_from(Utc::now());
+        let reports = self.joined(report_type);
+        let mut silent: Vec<Silent> = self
+            .users
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|u| u.active.value && is_selected(report_type, u))
+            .map(|u| Silent {
+                name: u.name.value.clone(),
+                manager: u.manager.value.clone(),
+                chat_id: u.chat_id.value,
+                username: u.username.value.clone(),
+                last_answer: reports
+                    .iter()
+                    .filter(|r| r.chat_id.value == u.chat_id.value)
+                    .map(|r| r.timestamp.value)
+                    .max(),
+            })
+            .filter(|s| !matches!(s.last_answer, Some(last) if last >= since))
+            .collect();
+        silent.sort_by(|a, b| {
+            a.last_answer
+                .cmp(&b.last_answer)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        Ok(silent)
+    }
+}
+
+impl AuditRepository for MemoryStorage {
+    fn add_audit(&self, entry: &AuditEntry) -> Result<()> {
+        let mut audit = self.audit.lock().unwrap();
+        let mut a = entry.clone();
+        a.id.value = audit.len() as u64 + 1;
+        audit.push(a);
+        Ok(())
+    }
+
+    fn audit_trail(&self, chat_id: i64, limit: usize) -> Result<Vec<AuditEntry>> {
+        Ok(self
+            .audit
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|a| a.chat_id.value == chat_id)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    fn prune_audit(&self, before: DateTime<Utc>) -> Result<usize> {
+        let mut audit = self.audit.lock().unwrap();
+        let count = audit.len();
+        audit.retain(|a| a.timestamp.value >= before);
+        Ok(count - audit.len())
+    }
+}
+
+/// Adds the time from `previous` until the next answer `until` to the totals of
+/// its user, only the part within the UTC days `from..=to` counts.
+pub(super) fn add_interval(
+    totals: &mut Vec<DailyTotal>,
+    previous: &Report,
+    until: DateTime<Utc>,
+    from: NaiveDate,
+    to: NaiveDate,
+) {
+    for (day, seconds) in daily_table::split_by_day(previous.timestamp.value, until) {
+        if day < from || day > to {
+            continue;
+        }
+        let total = match totals
+            .iter_mut()
+            .position(|t| t.chat_id == previous.chat_id.value)
+        {
+            Some(position) => &mut totals[position],
+            None => {
+                totals.push(DailyTotal {
+                    name: previous.name.value.clone(),
+                    manager: previous.manager.value.clone(),
+                    chat_id: previous.chat_id.value,
+                    tracked: 0,
+                    electricity_off: 0,
+                    network_off: 0,
+                });
+                totals.last_mut().unwrap()
+            }
+        };
+        total.tracked += seconds;
+        if !previous.electricity.value {
+            total.electricity_off += seconds;
+        }
+        if !previous.network.value {
+            total.network_off += seconds;
+        }
+    }
+}
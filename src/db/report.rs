@@ -1,25 +1,27 @@
-use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono::{DateTime, Utc};
 use core::fmt;
 use typed_builder::TypedBuilder as Builder;
 
 use crate::{
-    bot::{fsm, fsm::Data, Error},
+    bot::{board, fsm, fsm::Data, Error},
     config::Config,
-    db::utils::{self, Header},
+    db::utils::{self, Header, Query, SqlValue},
 };
+use rusqlite::{Connection, ToSql};
 
 use super::{
+    repo::{self, Storage},
     survey_table::{self, SurveyEntry},
     user_table::{self, User},
-    utils::query_wrapper,
 };
 
-#[derive(Debug, Builder)]
+#[derive(Debug, Clone, Builder)]
 pub struct Report {
     pub name: Header<String>,
     pub manager: Header<String>,
     pub chat_id: Header<i64>,
-    pub timestamp: Header<String>,
+    pub username: Header<Option<String>>,
+    pub timestamp: Header<DateTime<Utc>>,
     pub electricity: Header<bool>,
     pub network: Header<bool>,
 }
@@ -46,14 +48,19 @@ impl fmt::Display for TimeOffset {
         fmt::Debug::fmt(self, f)
     }
 }
-// impl TimeOffset {
-//     fn to_string(&self) {
-//         match self {
-//             TimeOffset::Day(d) => {format!("Day(s) - {}")}
-//         }
-//     }
-
-// }
+impl TimeOffset {
+    /// Beginning of the period which lasts until `end`.
+    pub fn start_from(&self, end: DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            TimeOffset::Day(days) => end - chrono::Duration::days(*days),
+            TimeOffset::Month(months) => end
+                .date_naive()
+                .checked_sub_months(chrono::Months::new(*months as u32))
+                .map(|date| DateTime::<Utc>::from_utc(date.and_time(end.time()), Utc))
+                .unwrap_or(end),
+        }
+    }
+}
 
 impl Report {
     pub fn new() -> Self {
@@ -63,7 +70,8 @@ impl Report {
             .name(Header::new(String::default(), u.name.name))
             .manager(Header::new(String::default(), u.manager.name))
             .chat_id(Header::new(0, u.chat_id.name))
-            .timestamp(Header::new(String::default(), s.timestamp.name))
+            .username(Header::new(None, u.username.name))
+            .timestamp(Header::new(DateTime::<Utc>::default(), s.timestamp.name))
             .electricity(Header::new(true, s.electricity.name))
             .network(Header::new(true, s.network.name))
             .build()
@@ -74,17 +82,23 @@ impl Report {
         r.name.value = row.get(0)?;
         r.manager.value = row.get(1)?;
         r.chat_id.value = row.get(2)?;
-        r.timestamp.value = row.get(3)?;
+        r.timestamp.value = utils::from_epoch(row.get(3)?);
         r.electricity.value = row.get(4)?;
         r.network.value = row.get(5)?;
+        r.username.value = row.get(6)?;
         Ok(r)
     }
-    pub fn select_all(&self) -> rusqlite::Result<Vec<Self>> {
-        Self::select_many(ReportQueryBuilder::new().select().get())
+    pub fn select_all(&self, conn: &Connection) -> rusqlite::Result<Vec<Self>> {
+        Self::select_many(conn, ReportQueryBuilder::new().select().get())
     }
 
-    pub fn select_many_timed(&self, offset: &TimeOffset) -> rusqlite::Result<Vec<Self>> {
+    pub fn select_many_timed(
+        &self,
+        conn: &Connection,
+        offset: &TimeOffset,
+    ) -> rusqlite::Result<Vec<Self>> {
         Self::select_many(
+            conn,
             ReportQueryBuilder::new()
                 .select()
                 .where_()
@@ -93,8 +107,49 @@ impl Report {
         )
     }
 
-    pub fn select_many_by<T: fmt::Display>(&self, h: &Header<T>) -> rusqlite::Result<Vec<Self>> {
+    /// Answers within `offset` before `end`, used to compare adjacent periods.
+    pub fn select_many_windowed(
+        &self,
+        conn: &Connection,
+        offset: &TimeOffset,
+        end: DateTime<Utc>,
+    ) -> rusqlite::Result<Vec<Self>> {
+        Self::select_many(
+            conn,
+            ReportQueryBuilder::new()
+                .select()
+                .where_()
+                .cond_window(offset, end)
+                .get(),
+        )
+    }
+
+    pub fn select_many_windowed_by<T: SqlValue>(
+        &self,
+        conn: &Connection,
+        h: &Header<T>,
+        offset: &TimeOffset,
+        end: DateTime<Utc>,
+    ) -> rusqlite::Result<Vec<Self>> {
+        Self::select_many(
+            conn,
+            ReportQueryBuilder::new()
+                .select()
+                .where_()
+                .cond_header(h)
+                .and()
+                .cond_window(offset, end)
+                .get(),
+        )
+    }
+
+    pub fn select_many_by<T: SqlValue>(
+        &self,
+        conn: &Connection,
+        h: &Header<T>,
+    ) -> rusqlite::Result<Vec<Self>> {
         Self::select_many(
+            conn,
             ReportQueryBuilder::new()
                 .select()
                 .where_()
@@ -103,12 +158,14 @@ impl Report {
         )
     }
 
-    pub fn select_many_timed_by<T: fmt::Display>(
+    pub fn select_many_timed_by<T: SqlValue>(
         &self,
+        conn: &Connection,
         h: &Header<T>,
         offset: &TimeOffset,
     ) -> rusqlite::Result<Vec<Self>> {
         Self::select_many(
+            conn,
             ReportQueryBuilder::new()
                 .select()
                 .where_()
@@ -119,28 +176,71 @@ impl Report {
         )
     }
 
-    fn select_many(query: String) -> rusqlite::Result<Vec<Self>> {
-        let conn = utils::open(Config::database_location())?;
-        let mut stmt = conn.prepare(&query)?;
-        let report_iter = stmt.query_map([], |row| Self::from_row(&row))?;
-        report_iter.collect()
+    /// The most recent answer of every user.
+    pub fn select_latest(&self, conn: &Connection) -> rusqlite::Result<Vec<Self>> {
+        Self::select_many(
+            conn,
+            ReportQueryBuilder::new()
+                .select()
+                .where_()
+                .cond_latest()
+                .get(),
+        )
+    }
+
+    /// The most recent answer of every user matching `h`.
+    pub fn select_latest_by<T: SqlValue>(
+        &self,
+        conn: &Connection,
+        h: &Header<T>,
+    ) -> rusqlite::Result<Vec<Self>> {
+        Self::select_many(
+            conn,
+            ReportQueryBuilder::new()
+                .select()
+                .where_()
+                .cond_header(h)
+                .and()
+                .cond_latest()
+                .get(),
+        )
     }
 
-    pub fn select_one_by<T: fmt::Display>(&self, h: &Header<T>) -> rusqlite::Result<Self> {
-        let conn = utils::open(Config::database_location())?;
+    /// The most recent answer before `before` of every user, of those matching `h` if given.
+    pub fn select_latest_before<T: SqlValue>(
+        &self,
+        conn: &Connection,
+        h: Option<&Header<T>>,
+        before: DateTime<Utc>,
+    ) -> rusqlite::Result<Vec<Self>> {
+        let mut builder = ReportQueryBuilder::new().select().where_();
+        if let Some(h) = h {
+            builder = builder.cond_header(h).and();
+        }
+        Self::select_many(conn, builder.cond_latest_before(before).get())
+    }
 
-        let query = ReportQueryBuilder::new()
-            .select()
-            .where_()
-            .cond_header(h)
-            .order()
-            .get();
+    fn select_many(conn: &Connection, query: Query) -> rusqlite::Result<Vec<Self>> {
+        query.query_map(conn, Self::from_row)
+    }
 
-        let mut stmt = conn.prepare(&query)?;
-        let mut report_iter = stmt.query_map([], |row| Self::from_row(&row))?;
-        report_iter
-            .next()
-            .unwrap_or_else(|| Err(rusqlite::Error::QueryReturnedNoRows))
+    pub fn select_one_by<T: SqlValue>(
+        &self,
+        conn: &Connection,
+        h: &Header<T>,
+    ) -> rusqlite::Result<Self> {
+        Self::select_many(
+            conn,
+            ReportQueryBuilder::new()
+                .select()
+                .where_()
+                .cond_header(h)
+                .order()
+                .get(),
+        )?
+        .into_iter()
+        .next()
+        .ok_or(rusqlite::Error::QueryReturnedNoRows)
     }
 }
 
@@ -153,26 +253,81 @@ impl From<fsm::Data> for Report {
         }
         r.name.value = data.name;
         r.chat_id.value = data.chat_id;
-        r.timestamp.value = data.utc.format(Config::time_format()).to_string();
+        r.timestamp.value = data.utc;
         r
     }
 }
 
+/// Registered user who didn't answer within the report period.
+#[derive(Debug)]
+pub struct Silent {
+    pub name: String,
+    pub manager: String,
+    pub chat_id: i64,
+    pub username: Option<String>,
+    pub last_answer: Option<DateTime<Utc>>,
+}
+
+impl Silent {
+    pub fn select_many(conn: &Connection, offset: &TimeOffset) -> rusqlite::Result<Vec<Self>> {
+        Self::select(
+            conn,
+            ReportQueryBuilder::new()
+                .select_silent()
+                .where_()
+                .cond_header(&User::new().active)
+                .group_silent(offset)
+                .get(),
+        )
+    }
+
+    pub fn select_many_by<T: SqlValue>(
+        conn: &Connection,
+        h: &Header<T>,
+        offset: &TimeOffset,
+    ) -> rusqlite::Result<Vec<Self>> {
+        Self::select(
+            conn,
+            ReportQueryBuilder::new()
+                .select_silent()
+                .where_()
+                .cond_header(h)
+                .and()
+                .cond_header(&User::new().active)
+                .group_silent(offset)
+                .get(),
+        )
+    }
+
+    fn select(conn: &Connection, query: Query) -> rusqlite::Result<Vec<Self>> {
+        query.query_map(conn, |row| {
+            let last_answer: Option<i64> = row.get(3)?;
+            Ok(Self {
+                name: row.get(0)?,
+                manager: row.get(1)?,
+                chat_id: row.get(2)?,
+                username: row.get(4)?,
+                last_answer: last_answer.map(utils::from_epoch),
+            })
+        })
+    }
+}
+
 pub struct ReportQueryBuilder {
-    query: String,
+    query: Query,
 }
 impl ReportQueryBuilder {
     pub fn new() -> Self {
         Self {
-            query: String::default(),
+            query: Query::default(),
         }
     }
     pub fn select(mut self) -> Self {
         let u = User::new();
         let s = SurveyEntry::new();
         let r = Report::new();
-        self.query.push_str(&format!(
-            "SELECT {},{},{},{},{},{} FROM {} 
+        self.query = self.query.push(&format!(
+            "SELECT {},{},{},{},{},{},{} FROM {} 
                 INNER JOIN {} 
                 ON {}.{}={}.{} ",
             r.name.name,
@@ -181,6 +336,7 @@ impl ReportQueryBuilder {
             r.timestamp.name,
             r.electricity.name,
             r.network.name,
+            r.username.name,
             user_table::TABLE_NAME,
             survey_table::TABLE_NAME,
             survey_table::TABLE_NAME,
@@ -191,145 +347,247 @@ impl ReportQueryBuilder {
         self
     }
     pub fn where_(mut self) -> Self {
-        self.query.push_str(&format!(" WHERE "));
+        self.query = self.query.push(" WHERE ");
         self
     }
     pub fn and(mut self) -> Self {
-        self.query.push_str(&format!(" AND "));
+        self.query = self.query.push(" AND ");
         self
     }
 
-    pub fn cond_header<T: fmt::Display>(mut self, h: &Header<T>) -> Self {
-        self.query.push_str(&format!(" {}='{}' ", h.name, h.value));
+    pub fn cond_header<T: SqlValue>(mut self, h: &Header<T>) -> Self {
+        self.query = self.query.cond(h);
         self
     }
 
-    pub fn cond_time(mut self, offset: &TimeOffset) -> Self {
+    pub fn cond_time(self, offset: &TimeOffset) -> Self {
         let r = Report::new();
-        let format_time = Config::time_format();
-        let offset_string = match offset {
-            TimeOffset::Day(days) => format!("{} day", days),
-            // TimeOffset::Week(week) => format!("{} week", week),
-            TimeOffset::Month(month) => format!("{} month", month),
-        };
-
-        self.query.push_str(&format!(
-            " strftime('{}', {}) >= strftime('{}', '{}', '-{}') ",
-            format_time,
-            r.timestamp.name,
-            format_time,
-            Utc::now().format(format_time),
-            offset_string
+        self.push(&format!(" {} >= ", r.timestamp.name))
+            .bind(offset.start_from(Utc::now()).timestamp())
+    }
+
+    pub fn cond_window(self, offset: &TimeOffset, end: DateTime<Utc>) -> Self {
+        let r = Report::new();
+        self.push(&format!(" {} >= ", r.timestamp.name))
+            .bind(offset.start_from(end).timestamp())
+            .push(&format!(" AND {} < ", r.timestamp.name))
+            .bind(end.timestamp())
+    }
+
+    pub fn cond_latest(mut self) -> Self {
+        let s = SurveyEntry::new();
+        self.query = self.query.push(&format!(
+            " {}.{} IN (SELECT MAX({}) FROM {} GROUP BY {}) ",
+            survey_table::TABLE_NAME,
+            s.id.name,
+            s.id.name,
+            survey_table::TABLE_NAME,
+            s.user_id.name,
         ));
         self
     }
+
+    pub fn cond_latest_before(self, before: DateTime<Utc>) -> Self {
+        let s = SurveyEntry::new();
+        self.push(&format!(
+            " {}.{} IN (SELECT MAX({}) FROM {} WHERE {} < ",
+            survey_table::TABLE_NAME,
+            s.id.name,
+            s.id.name,
+            survey_table::TABLE_NAME,
+            s.timestamp.name,
+        ))
+        .bind(before.timestamp())
+        .push(&format!(" GROUP BY {}) ", s.user_id.name))
+    }
+
+    /// Users (with their last answer) joined with the survey including the ones without answers.
+    pub fn select_silent(mut self) -> Self {
+        let u = User::new();
+        let s = SurveyEntry::new();
+        self.query = self.query.push(&format!(
+            "SELECT {},{},{},MAX({}),{} FROM {}
+                LEFT JOIN {}
+                ON {}.{}={}.{} ",
+            u.name.name,
+            u.manager.name,
+            u.chat_id.name,
+            s.timestamp.name,
+            u.username.name,
+            user_table::TABLE_NAME,
+            survey_table::TABLE_NAME,
+            survey_table::TABLE_NAME,
+            s.user_id.name,
+            user_table::TABLE_NAME,
+            u.id.name
+        ));
+        self
+    }
+
+    /// Keeps the users from `select_silent` whose last answer is older than `offset`.
+    pub fn group_silent(self, offset: &TimeOffset) -> Self {
+        let u = User::new();
+        let s = SurveyEntry::new();
+        let max_timestamp = format!("MAX({})", s.timestamp.name);
+        self.push(&format!(
+            " GROUP BY {}.{} HAVING {} IS NULL OR {} < ",
+            user_table::TABLE_NAME,
+            u.id.name,
+            max_timestamp,
+            max_timestamp,
+        ))
+        .bind(offset.start_from(Utc::now()).timestamp())
+        .push(&format!(" ORDER BY {}, {} ", max_timestamp, u.name.name))
+    }
+
+    fn push(mut self, sql: &str) -> Self {
+        self.query = self.query.push(sql);
+        self
+    }
+
+    fn bind<T: ToSql + 'static>(mut self, value: T) -> Self {
+        self.query = self.query.bind(value);
+        self
+    }
+
     pub fn order(mut self) -> Self {
         let s = SurveyEntry::new();
-        self.query.push_str(&format!(
+        self.query = self.query.push(&format!(
             " ORDER BY {}.{} DESC LIMIT 1 ",
             survey_table::TABLE_NAME,
             s.id.name,
         ));
         self
     }
-    pub fn get(self) -> String {
-        query_wrapper(self.query)
+    pub fn get(self) -> Query {
+        self.query
     }
 }
 
-pub fn survey_save(data: &Data) -> Result<(), Error> {
-    let mut result: Result<(), Error> = Ok(());
-    let report = Report::from(data.clone());
-    let mut insert_survey = false;
-    let select_result = report.select_one_by(&report.chat_id);
-
-    match select_result {
-        Ok(report) => {
-            if let Ok(date_time) =
-                NaiveDateTime::parse_from_str(&report.timestamp.value, Config::time_format())
-            {
-                let utc = DateTime::<Utc>::from_utc(date_time, Utc);
-                let delta = chrono::Duration::minutes(10) - Utc::now().signed_duration_since(utc);
-                if delta < chrono::Duration::zero() {
-                    insert_survey = true;
-                } else {
-                    result = Error::Verbose(format!(
-                        "Too many requests now: {}, last on: {}, please wait for {} minute(s) ",
-                        Utc::now().format(Config::time_format()),
-                        report.timestamp.value,
-                        delta.num_minutes() + 1,
-                    ))
-                    .wrap();
-                }
-            } else {
-                result =
-                    Error::Verbose(format!("Can't parse time: {}", report.timestamp.value)).wrap();
-            }
-        }
-        Err(error) => {
-            insert_survey = true;
-            log::debug!("Survey - true due to no entries: {}", error.to_string());
+pub fn survey_save(storage: &dyn Storage, data: &Data) -> Result<(), Error> {
+    let storage_error = |e: repo::Error| Error::Verbose(format!("Can't save: {}", e));
+    if let Some(last) = storage.last_report(data.chat_id).map_err(storage_error)? {
+        let delta =
+            chrono::Duration::minutes(10) - Utc::now().signed_duration_since(last.timestamp.value);
+        if delta >= chrono::Duration::zero() {
+            return Error::Verbose(format!(
+                "Too many requests now: {}, last on: {}, please wait for {} minute(s) ",
+                Utc::now().format(Config::time_format()),
+                last.timestamp.value.format(Config::time_format()),
+                delta.num_minutes() + 1,
+            ))
+            .wrap();
         }
     }
 
-    log::debug!("insert_survey:{}", insert_survey);
-    if insert_survey {
-        let mut user = user_table::User::from(data.clone());
-        let result = user.select_one_by(&user.chat_id);
-        if let Err(_) = result {
-            user = user.insert_or_update().unwrap(); // don't care for time being
-        } else {
-            user = result.unwrap();
-        }
-        let mut surey: survey_table::SurveyEntry = data.clone().into();
-        log::debug!("Data: {:?}", data);
-        log::debug!("About to inser survey: {:?}", surey);
-        surey.user_id.value = user.id.value;
-        surey.insert().unwrap(); // don't care for time being
-    }
-    result
+    let user = match storage.find_user(data.chat_id).map_err(storage_error)? {
+        Some(user) => user,
+        None => storage
+            .save_user(&User::from(data.clone()))
+            .map_err(storage_error)?,
+    };
+    let mut survey: SurveyEntry = data.clone().into();
+    survey.user_id.value = user.id.value;
+    log::debug!("About to insert survey: {:?}", survey);
+    storage.add_survey(&survey).map_err(storage_error)?;
+    board::notify();
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use std::fs;
-
-    use chrono::Utc;
+    use chrono::{Duration, Utc};
 
     use crate::{
-        config::Config,
-        db::{report::Report, survey_table::SurveyEntry, user_table::User, utils::DatabaseSource},
+        bot::fsm::ReportType,
+        db::{
+            repo::{ReportQuery, SqliteStorage, SurveyRepository, UserRepository},
+            report::TimeOffset,
+            survey_table::SurveyEntry,
+            user_table::User,
+        },
     };
 
     #[test]
     pub fn test_report() {
-        if let DatabaseSource::File(path) = Config::database_location() {
-            let _ = fs::remove_file(path);
-        }
-
-        pretty_env_logger::formatted_timed_builder()
-            .filter(Some("guardian"), log::LevelFilter::Trace)
-            .init();
+        let storage = SqliteStorage::memory();
 
         let u1 = User::new();
-        assert_eq!(Ok(()), u1.create_table());
-        let u2 = u1.insert_or_update().unwrap();
+        let u2 = storage.save_user(&u1).unwrap();
         assert_eq!(u1.chat_id, u2.chat_id);
         assert_ne!(u1.id, u2.id);
+        let mut u3 = User::new();
+        u3.name.value = "Jonny Black".to_string();
+        u3.chat_id.value = 1;
+        storage.save_user(&u3).unwrap();
 
+        let first = Utc::now() - Duration::hours(2);
         let mut s1 = SurveyEntry::new();
         s1.user_id.value = u2.id.value;
-        s1.timestamp.value = Utc::now().format("%Y-%m-%d %H:%M:%S").to_string();
-        assert_eq!(Ok(()), s1.create_table());
-        let s2 = s1.insert().unwrap();
-        assert_ne!(s1.id, s2.id);
-        assert_eq!(s1.user_id, s2.user_id);
-
-        let r = Report::new();
-
-        let col = r.select_many_by(&u1.name);
-        log::debug!("> {:?}", col);
-        let col = r.select_all();
-        log::debug!("> {:?}", col);
+        s1.timestamp.value = first;
+        storage.add_survey(&s1).unwrap();
+        s1.timestamp.value = first + Duration::hours(1);
+        s1.network.value = false;
+        storage.add_survey(&s1).unwrap();
+
+        let all = storage
+            .reports(&ReportType::All, &TimeOffset::Day(1), Utc::now())
+            .unwrap();
+        assert_eq!(all.len(), 2);
+        let team = storage
+            .reports(
+                &ReportType::Team("Nobody".to_string()),
+                &TimeOffset::Day(1),
+                Utc::now(),
+            )
+            .unwrap();
+        assert!(team.is_empty());
+        let window = storage
+            .reports(
+                &ReportType::All,
+                &TimeOffset::Day(1),
+                first + Duration::minutes(30),
+            )
+            .unwrap();
+        assert_eq!(window.len(), 1);
+        // Stored as seconds since the epoch
+        assert_eq!(window[0].timestamp.value.timestamp(), first.timestamp());
+
+        let before = storage
+            .latest_reports_before(&ReportType::All, first + Duration::minutes(30))
+            .unwrap();
+        assert_eq!(before.len(), 1);
+        assert!(before[0].network.value);
+
+        // The first answer lasted an hour until the second one
+        let totals = storage
+            .daily_totals(
+                &ReportType::Me(u1.chat_id.value),
+                first.date_naive(),
+                Utc::now().date_naive(),
+            )
+            .unwrap();
+        assert_eq!(totals.len(), 1);
+        assert_eq!(totals[0].tracked, 3600);
+        assert_eq!(totals[0].network_off, 0);
+
+        let last = storage.last_report(u1.chat_id.value).unwrap().unwrap();
+        assert!(!last.network.value);
+        assert_eq!(storage.latest_reports(&ReportType::All).unwrap().len(), 1);
+
+        let silent = storage
+            .silent(&ReportType::All, &TimeOffset::Day(1))
+            .unwrap();
+        assert_eq!(silent.len(), 1);
+        assert_eq!(silent[0].name, "Jonny Black".to_string());
+
+        // Users who left are not asked about
+        assert!(storage.set_active(u3.chat_id.value, false).unwrap());
+        assert!(!storage.set_active(42, false).unwrap());
+        assert_eq!(storage.inactive_chat_ids().unwrap(), vec![u3.chat_id.value]);
+        assert!(storage
+            .silent(&ReportType::All, &TimeOffset::Day(1))
+            .unwrap()
+            .is_empty());
     }
 }
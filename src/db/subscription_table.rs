@@ -0,0 +1,97 @@
+use rusqlite::{Connection, OptionalExtension, Result};
+use typed_builder::TypedBuilder as Builder;
+
+use crate::db::utils::query_wrapper;
+
+use super::{pool, utils::Header};
+
+pub const TABLE_NAME: &str = "subscription";
+
+/// Weekly digest subscription. Managers get the digest without a row,
+/// the row is created on the first delivery or when somebody opts in/out.
+#[derive(Debug, Builder)]
+pub struct Subscription {
+    pub id: Header<u64>,
+    pub chat_id: Header<i64>,
+    pub enabled: Header<bool>,
+    pub last_sent: Header<String>,
+}
+
+impl Subscription {
+    pub fn new() -> Self {
+        Self::builder()
+            .id(Header::new(0, "id"))
+            .chat_id(Header::new(0, "chat_id"))
+            .enabled(Header::new(true, "enabled"))
+            .last_sent(Header::new(String::default(), "last_sent"))
+            .build()
+    }
+
+    pub fn insert_or_update(&self) -> Result<()> {
+        let conn = pool::get()?;
+        let query = query_wrapper(format!(
+            "INSERT INTO {} ({},{},{}) VALUES (?1, ?2, ?3)
+            ON CONFLICT({}) DO UPDATE SET {}=?2, {}=?3",
+            TABLE_NAME,
+            self.chat_id.name,
+            self.enabled.name,
+            self.last_sent.name,
+            self.chat_id.name,
+            self.enabled.name,
+            self.last_sent.name,
+        ));
+        conn.execute(
+            &query,
+            (
+                &self.chat_id.value,
+                &self.enabled.value,
+                &self.last_sent.value,
+            ),
+        )?;
+        Ok(())
+    }
+
+    pub fn select_by_chat_id(chat_id: i64) -> Result<Option<Self>> {
+        let conn = pool::get()?;
+        let s = Self::new();
+        let query = query_wrapper(format!(
+            "SELECT * FROM {} WHERE {}=?1",
+            TABLE_NAME, s.chat_id.name
+        ));
+        conn.query_row(&query, [chat_id], Self::from_row).optional()
+    }
+
+    pub fn delete_by_chat_id_in(conn: &Connection, chat_id: i64) -> Result<()> {
+        let query = query_wrapper(format!(
+            "DELETE FROM {} WHERE {}=?1",
+            TABLE_NAME,
+            Self::new().chat_id.name
+        ));
+        conn.execute(&query, [chat_id])?;
+        Ok(())
+    }
+
+    pub fn select_all(&self) -> Result<Vec<Self>> {
+        let conn = pool::get()?;
+        let query = query_wrapper(format!("SELECT * FROM {}", TABLE_NAME));
+
+        let mut stmt = conn.prepare(&query)?;
+        let iter = stmt.query_map([], Self::from_row)?;
+        iter.collect()
+    }
+
+    fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+        let mut s = Self::new();
+        s.id.value = row.get(0)?;
+        s.chat_id.value = row.get(1)?;
+        s.enabled.value = row.get(2)?;
+        s.last_sent.value = row.get(3)?;
+        Ok(s)
+    }
+}
+
+impl Default for Subscription {
+    fn default() -> Self {
+        Self::new()
+    }
+}
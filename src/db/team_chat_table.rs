@@ -0,0 +1,86 @@
+use rusqlite::{OptionalExtension, Result};
+use typed_builder::TypedBuilder as Builder;
+
+use crate::db::utils::query_wrapper;
+
+use super::{pool, utils::Header};
+
+pub const TABLE_NAME: &str = "team_chat";
+
+/// Group chat linked to the team of a manager.
+#[derive(Debug, Builder)]
+pub struct TeamChat {
+    pub id: Header<u64>,
+    pub chat_id: Header<i64>,
+    pub manager: Header<String>,
+    pub last_summary: Header<String>,
+}
+
+impl TeamChat {
+    pub fn new() -> Self {
+        Self::builder()
+            .id(Header::new(0, "id"))
+            .chat_id(Header::new(0, "chat_id"))
+            .manager(Header::new(String::default(), "manager"))
+            .last_summary(Header::new(String::default(), "last_summary"))
+            .build()
+    }
+
+    pub fn insert_or_update(&self) -> Result<()> {
+        let conn = pool::get()?;
+        let query = query_wrapper(format!(
+            "INSERT INTO {} ({},{},{}) VALUES (?1, ?2, ?3)
+            ON CONFLICT({}) DO UPDATE SET {}=?2, {}=?3",
+            TABLE_NAME,
+            self.chat_id.name,
+            self.manager.name,
+            self.last_summary.name,
+            self.chat_id.name,
+            self.manager.name,
+            self.last_summary.name,
+        ));
+        conn.execute(
+            &query,
+            (
+                &self.chat_id.value,
+                &self.manager.value,
+                &self.last_summary.value,
+            ),
+        )?;
+        Ok(())
+    }
+
+    pub fn select_by_chat_id(chat_id: i64) -> Result<Option<Self>> {
+        let conn = pool::get()?;
+        let t = Self::new();
+        let query = query_wrapper(format!(
+            "SELECT * FROM {} WHERE {}=?1",
+            TABLE_NAME, t.chat_id.name
+        ));
+        conn.query_row(&query, [chat_id], Self::from_row).optional()
+    }
+
+    pub fn select_all(&self) -> Result<Vec<Self>> {
+        let conn = pool::get()?;
+        let query = query_wrapper(format!("SELECT * FROM {}", TABLE_NAME));
+
+        let mut stmt = conn.prepare(&query)?;
+        let iter = stmt.query_map([], Self::from_row)?;
+        iter.collect()
+    }
+
+    fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
+        let mut t = Self::new();
+        t.id.value = row.get(0)?;
+        t.chat_id.value = row.get(1)?;
+        t.manager.value = row.get(2)?;
+        t.last_summary.value = row.get(3)?;
+        Ok(t)
+    }
+}
+
+impl Default for TeamChat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,20 +1,35 @@
-use core::fmt;
-
-use rusqlite::Result;
+use rusqlite::{Connection, OptionalExtension, Result};
 use typed_builder::TypedBuilder as Builder;
 
-use crate::{bot::fsm, config::Config, db::utils::query_wrapper};
-
-use super::utils::{self, Header};
+use crate::{
+    bot::{fsm, telecom},
+    db::utils::query_wrapper,
+};
+
+use super::{
+    audit_table::{self, AuditEntry},
+    daily_table::{self, DailyAvailability},
+    outbox_table::{self, OutboxMessage},
+    pool,
+    subscription_table::{self, Subscription},
+    survey_table::{self, SurveyEntry},
+    utils::{Header, Query, SqlValue},
+};
 
 pub const TABLE_NAME: &str = "user";
 
-#[derive(Debug, Builder)]
+#[derive(Debug, Clone, Builder)]
 pub struct User {
     pub id: Header<u64>,
     pub name: Header<String>,
     pub manager: Header<String>,
     pub chat_id: Header<i64>,
+    /// Telegram profile, refreshed on every message, see `update_profile`.
+    pub username: Header<Option<String>>,
+    pub first_name: Header<Option<String>>,
+    pub last_name: Header<Option<String>>,
+    /// Users who left or blocked the bot are kept but not surveyed, see `set_active`.
+    pub active: Header<bool>,
 }
 
 impl User {
@@ -24,134 +39,202 @@ impl User {
             .name(Header::new("John Doe".into(), "name"))
             .manager(Header::new("Richard Roe".into(), "manager"))
             .chat_id(Header::new(0, "chat_id"))
+            .username(Header::new(None, "username"))
+            .first_name(Header::new(None, "first_name"))
+            .last_name(Header::new(None, "last_name"))
+            .active(Header::new(true, "active"))
             .build()
     }
 
-    pub fn create_table(&self) -> Result<()> {
-        let conn = utils::open(Config::database_location())?;
-        let query = query_wrapper(format!(
-            "CREATE TABLE IF NOT EXISTS {} (
-            {}  INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT ,
-            {}  TEXT NOT NULL UNIQUE,
-            {}  TEXT NOT NULL,
-            {}  INTEGER NOT NULL UNIQUE
-            )",
-            TABLE_NAME, self.id.name, self.name.name, self.manager.name, self.chat_id.name,
-        ));
-
-        conn.execute(&query, ())?;
-
-        Ok(())
-    }
-
     pub fn insert(&self) -> Result<Self> {
-        let conn = utils::open(Config::database_location())?;
+        let conn = pool::get()?;
         let query = query_wrapper(format!(
             "INSERT INTO {} ({},{},{}) 
-            VALUES (?, ?, ?)",
+            VALUES (?, ?, ?) RETURNING *",
             TABLE_NAME, self.name.name, self.manager.name, self.chat_id.name,
         ));
-        conn.execute(
+        conn.query_row(
             &query,
             (&self.name.value, &self.manager.value, &self.chat_id.value),
-        )?;
+            Self::from_row,
+        )
+    }
 
-        let query = query_wrapper(format!(
-            "SELECT * FROM {} ORDER BY {} DESC LIMIT 1",
-            TABLE_NAME, self.id.name
-        ));
+    /// Inserts the user or renames the one with the same chat id, returns the written row.
+    pub fn insert_or_update_in(&self, conn: &Connection) -> Result<Self> {
+        let result = Query::new(&format!(
+            "INSERT INTO {} ({},{},{}) VALUES (",
+            TABLE_NAME, self.name.name, self.manager.name, self.chat_id.name,
+        ))
+        .bind(self.name.value.clone())
+        .push(", ")
+        .bind(self.manager.value.clone())
+        .push(", ")
+        .bind(self.chat_id.value)
+        .push(&format!(
+            ") ON CONFLICT({}) DO UPDATE SET {}=excluded.{}, {}=excluded.{} RETURNING *",
+            self.chat_id.name, self.name.name, self.name.name, self.manager.name, self.manager.name,
+        ))
+        .query_map(conn, Self::from_row)?
+        .into_iter()
+        .next()
+        .ok_or(rusqlite::Error::QueryReturnedNoRows);
+        log::debug!("user: {:?}", result);
+        result
+    }
 
-        let mut stmt = conn.prepare(&query)?;
-        let mut user_iter = stmt.query_map([], |row| Self::from_row(row))?;
+    pub fn update_one<T, U>(&self, h_update: &Header<T>, h_where: &Header<U>) -> Result<()>
+    where
+        T: SqlValue,
+        U: SqlValue,
+    {
+        let conn = pool::get()?;
+        Query::new(&format!("UPDATE {} SET", TABLE_NAME))
+            .cond(h_update)
+            .push("WHERE")
+            .cond(h_where)
+            .execute(&conn)
+            .map(|_| ())
+    }
 
-        // Ok(User::new())
-        user_iter.next().unwrap()
+    pub fn select_one_by_in<T: SqlValue>(&self, conn: &Connection, h: &Header<T>) -> Result<Self> {
+        Query::new(&format!("SELECT * FROM {} WHERE", TABLE_NAME))
+            .cond(h)
+            .push(&format!("ORDER BY {} DESC LIMIT 1", self.id.name))
+            .query_map(conn, Self::from_row)?
+            .into_iter()
+            .next()
+            .ok_or(rusqlite::Error::QueryReturnedNoRows)
     }
 
-    pub fn insert_or_update(&self) -> Result<Self> {
-        let conn = utils::open(Config::database_location())?;
-        let query = query_wrapper(format!(
-            "INSERT OR IGNORE INTO {} ({},{},{}) 
-                VALUES ('{}', '{}', '{}')",
-            TABLE_NAME,
-            self.name.name,
-            self.manager.name,
-            self.chat_id.name,
-            self.name.value,
-            self.manager.value,
-            self.chat_id.value,
-        ));
-        conn.execute(
-            &query,
-            (), // (&self.name.value, &self.manager.value, &self.chat_id.value),
-        )?;
+    pub fn select_many_by<T: SqlValue>(&self, h: &Header<T>) -> Result<Vec<Self>> {
+        let conn = pool::get()?;
+        Query::new(&format!("SELECT * FROM {} WHERE", TABLE_NAME))
+            .cond(h)
+            .push(&format!("ORDER BY {}", self.name.name))
+            .query_map(&conn, Self::from_row)
+    }
 
-        let query = query_wrapper(format!(
-            "UPDATE {} SET {}='{}', {}='{}' WHERE {}='{}';",
-            TABLE_NAME,
-            self.name.name,
-            self.name.value,
-            self.manager.name,
-            self.manager.value,
-            self.chat_id.name,
-            self.chat_id.value
-        ));
-        conn.execute(
-            &query,
-            (), // (&self.name.value, &self.manager.value, &self.chat_id.value),
-        )?;
+    /// Saves the Telegram profile of a registered user, false if there is no such user.
+    pub fn update_profile_in(
+        conn: &Connection,
+        chat_id: i64,
+        profile: &telecom::User,
+    ) -> Result<bool> {
+        let u = Self::new().with_profile(profile);
+        Query::new(&format!("UPDATE {} SET", TABLE_NAME))
+            .cond(&u.username)
+            .push(",")
+            .cond(&u.first_name)
+            .push(",")
+            .cond(&u.last_name)
+            .push("WHERE")
+            .cond(&Header::new(chat_id, u.chat_id.name))
+            .execute(conn)
+            .map(|updated| updated > 0)
+    }
 
-        let query = query_wrapper(format!(
-            "SELECT * FROM {} ORDER BY {} DESC LIMIT 1",
-            TABLE_NAME, self.id.name
-        ));
+    /// Marks the user as active or gone, false if there is no such user.
+    pub fn set_active_in(conn: &Connection, chat_id: i64, active: bool) -> Result<bool> {
+        let u = Self::new();
+        Query::new(&format!("UPDATE {} SET", TABLE_NAME))
+            .cond(&Header::new(active, u.active.name))
+            .push("WHERE")
+            .cond(&Header::new(chat_id, u.chat_id.name))
+            .execute(conn)
+            .map(|updated| updated > 0)
+    }
 
-        let mut stmt = conn.prepare(&query)?;
-        let mut user_iter = stmt.query_map([], |row| Self::from_row(row))?;
+    pub fn with_profile(mut self, profile: &telecom::User) -> Self {
+        self.username.value = profile.username.clone();
+        self.first_name.value = Some(profile.first_name.clone());
+        self.last_name.value = profile.last_name.clone();
+        self
+    }
 
-        // Ok(User::new())
-        let result = user_iter.next().unwrap();
-        log::debug!("user: {:?}", result.as_ref().unwrap());
-        result
+    /// First and last name from the Telegram profile.
+    pub fn profile_name(&self) -> Option<String> {
+        let first_name = self.first_name.value.as_deref()?;
+        Some(match self.last_name.value.as_deref() {
+            Some(last_name) => format!("{} {}", first_name, last_name),
+            None => first_name.to_string(),
+        })
     }
 
-    pub fn update_one<T, U>(&self, h_update: &Header<T>, h_where: &Header<U>) -> Result<()>
-    where
-        T: fmt::Display,
-        U: fmt::Display,
-    {
-        let conn = utils::open(Config::database_location())?;
-        let query = query_wrapper(format!(
-            "UPDATE {} SET {}='{}' WHERE {}='{}'",
-            TABLE_NAME, h_update.name, h_update.value, h_where.name, h_where.value
-        ));
-        match conn.execute(&query, ()) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e),
-        }
+    /// The self-entered name doesn't match the Telegram profile, the order of the words doesn't matter.
+    pub fn differs_from_profile(&self) -> bool {
+        let words = |name: &str| {
+            let mut words: Vec<String> = name.split_whitespace().map(str::to_lowercase).collect();
+            words.sort();
+            words
+        };
+        matches!(self.profile_name(), Some(profile) if words(&profile) != words(&self.name.value))
     }
 
-    pub fn select_one_by<T: fmt::Display>(&self, h: &Header<T>) -> Result<Self> {
-        let conn = utils::open(Config::database_location())?;
-        let query = query_wrapper(format!(
-            "SELECT * FROM {} WHERE {}='{}' ORDER BY {} DESC LIMIT 1",
-            TABLE_NAME, h.name, h.value, self.id.name
-        ));
+    pub fn select_all_in(&self, conn: &Connection) -> Result<Vec<User>> {
+        let query = query_wrapper(format!("SELECT * FROM {}", TABLE_NAME));
 
         let mut stmt = conn.prepare(&query)?;
-        let mut user_iter = stmt.query_map([], |row| Self::from_row(row))?;
-        user_iter
-            .next()
-            .unwrap_or_else(|| Err(rusqlite::Error::QueryReturnedNoRows))
+        let user_iter = stmt.query_map([], Self::from_row)?;
+        user_iter.collect()
     }
 
-    pub fn select_all(&self) -> Result<Vec<User>> {
-        let conn = utils::open(Config::database_location())?;
-        let query = query_wrapper(format!("SELECT * FROM {}", TABLE_NAME));
+    pub fn select_inactive_chat_ids_in(&self, conn: &Connection) -> Result<Vec<i64>> {
+        let query = query_wrapper(format!(
+            "SELECT {} FROM {} WHERE {}=?1",
+            self.chat_id.name, TABLE_NAME, self.active.name
+        ));
 
         let mut stmt = conn.prepare(&query)?;
-        let user_iter = stmt.query_map([], |row| Self::from_row(row))?;
-        user_iter.collect()
+        let chat_id_iter = stmt.query_map([false], |row| row.get(0))?;
+        chat_id_iter.collect()
+    }
+
+    /// Deletes the user with their answers, rollup rows, digest subscription, audit trail
+    /// and the messages queued or sent to them. Returns false if there was no such user.
+    pub fn delete_with_data_in(&self, conn: &mut Connection, chat_id: i64) -> Result<bool> {
+        let tx = conn.transaction()?;
+        let id: Option<u64> = tx
+            .query_row(
+                &query_wrapper(format!(
+                    "SELECT {} FROM {} WHERE {}=?1",
+                    self.id.name, TABLE_NAME, self.chat_id.name
+                )),
+                [chat_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let id = match id {
+            Some(id) => id,
+            None => return Ok(false),
+        };
+        let s = SurveyEntry::new();
+        let d = DailyAvailability::new();
+        for (table, column) in [
+            (daily_table::TABLE_NAME, d.user_id.name),
+            (survey_table::TABLE_NAME, s.user_id.name),
+            (TABLE_NAME, self.id.name),
+        ] {
+            tx.execute(
+                &query_wrapper(format!("DELETE FROM {} WHERE {}=?1", table, column)),
+                [id],
+            )?;
+        }
+        for (table, column) in [
+            (
+                subscription_table::TABLE_NAME,
+                Subscription::new().chat_id.name,
+            ),
+            (audit_table::TABLE_NAME, AuditEntry::new().chat_id.name),
+            (outbox_table::TABLE_NAME, OutboxMessage::new().chat_id.name),
+        ] {
+            tx.execute(
+                &query_wrapper(format!("DELETE FROM {} WHERE {}=?1", table, column)),
+                [chat_id],
+            )?;
+        }
+        tx.commit()?;
+        Ok(true)
     }
 
     fn from_row(row: &rusqlite::Row) -> Result<Self, rusqlite::Error> {
@@ -160,6 +243,10 @@ impl User {
         u.name.value = row.get(1)?;
         u.manager.value = row.get(2)?;
         u.chat_id.value = row.get(3)?;
+        u.username.value = row.get(4)?;
+        u.first_name.value = row.get(5)?;
+        u.last_name.value = row.get(6)?;
+        u.active.value = row.get(7)?;
         Ok(u)
     }
 }
@@ -182,45 +269,101 @@ impl From<fsm::Data> for User {
 
 #[cfg(test)]
 mod tests {
-    use std::fs;
+    use rusqlite::Connection;
 
     use crate::{
-        config::Config,
-        db::{user_table::User, utils::DatabaseSource},
+        bot::telecom,
+        db::{
+            migration,
+            repo::{SqliteStorage, UserRepository},
+            user_table::User,
+        },
     };
 
     #[test]
     pub fn test_user_insert() {
-        if let DatabaseSource::File(path) = Config::database_location() {
-            let _ = fs::remove_file(path);
-        }
-
-        pretty_env_logger::formatted_timed_builder()
-            .filter(Some("guardian"), log::LevelFilter::Trace)
-            .init();
+        let storage = SqliteStorage::memory();
 
         let mut u1 = User::new();
-        assert_eq!(Ok(()), u1.create_table());
-        let u2 = u1.insert().unwrap();
+        let u2 = storage.save_user(&u1).unwrap();
         assert_eq!(u1.chat_id, u2.chat_id);
         assert_ne!(u1.id, u2.id);
 
         u1.name.value = "Jonny Black".to_string();
         u1.chat_id.value += 1;
-        let u3 = u1.insert_or_update().unwrap();
+        let u3 = storage.save_user(&u1).unwrap();
         assert_eq!(u3.name.value, "Jonny Black".to_string());
-        let collection = u1.select_all();
-        println!("collection name: {:?}", collection);
         u1.name.value = "Jonny White".to_string();
-        let _ = u1.insert_or_update();
-        let collection = u1.select_all();
-        println!("collection rename: {:?}", collection);
+        let u4 = storage.save_user(&u1).unwrap();
+        assert_eq!(u3.id, u4.id);
+
+        let collection = storage.all_users().unwrap();
+        assert_eq!(collection.len(), 2);
 
-        assert_eq!(collection.unwrap().len(), 2);
+        let found = storage.find_user(u1.chat_id.value).unwrap().unwrap();
+        assert_eq!(found.name.value, "Jonny White".to_string());
+        assert!(storage.find_user(-1).unwrap().is_none());
 
-        let found = u1.select_one_by(&u1.chat_id);
-        println!("found: {:?}", found);
-        let found = u1.select_one_by(&u1.name);
-        println!("found: {:?}", found);
+        // Names are unique like in the table
+        let mut u5 = User::new();
+        u5.chat_id.value = 42;
+        assert!(storage.save_user(&u5).is_err());
+    }
+
+    #[test]
+    pub fn test_user_profile() {
+        let storage = SqliteStorage::memory();
+        let mut u = User::new();
+        u.name.value = "Jonny White".to_string();
+        storage.save_user(&u).unwrap();
+
+        let mut profile = telecom::User::builder()
+            .first_name("White".to_string())
+            .last_name("Jonny")
+            .username("jwhite")
+            .build();
+        assert!(!storage.update_profile(42, &profile).unwrap());
+        assert!(storage.update_profile(0, &profile).unwrap());
+        let found = storage.find_user(0).unwrap().unwrap();
+        assert_eq!(found.username.value, Some("jwhite".to_string()));
+        assert!(!found.differs_from_profile());
+
+        profile.first_name = "John".to_string();
+        storage.update_profile(0, &profile).unwrap();
+        // Saving the name again keeps the profile
+        storage.save_user(&u).unwrap();
+        let found = storage.find_user(0).unwrap().unwrap();
+        assert_eq!(found.profile_name(), Some("John Jonny".to_string()));
+        assert!(found.differs_from_profile());
+    }
+
+    #[test]
+    pub fn test_user_delete_with_data() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migration::run_on(&mut conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO user (id, name, manager, chat_id) VALUES (1, 'John Doe', 'Richard Roe', 10);
+            INSERT INTO survey VALUES (1, 1, 1668938400, 1, 1);
+            INSERT INTO audit (chat_id, timestamp, kind, from_state, event, to_state, result)
+                VALUES (10, 1668938400, 'transition', 'Idle', 'Survey', 'Survey', 'ok'),
+                (0, 1668938400, 'admin', '', 'backup', '', 'ok');
+            INSERT INTO outbox (chat_id, method, payload, created, expires, attempts, status,
+                last_error)
+                VALUES (10, 'sendMessage', '{}', 1668938400, 1668942000, 1, 'sent', '');",
+        )
+        .unwrap();
+
+        assert!(User::new().delete_with_data_in(&mut conn, 10).unwrap());
+        assert!(!User::new().delete_with_data_in(&mut conn, 10).unwrap());
+        let count = |table: &str| -> i64 {
+            conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+        assert_eq!(count("survey"), 0);
+        assert_eq!(count("outbox"), 0);
+        // Only the operator's entries are left
+        assert_eq!(count("audit"), 1);
     }
 }
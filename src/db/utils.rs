@@ -1,18 +1,17 @@
-use rusqlite::{Connection, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use rusqlite::{params_from_iter, Connection, Result, Row, ToSql};
 
 pub enum DatabaseSource {
     Memory,
     File(String),
 }
 
-pub fn open(src: DatabaseSource) -> Result<Connection> {
-    match src {
-        DatabaseSource::Memory => Ok(Connection::open_in_memory()?),
-        DatabaseSource::File(path) => Ok(Connection::open(path.as_str())?),
-    }
+/// Timestamps are stored as INTEGER seconds since the epoch.
+pub fn from_epoch(seconds: i64) -> DateTime<Utc> {
+    Utc.timestamp_opt(seconds, 0).single().unwrap_or_default()
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Header<T> {
     pub name: &'static str,
     pub value: T,
@@ -24,6 +23,70 @@ impl<T> Header<T> {
     }
 }
 
+/// Values which can be bound to a `Query` straight from a `Header`.
+pub trait SqlValue: ToSql + Clone + 'static {}
+impl<T: ToSql + Clone + 'static> SqlValue for T {}
+
+/// SQL text with `?N` placeholders and the values bound to them.
+/// Only table and column names get into the text, values are always bound.
+#[derive(Default)]
+pub struct Query {
+    sql: String,
+    params: Vec<Box<dyn ToSql>>,
+}
+
+impl Query {
+    pub fn new(sql: &str) -> Self {
+        Self {
+            sql: sql.to_string(),
+            params: Vec::new(),
+        }
+    }
+
+    pub fn push(mut self, sql: &str) -> Self {
+        self.sql.push_str(sql);
+        self
+    }
+
+    /// Appends a placeholder for `value`.
+    pub fn bind<T: ToSql + 'static>(mut self, value: T) -> Self {
+        self.params.push(Box::new(value));
+        self.sql.push_str(&format!("?{}", self.params.len()));
+        self
+    }
+
+    /// Appends ` name=?N ` bound to the header value.
+    pub fn cond<T: SqlValue>(self, h: &Header<T>) -> Self {
+        self.push(&format!(" {}=", h.name))
+            .bind(h.value.clone())
+            .push(" ")
+    }
+
+    pub fn sql(&self) -> String {
+        query_wrapper(self.sql.clone())
+    }
+
+    pub fn execute(&self, conn: &Connection) -> Result<usize> {
+        conn.execute(&self.sql(), params_from_iter(self.params.iter()))
+    }
+
+    pub fn query_map<T, F>(&self, conn: &Connection, f: F) -> Result<Vec<T>>
+    where
+        F: FnMut(&Row<'_>) -> Result<T>,
+    {
+        let mut stmt = conn.prepare(&self.sql())?;
+        let iter = stmt.query_map(params_from_iter(self.params.iter()), f)?;
+        iter.collect()
+    }
+
+    pub fn query_row<T, F>(&self, conn: &Connection, f: F) -> Result<T>
+    where
+        F: FnOnce(&Row<'_>) -> Result<T>,
+    {
+        conn.query_row(&self.sql(), params_from_iter(self.params.iter()), f)
+    }
+}
+
 #[inline]
 pub fn query_wrapper(query: String) -> String {
     let mut query_final = query.replace("\n", " ");
@@ -33,3 +96,34 @@ pub fn query_wrapper(query: String) -> String {
     log::debug!("#SQL: [{}]", query_final);
     query_final
 }
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::{Header, Query};
+
+    #[test]
+    pub fn test_query_binds_values() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute("CREATE TABLE user (name TEXT, manager TEXT)", ())
+            .unwrap();
+        let manager = Header::new("Richard O'Roe".to_string(), "manager");
+
+        Query::new("INSERT INTO user (name, manager) VALUES (")
+            .bind("John Doe")
+            .push(", ")
+            .bind(manager.value.clone())
+            .push(")")
+            .execute(&conn)
+            .unwrap();
+        let query = Query::new("SELECT name FROM user WHERE").cond(&manager);
+        assert_eq!(query.sql(), "SELECT name FROM user WHERE manager=?1 ");
+        assert_eq!(
+            query
+                .query_map(&conn, |row| row.get::<_, String>(0))
+                .unwrap(),
+            vec!["John Doe".to_string()]
+        );
+    }
+}
@@ -1,4 +1,5 @@
 pub mod bot;
+pub mod cli;
 pub mod config;
 pub mod db;
 pub mod http_client;
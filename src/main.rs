@@ -1,7 +1,11 @@
-use guardian::bot::telandler::Teladler;
+use guardian::cli::{self, Cli};
 fn main() {
     pretty_env_logger::formatted_timed_builder()
         .filter(Some("guardian"), log::LevelFilter::Trace)
         .init();
-    Teladler::new().exec();
+    let result = Cli::parse(std::env::args().skip(1)).and_then(cli::run);
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 }
@@ -1,15 +1,18 @@
 use chrono::Utc;
 use typed_builder::TypedBuilder as Builder;
 
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use crate::{
     bot::{
-        fsm::{self, Data, State},
-        telecom::{ReplyEnum, UserInput},
+        fsm::{self, Data, ReportType, State},
+        telecom::{self, ReplyEnum, UserInput},
         Error,
     },
-    db::report,
+    db::repo::Storage,
 };
 
 // static USER_DATA: OnceCell<Box<Arc<UserData>>> = OnceCell::new();
@@ -17,25 +20,27 @@ use crate::{
 #[derive(Builder)]
 pub struct UserData {
     user_data_table: HashMap<i64, fsm::State>,
+    /// Telegram profiles saved last, keyed by chat id.
+    #[builder(default)]
+    profiles: HashMap<i64, telecom::User>,
+    storage: Arc<dyn Storage>,
 }
 
 impl UserData {
-    pub fn new() -> Self {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
         let hash_map: HashMap<i64, fsm::State> = HashMap::new();
-        Self::builder().user_data_table(hash_map).build().init()
+        Self::builder()
+            .user_data_table(hash_map)
+            .storage(storage)
+            .build()
+            .init()
     }
 
     pub fn init(mut self) -> Self {
-        if let Ok(report) = report::Report::new().select_all() {
-            let mut map: HashMap<String, report::Report> = HashMap::new();
+        if let Ok(report) = self.storage.latest_reports(&ReportType::All) {
             report.into_iter().for_each(|r| {
-                // log::debug!("report; {:?}", r);
-                map.insert(r.name.value.clone(), r);
-            });
-
-            map.into_iter().for_each(|m| {
                 self.user_data_table
-                    .insert(m.1.chat_id.value, Data::from(m.1).wrap_no_update(fsm::State::Idle));
+                    .insert(r.chat_id.value, Data::from(r).wrap_no_update(fsm::State::Idle));
             });
         }
         self
@@ -46,8 +51,12 @@ impl UserData {
     }
 
     pub fn find_expired(&self) -> Vec<i64> {
+        let inactive = self.inactive();
         let mut chat_id_collection = Vec::new();
         for u in &self.user_data_table {
+            if inactive.contains(u.0) {
+                continue;
+            }
             if Utc::now().signed_duration_since(u.1.data().utc) > chrono::Duration::hours(24) {
                 chat_id_collection.push(u.1.data().chat_id);
             }
@@ -56,8 +65,12 @@ impl UserData {
     }
 
     pub fn find_with_issues(&self) -> Vec<i64> {
+        let inactive = self.inactive();
         let mut chat_id_collection = Vec::new();
         for u in &self.user_data_table {
+            if inactive.contains(u.0) {
+                continue;
+            }
             if Utc::now().signed_duration_since(u.1.data().utc) > chrono::Duration::hours(1)
                 && !u.1.data().issues.is_none()
             {
@@ -67,6 +80,17 @@ impl UserData {
         chat_id_collection
     }
 
+    /// Chat ids of the users who left or blocked the bot.
+    fn inactive(&self) -> HashSet<i64> {
+        match self.storage.inactive_chat_ids() {
+            Ok(chat_ids) => chat_ids.into_iter().collect(),
+            Err(e) => {
+                log::error!("Can't read inactive users: {}", e);
+                HashSet::new()
+            }
+        }
+    }
+
     pub fn handle_incoming_v2(&mut self, user_input: &UserInput) -> Result<ReplyEnum, Error> {
         let mut state = self
             .user_data_table
@@ -79,13 +103,149 @@ impl UserData {
             })
             .to_owned();
 
-        state = state.consume_as_str(&user_input.text).transit();
+        state = state.consume_as_str(&user_input.text).transit(self.storage.as_ref());
         let reply = state.reply();
-        self.user_data_table
-            .insert(user_input.chat_id, state.clone());
+        if let State::Forgotten(_) = state {
+            self.user_data_table.remove(&user_input.chat_id);
+            self.profiles.remove(&user_input.chat_id);
+        } else {
+            self.refresh_profile(user_input);
+            self.user_data_table
+                .insert(user_input.chat_id, state.clone());
+        }
         reply.ok_or(Error::make_verbose(&format!(
             "Skipped input: {}",
             user_input.text
         )))
     }
+
+    /// Saves the Telegram profile of a registered user when it changed since their last message.
+    fn refresh_profile(&mut self, user_input: &UserInput) {
+        let profile = match &user_input.user {
+            Some(profile) => profile,
+            None => return,
+        };
+        if self.profiles.get(&user_input.chat_id) == Some(profile) {
+            return;
+        }
+        match self.storage.update_profile(user_input.chat_id, profile) {
+            Ok(true) => {
+                self.profiles.insert(user_input.chat_id, profile.clone());
+                if let Ok(Some(user)) = self.storage.find_user(user_input.chat_id) {
+                    if user.differs_from_profile() {
+                        log::warn!(
+                            "{} is registered as {} but is {} in Telegram",
+                            user_input.chat_id,
+                            user.name.value,
+                            user.profile_name().unwrap_or_default()
+                        );
+                    }
+                }
+            }
+            // Not registered yet
+            Ok(false) => (),
+            Err(e) => log::error!("Can't save the profile of {}: {}", user_input.chat_id, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        bot::{fsm::Event, telecom::UserInput},
+        db::{
+            audit_table,
+            repo::{AuditRepository, MemoryStorage, ReportQuery, UserRepository},
+        },
+    };
+
+    use super::UserData;
+
+    fn input(text: &str) -> UserInput {
+        UserInput::builder()
+            .chat_id(7)
+            .text(text.to_string())
+            .date(0)
+            .build()
+    }
+
+    #[test]
+    pub fn test_user_data_registers_and_answers() {
+        let storage = Arc::new(MemoryStorage::new());
+        let mut user_data = UserData::new(storage.clone());
+
+        for text in ["/start", "Jonny Black", "Elina Bodzhek", "Allright"] {
+            assert!(user_data.handle_incoming_v2(&input(text)).is_ok());
+        }
+        let user = storage.find_user(7).unwrap().unwrap();
+        assert_eq!(user.name.value, "Jonny Black".to_string());
+        assert!(storage.last_report(7).unwrap().is_some());
+    }
+
+    #[test]
+    pub fn test_user_data_forget() {
+        let storage = Arc::new(MemoryStorage::new());
+        let mut user_data = UserData::new(storage.clone());
+
+        for text in ["/start", "Jonny Black", "Elina Bodzhek", "/forget"] {
+            assert!(user_data.handle_incoming_v2(&input(text)).is_ok());
+        }
+        // Nothing is deleted until confirmed
+        assert!(storage.find_user(7).unwrap().is_some());
+        assert!(user_data.handle_incoming_v2(&input("Delete my data")).is_ok());
+        assert!(storage.find_user(7).unwrap().is_none());
+        assert!(user_data.collect_chat_ids().is_empty());
+        assert!(storage.audit_trail(7, 10).unwrap().is_empty());
+        let operator = storage.audit_trail(audit_table::OPERATOR, 10).unwrap();
+        assert_eq!(operator.len(), 1);
+        assert_eq!(operator[0].event.value, "forget");
+    }
+
+    #[test]
+    pub fn test_user_data_audit_trail() {
+        let storage = Arc::new(MemoryStorage::new());
+        let mut user_data = UserData::new(storage.clone());
+
+        for text in ["/start", "Jonny Black", "/audit 7 5"] {
+            assert!(user_data.handle_incoming_v2(&input(text)).is_ok());
+        }
+        let trail = storage.audit_trail(7, 10).unwrap();
+        let steps: Vec<(&str, &str, &str)> = trail
+            .iter()
+            .rev()
+            .map(|a| {
+                (
+                    a.from_state.value.as_str(),
+                    a.event.value.as_str(),
+                    a.to_state.value.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            steps,
+            vec![
+                ("New", "Start", "RegName"),
+                // The typed name stays out of the trail
+                ("RegName", "Name", "RegManager"),
+                ("RegManager", "Audit(\"7 5\")", "RegManager"),
+            ]
+        );
+        assert_eq!(trail[0].result.value, "error: Not the owner");
+        assert_eq!(
+            Event::from_string("/audit"),
+            Some(Event::Audit(String::default()))
+        );
+        assert_eq!(
+            Event::from_string("/deactivate @jblack"),
+            Some(Event::Deactivate("@jblack".to_string()))
+        );
+        assert_eq!(Event::from_string("/backup"), Some(Event::Backup));
+        assert_eq!(Event::from_string("/back"), Some(Event::Back));
+        assert_eq!(Event::from_string("/help@guardian_bot"), Some(Event::Help));
+        assert_eq!(Event::from_string("My Team"), Some(Event::ReportTeam));
+        assert_eq!(Event::from_string("please /forget me"), None);
+        assert_eq!(Event::from_string("Delete my data, I changed my mind"), None);
+    }
 }